@@ -0,0 +1,99 @@
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::BlockHash;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use spam_block_reqs::generator::{InventoryRequests, RequestGenerator};
+use spam_block_reqs::version::VersionBuilder;
+use spam_block_reqs::{parse_header, InventoryType, RequestConfig, MESSAGE_HEADER_SIZE};
+use std::time::Duration;
+
+fn config(number: usize) -> RequestConfig {
+    RequestConfig {
+        magic: Network::Bitcoin.magic(),
+        block_hashes: (0..100u8).map(|i| BlockHash::from_inner([i; 32])).collect(),
+        txids: vec![],
+        number,
+        batch: 1000,
+        block_source: None,
+        read_rate: None,
+        jitter: None,
+        burst: None,
+        pause: Duration::ZERO,
+        rate: None,
+        control: None,
+        profile: None,
+        verify_checksums: false,
+        discard: false,
+        decode_pool: None,
+        memory: None,
+        pipe: None,
+        version: None,
+        emulation: None,
+        handshake_timeout: None,
+        required_services: ServiceFlags::NONE,
+        recent_blocks: false,
+        repeat: false,
+    }
+}
+
+/// Building and serializing the getdata messages of a run, as every connection does before
+/// sending.
+fn request_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_serialization");
+    let generator = InventoryRequests::new(vec![InventoryType::WitnessBlock]);
+    for number in [1_000, 100_000] {
+        let config = config(number);
+        group.throughput(Throughput::Elements(number as u64));
+        group.bench_function(number.to_string(), |b| {
+            b.iter(|| {
+                generator
+                    .requests(&config)
+                    .unwrap()
+                    .into_iter()
+                    .map(|payload| {
+                        serialize(&RawNetworkMessage {
+                            magic: config.magic,
+                            payload,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Parsing the header the receive loop reads ahead of every message.
+fn header_parsing(c: &mut Criterion) {
+    let message = serialize(&RawNetworkMessage {
+        magic: Network::Bitcoin.magic(),
+        payload: NetworkMessage::Ping(0),
+    });
+    let header: [u8; MESSAGE_HEADER_SIZE] = message[..MESSAGE_HEADER_SIZE].try_into().unwrap();
+    c.bench_function("header_parsing", |b| {
+        b.iter(|| parse_header(black_box(&header)).unwrap())
+    });
+}
+
+/// Building and serializing the version message each connection opens its handshake with.
+fn handshake_construction(c: &mut Criterion) {
+    c.bench_function("handshake_construction", |b| {
+        b.iter(|| {
+            let version = VersionBuilder::default().build().unwrap();
+            serialize(&RawNetworkMessage {
+                magic: Network::Bitcoin.magic(),
+                payload: NetworkMessage::Version(version),
+            })
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    request_serialization,
+    header_parsing,
+    handshake_construction
+);
+criterion_main!(benches);
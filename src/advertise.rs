@@ -0,0 +1,269 @@
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Connection;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::network::address::{AddrV2, AddrV2Message, Address};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use log::trace;
+use std::fmt;
+use std::io::{BufReader, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often to check the listener for new connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long an inbound peer gets to send its version message.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Base32 alphabet of onion and I2P addresses, RFC 4648 in lower case.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Version byte closing a decoded Tor v3 onion address.
+const TORV3_VERSION: u8 = 3;
+
+/// An address to advertise, on any network addrv2 messages carry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertised {
+    pub address: AddrV2,
+    pub port: u16,
+    text: String,
+}
+
+impl Advertised {
+    /// The message advertising the address: addr for IPv4 and IPv6, which every node reads, and
+    /// addrv2 for the networks only it can carry.
+    fn message(&self, timestamp: u32) -> NetworkMessage {
+        let services = ServiceFlags::WITNESS;
+        match self.address {
+            AddrV2::Ipv4(ip) => NetworkMessage::Addr(vec![(
+                timestamp,
+                Address::new(&SocketAddr::new(IpAddr::V4(ip), self.port), services),
+            )]),
+            AddrV2::Ipv6(ip) => NetworkMessage::Addr(vec![(
+                timestamp,
+                Address::new(&SocketAddr::new(IpAddr::V6(ip), self.port), services),
+            )]),
+            _ => NetworkMessage::AddrV2(vec![AddrV2Message {
+                time: timestamp,
+                services,
+                addr: self.address.clone(),
+                port: self.port,
+            }]),
+        }
+    }
+}
+
+impl fmt::Display for Advertised {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Parses an address to advertise: `ip:port`, `[ipv6]:port`, `<56 chars>.onion:port` or
+/// `<52 chars>.b32.i2p:port`. IPv6 addresses in fc00::/8 are CJDNS addresses, as BIP155 has it.
+/// The checksum of onion addresses is not verified.
+pub fn parse_advertised(s: &str) -> Result<Advertised> {
+    let invalid = || {
+        anyhow!("Invalid address {s}, expected ip:port, <onion>.onion:port or <i2p>.b32.i2p:port")
+    };
+    let advertised = |address, port| Advertised {
+        address,
+        port,
+        text: s.to_string(),
+    };
+    if let Ok(socket) = s.parse::<SocketAddr>() {
+        let address = match socket.ip() {
+            IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+            IpAddr::V6(ip) if ip.octets()[0] == 0xfc => AddrV2::Cjdns(ip),
+            IpAddr::V6(ip) => AddrV2::Ipv6(ip),
+        };
+        return Ok(advertised(address, socket.port()));
+    }
+    let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host.to_lowercase();
+    if let Some(onion) = host.strip_suffix(".onion") {
+        let decoded = base32_decode(onion).filter(|decoded| decoded.len() == 35);
+        let decoded = decoded
+            .filter(|decoded| decoded[34] == TORV3_VERSION)
+            .ok_or_else(invalid)?;
+        let mut key = [0; 32];
+        key.copy_from_slice(&decoded[..32]);
+        return Ok(advertised(AddrV2::TorV3(key), port));
+    }
+    if let Some(i2p) = host.strip_suffix(".b32.i2p") {
+        let decoded = base32_decode(i2p)
+            .filter(|decoded| i2p.len() == 52 && decoded.len() == 32)
+            .ok_or_else(invalid)?;
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&decoded);
+        return Ok(advertised(AddrV2::I2p(hash), port));
+    }
+    Err(invalid())
+}
+
+/// Decodes unpadded lower case base32, dropping the bits left over past the last whole byte.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&known| known == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// A connection made to our listener after we advertised it.
+#[derive(Debug)]
+pub struct InboundConnection {
+    /// Time since we first advertised our address.
+    pub after: Duration,
+    pub peer: SocketAddr,
+    /// User agent from the peer's version message, if it sent one.
+    pub user_agent: Option<String>,
+}
+
+/// Advertises `advertised` to the target via addr or addrv2 messages every `interval`, and
+/// records the connections made to `listener` until `wait` has passed.
+pub fn advertise_and_listen(
+    stream: &mut Connection,
+    listener: &TcpListener,
+    magic: u32,
+    advertised: &Advertised,
+    interval: Duration,
+    wait: Duration,
+) -> Result<Vec<InboundConnection>> {
+    let handler = MessageHandler::new(magic, None).with_addrv2();
+    perform_handshake(stream, &handler)?;
+
+    // Keep answering the target's pings while we wait for inbound connections.
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let reader = stream.try_clone()?;
+        let writer = writer.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, reader);
+            loop {
+                let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+                handler.handle(&mut *writer.lock().unwrap(), &message.payload)?;
+            }
+        });
+    }
+
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+    let mut next_advertisement = start;
+    let mut inbound = Vec::new();
+    while start.elapsed() < wait {
+        if Instant::now() >= next_advertisement {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+            let message = RawNetworkMessage {
+                magic,
+                payload: advertised.message(timestamp),
+            };
+            writer.lock().unwrap().write_all(&serialize(&message))?;
+            trace!("Advertised {advertised}");
+            next_advertisement += interval;
+        }
+
+        match listener.accept() {
+            Ok((connection, peer)) => {
+                trace!("Inbound connection from {peer}");
+                inbound.push(InboundConnection {
+                    after: start.elapsed(),
+                    peer,
+                    user_agent: read_user_agent(connection),
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(inbound)
+}
+
+fn read_user_agent(connection: TcpStream) -> Option<String> {
+    connection.set_nonblocking(false).ok()?;
+    connection.set_read_timeout(Some(VERSION_TIMEOUT)).ok()?;
+    let mut reader = BufReader::new(connection);
+    match RawNetworkMessage::consensus_decode(&mut reader)
+        .ok()?
+        .payload
+    {
+        NetworkMessage::Version(version) => Some(version.user_agent),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_addresses_are_advertised_in_addr_messages() {
+        let advertised = parse_advertised("10.0.0.2:8333").unwrap();
+        assert_eq!(
+            advertised.address,
+            AddrV2::Ipv4("10.0.0.2".parse().unwrap())
+        );
+        assert!(matches!(advertised.message(0), NetworkMessage::Addr(_)));
+        let advertised = parse_advertised("[2001:db8::1]:8333").unwrap();
+        assert!(matches!(advertised.address, AddrV2::Ipv6(_)));
+        assert!(matches!(advertised.message(0), NetworkMessage::Addr(_)));
+    }
+
+    #[test]
+    fn other_networks_are_advertised_in_addrv2_messages() {
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:8333";
+        let advertised = parse_advertised(onion).unwrap();
+        assert!(matches!(advertised.address, AddrV2::TorV3(_)));
+        assert_eq!(advertised.port, 8333);
+        assert_eq!(advertised.to_string(), onion);
+        let NetworkMessage::AddrV2(messages) = advertised.message(7) else {
+            panic!("Expected an addrv2 message");
+        };
+        assert_eq!(messages[0].addr, advertised.address);
+        assert_eq!(messages[0].time, 7);
+
+        let i2p = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0";
+        assert!(matches!(
+            parse_advertised(i2p).unwrap().address,
+            AddrV2::I2p(_)
+        ));
+        assert!(matches!(
+            parse_advertised("[fc00::1]:8333").unwrap().address,
+            AddrV2::Cjdns(_)
+        ));
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        for address in [
+            "10.0.0.2",
+            "example.com:8333",
+            "tooshort.onion:8333",
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+            "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkd1.b32.i2p:0",
+        ] {
+            assert!(parse_advertised(address).is_err(), "{address}");
+        }
+    }
+
+    #[test]
+    fn base32_decodes_whole_bytes() {
+        assert_eq!(base32_decode("mzxw6"), Some(b"foo".to_vec()));
+        assert_eq!(base32_decode("mzxw6yq"), Some(b"foob".to_vec()));
+        assert_eq!(base32_decode("MZXW6"), None);
+    }
+}
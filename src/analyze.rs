@@ -0,0 +1,125 @@
+use crate::report::BINARY_LOG_MAGIC;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::fs::read;
+use std::path::Path;
+use std::time::Duration;
+
+/// Size of a record in a binary response log.
+const RECORD_SIZE: usize = 16;
+
+/// A response read back from an event log or a response log.
+#[derive(Clone, Debug)]
+pub struct RecordedResponse {
+    /// Receive time, in nanoseconds since the Unix epoch.
+    pub ts_ns: u64,
+    pub conn: usize,
+    /// Size on the wire, missing from event logs written before it was recorded.
+    pub bytes: Option<usize>,
+    /// Time since the request was sent. Only event logs record it.
+    pub latency: Option<Duration>,
+}
+
+impl RecordedResponse {
+    /// The response an event of an event log records, if it is a response event.
+    pub fn from_event(event: &Value) -> Result<Option<Self>> {
+        if event["event"] != "response" {
+            return Ok(None);
+        }
+        let field = |name: &str| {
+            event[name]
+                .as_u64()
+                .ok_or_else(|| anyhow!("response event without {name}"))
+        };
+        Ok(Some(RecordedResponse {
+            ts_ns: field("ts_us")? * 1000,
+            conn: field("conn")? as usize,
+            bytes: event["bytes"].as_u64().map(|bytes| bytes as usize),
+            latency: Some(Duration::from_micros(field("latency_us")?)),
+        }))
+    }
+}
+
+/// Reads the responses recorded at `path`, oldest first, telling a JSONL event log, a CSV
+/// response log and a binary response log apart by the header or the first object they start
+/// with.
+pub fn read_responses(path: &Path) -> Result<Vec<RecordedResponse>> {
+    let contents = read(path)?;
+    let invalid = |reason: &str| anyhow!("Invalid log {}: {reason}", path.display());
+    let mut responses = if contents.starts_with(b"ts_ns,conn,bytes\n") {
+        String::from_utf8(contents)?
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<_> = line.split(',').collect();
+                let [ts_ns, conn, bytes] = fields[..] else {
+                    return Err(invalid("expected 3 fields per line"));
+                };
+                Ok(RecordedResponse {
+                    ts_ns: ts_ns.parse()?,
+                    conn: conn.parse()?,
+                    bytes: Some(bytes.parse()?),
+                    latency: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else if contents.starts_with(b"{") {
+        let mut responses = Vec::new();
+        for line in String::from_utf8(contents)?.lines() {
+            let event: Value = serde_json::from_str(line)?;
+            let response =
+                RecordedResponse::from_event(&event).map_err(|e| invalid(&e.to_string()))?;
+            responses.extend(response);
+        }
+        responses
+    } else if let Some(records) = contents.strip_prefix(BINARY_LOG_MAGIC) {
+        if records.len() % RECORD_SIZE != 0 {
+            return Err(invalid("binary response log ends in a partial record"));
+        }
+        records
+            .chunks(RECORD_SIZE)
+            .map(|record| {
+                let (ts_ns, rest) = record.split_at(8);
+                let (conn, bytes) = rest.split_at(4);
+                RecordedResponse {
+                    ts_ns: u64::from_le_bytes(ts_ns.try_into().unwrap()),
+                    conn: u32::from_le_bytes(conn.try_into().unwrap()) as usize,
+                    bytes: Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize),
+                    latency: None,
+                }
+            })
+            .collect()
+    } else {
+        return Err(invalid(
+            "neither a JSONL event log nor a CSV or binary response log",
+        ));
+    };
+    responses.sort_by_key(|response| response.ts_ns);
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn binary_logs_are_told_apart_by_their_header() {
+        let path = std::env::temp_dir().join(format!("analyze-{}.bin", std::process::id()));
+        // A timestamp whose first byte reads as the start of a JSON object.
+        let record = [
+            &(b'{' as u64).to_le_bytes()[..],
+            &3u32.to_le_bytes(),
+            &80u32.to_le_bytes(),
+        ];
+        fs::write(&path, [&BINARY_LOG_MAGIC[..], &record.concat()].concat()).unwrap();
+        let responses = read_responses(&path).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].ts_ns, b'{' as u64);
+        assert_eq!((responses[0].conn, responses[0].bytes), (3, Some(80)));
+
+        fs::write(&path, record.concat()).unwrap();
+        assert!(read_responses(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::handler::{served, MessageHandler};
+use anyhow::Result;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+/// A [`MessageHandler`] for async embedders, writing its replies to a [`Sink`] of messages rather
+/// than a blocking writer, so it can answer the target over a connection framed with
+/// [`MessageCodec`](crate::codec::MessageCodec).
+#[derive(Clone, Debug)]
+pub struct AsyncMessageHandler {
+    handler: MessageHandler,
+}
+
+impl AsyncMessageHandler {
+    pub fn new(handler: MessageHandler) -> Self {
+        Self { handler }
+    }
+
+    /// Answers `message` if it is a request, returning the number of blocks served.
+    pub async fn handle<S>(&self, sink: &mut S, message: &NetworkMessage) -> Result<usize>
+    where
+        S: Sink<NetworkMessage> + Unpin,
+        S::Error: Into<anyhow::Error>,
+    {
+        let replies = self.handler.replies(message);
+        let served = served(&replies);
+        for reply in replies {
+            sink.feed(reply.payload).await.map_err(Into::into)?;
+        }
+        sink.flush().await.map_err(Into::into)?;
+        Ok(served)
+    }
+
+    /// Answers every message read from `stream` until it ends, returning the number of blocks
+    /// served.
+    pub async fn run<St, E, S>(&self, mut stream: St, mut sink: S) -> Result<usize>
+    where
+        St: Stream<Item = Result<RawNetworkMessage, E>> + Unpin,
+        E: Into<anyhow::Error>,
+        S: Sink<NetworkMessage> + Unpin,
+        S::Error: Into<anyhow::Error>,
+    {
+        let mut served = 0;
+        while let Some(message) = stream.next().await {
+            let message = message.map_err(Into::into)?;
+            served += self.handle(&mut sink, &message.payload).await?;
+        }
+        Ok(served)
+    }
+
+    pub fn handler(&self) -> &MessageHandler {
+        &self.handler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Error;
+    use bitcoin::hashes::Hash;
+    use bitcoin::network::message_blockdata::Inventory;
+    use bitcoin::{BlockHash, Network};
+    use futures::executor::block_on;
+    use futures::stream;
+
+    #[test]
+    fn async_handler_answers_into_a_sink() {
+        let messages = [
+            NetworkMessage::Ping(7),
+            NetworkMessage::Verack,
+            NetworkMessage::GetData(vec![Inventory::Block(BlockHash::all_zeros())]),
+        ];
+        let stream = stream::iter(messages.map(|payload| {
+            Ok::<_, Error>(RawNetworkMessage {
+                magic: Network::Bitcoin.magic(),
+                payload,
+            })
+        }));
+        let handler = AsyncMessageHandler::new(MessageHandler::new(Network::Bitcoin.magic(), None));
+        let mut sink = Vec::new();
+        let served = block_on(handler.run(stream, &mut sink));
+
+        assert_eq!(served.unwrap(), 0);
+        assert_eq!(
+            sink,
+            vec![
+                NetworkMessage::Pong(7),
+                NetworkMessage::NotFound(vec![Inventory::Block(BlockHash::all_zeros())]),
+            ]
+        );
+    }
+}
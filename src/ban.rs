@@ -0,0 +1,54 @@
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Transport;
+use anyhow::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// How the target treats a fresh connection from us.
+pub enum ConnectionStatus {
+    /// The handshake completed, so we are neither banned nor discouraged.
+    Accepted,
+    /// The TCP connection itself was refused or timed out.
+    Refused(Error),
+    /// The connection was accepted but dropped before the handshake completed, which is how a
+    /// node treats banned or discouraged peers when it has no inbound slots to spare.
+    Dropped(Error),
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionStatus::Accepted => write!(f, "target accepted a fresh connection"),
+            ConnectionStatus::Refused(e) => write!(f, "target refused a fresh connection: {e:#}"),
+            ConnectionStatus::Dropped(e) => {
+                write!(
+                    f,
+                    "target dropped a fresh connection during handshake: {e:#}"
+                )
+            }
+        }
+    }
+}
+
+/// Opens a fresh connection and performs a handshake to detect whether we were banned or
+/// discouraged by the target.
+pub fn check_connection(
+    transport: &Transport,
+    address: &str,
+    magic: u32,
+    timeout: Duration,
+) -> ConnectionStatus {
+    let mut stream = match transport.connect_timeout(address, timeout) {
+        Ok(stream) => stream,
+        Err(e) => return ConnectionStatus::Refused(e),
+    };
+    let handshake = stream
+        .set_read_timeout(Some(timeout))
+        .map_err(Error::from)
+        .and_then(|_| perform_handshake(&mut stream, &MessageHandler::new(magic, None)));
+    match handshake {
+        Ok(_) => ConnectionStatus::Accepted,
+        Err(e) => ConnectionStatus::Dropped(e),
+    }
+}
@@ -0,0 +1,149 @@
+use crate::generator::too_deep;
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Connection;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::encode::Error;
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_compact_blocks::GetBlockTxn;
+use bitcoin::util::bip152::{BlockTransactionsRequest, HeaderAndShortIds, ShortId};
+use bitcoin::{BlockHash, Transaction};
+use std::collections::HashSet;
+use std::io::{BufReader, ErrorKind, Write};
+use std::time::Duration;
+
+/// How long to wait for each reply, so a target that never answers fails the measurement.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes on the wire, headers included, to fetch one block in full and as a compact block. Each
+/// flow counts its request as well as its reply.
+#[derive(Debug, Default)]
+pub struct BandwidthReport {
+    /// Size of the witness block getdata and the block message answering it.
+    pub full: usize,
+    /// Size of the compact block getdata and the cmpctblock message answering it.
+    pub compact: usize,
+    /// Size of the getblocktxn and blocktxn messages fetching the missing transactions, if any
+    /// were missing.
+    pub blocktxn: usize,
+    /// Transactions in the block.
+    pub txs: usize,
+    /// Transactions neither prefilled in the compact block nor in the given mempool.
+    pub missing: usize,
+}
+
+impl BandwidthReport {
+    /// Bytes of the whole compact block flow.
+    pub fn compact_total(&self) -> usize {
+        self.compact + self.blocktxn
+    }
+}
+
+/// Fetches `block_hash` in full and as a compact block, then requests the transactions a node
+/// whose mempool holds `mempool` would be missing, measuring the bytes each flow transfers.
+pub fn measure_bandwidth(
+    stream: &mut Connection,
+    magic: u32,
+    block_hash: BlockHash,
+    mempool: &[Transaction],
+) -> Result<BandwidthReport> {
+    let handler = MessageHandler::new(magic, None);
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    perform_handshake(stream, &handler)?;
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    let mut report = BandwidthReport::default();
+
+    let payload = NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)]);
+    let (size, reply) = exchange(stream, &mut reader, &handler, payload, "block")?;
+    let NetworkMessage::Block(block) = reply else {
+        unreachable!()
+    };
+    report.full = size;
+    report.txs = block.txdata.len();
+
+    let payload = NetworkMessage::GetData(vec![Inventory::CompactBlock(block_hash)]);
+    let (size, reply) = exchange(stream, &mut reader, &handler, payload, "cmpctblock")?;
+    let NetworkMessage::CmpctBlock(compact) = reply else {
+        unreachable!()
+    };
+    report.compact = size;
+
+    let indexes = missing_indexes(&compact.compact_block, mempool);
+    report.missing = indexes.len();
+    if !indexes.is_empty() {
+        let payload = NetworkMessage::GetBlockTxn(GetBlockTxn {
+            txs_request: BlockTransactionsRequest {
+                block_hash,
+                indexes,
+            },
+        });
+        let (size, _) = exchange(stream, &mut reader, &handler, payload, "blocktxn")?;
+        report.blocktxn = size;
+    }
+    Ok(report)
+}
+
+/// Sends `payload` and waits for a reply with `command`, returning the size of both on the wire.
+fn exchange(
+    stream: &mut Connection,
+    reader: &mut BufReader<Connection>,
+    handler: &MessageHandler,
+    payload: NetworkMessage,
+    command: &str,
+) -> Result<(usize, NetworkMessage)> {
+    let message = RawNetworkMessage {
+        magic: handler.magic(),
+        payload,
+    };
+    let request = serialize(&message);
+    stream.write_all(&request)?;
+    loop {
+        let reply = RawNetworkMessage::consensus_decode(&mut *reader).map_err(|e| match e {
+            Error::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                anyhow!("Target sent no {command} within {REPLY_TIMEOUT:?}")
+            }
+            e => e.into(),
+        })?;
+        if reply.cmd() == command {
+            return Ok((request.len() + serialize(&reply).len(), reply.payload));
+        }
+        match reply.payload {
+            // Blocks outside the compact block window are served in full instead.
+            NetworkMessage::Block(_) if command != "block" => {
+                return Err(too_deep(&[command]));
+            }
+            NetworkMessage::NotFound(_) => {
+                return Err(anyhow!("Target does not have the block"));
+            }
+            payload => {
+                handler.handle(stream, &payload)?;
+            }
+        }
+    }
+}
+
+/// Indexes in the block of the transactions `block` only gives short IDs for that match none
+/// of `mempool`.
+fn missing_indexes(block: &HeaderAndShortIds, mempool: &[Transaction]) -> Vec<u64> {
+    let keys = ShortId::calculate_siphash_keys(&block.header, block.nonce);
+    let known: HashSet<_> = mempool
+        .iter()
+        .map(|tx| ShortId::with_siphash_keys(&tx.wtxid(), keys))
+        .collect();
+    let mut prefilled = HashSet::new();
+    let mut index = None;
+    for tx in &block.prefilled_txs {
+        let next = index.map_or(0, |index| index + 1) + tx.idx as u64;
+        prefilled.insert(next);
+        index = Some(next);
+    }
+    let len = (block.short_ids.len() + block.prefilled_txs.len()) as u64;
+    (0..len)
+        .filter(|index| !prefilled.contains(index))
+        .zip(&block.short_ids)
+        .filter(|(_, short_id)| !known.contains(short_id))
+        .map(|(index, _)| index)
+        .collect()
+}
@@ -0,0 +1,75 @@
+use crate::generator::{Inspector, InventoryRequests};
+use crate::InventoryType;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::{Block, Txid};
+use std::sync::Mutex;
+
+/// Aggregates over every block received.
+#[derive(Clone, Debug, Default)]
+pub struct BlockStats {
+    pub blocks: usize,
+    pub txs: usize,
+    /// Total weight in weight units.
+    pub weight: u64,
+    /// Bytes of witness data, i.e. what each transaction adds to its stripped size.
+    pub witness_bytes: u64,
+    /// Total serialized size.
+    pub size: u64,
+    /// The largest transaction by serialized size, and that size.
+    pub largest_tx: Option<(Txid, usize)>,
+}
+
+impl BlockStats {
+    fn add(&mut self, block: &Block) {
+        self.blocks += 1;
+        self.txs += block.txdata.len();
+        self.weight += block.weight() as u64;
+        self.size += block.size() as u64;
+        for tx in &block.txdata {
+            let size = tx.size();
+            self.witness_bytes += (size - tx.strippedsize()) as u64;
+            if self.largest_tx.is_none_or(|(_, largest)| size > largest) {
+                self.largest_tx = Some((tx.txid(), size));
+            }
+        }
+    }
+}
+
+/// Requests inventory like [`InventoryRequests`] and fully decodes every block received,
+/// accumulating [`BlockStats`] about what the target served.
+#[derive(Debug)]
+pub struct BlockStatsCollector {
+    requests: InventoryRequests,
+    stats: Mutex<BlockStats>,
+}
+
+impl BlockStatsCollector {
+    pub fn new(template: Vec<InventoryType>) -> Self {
+        Self {
+            requests: InventoryRequests::new(template),
+            stats: Mutex::default(),
+        }
+    }
+
+    pub fn stats(&self) -> BlockStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+impl Inspector for BlockStatsCollector {
+    type Inner = InventoryRequests;
+
+    fn inner(&self) -> &InventoryRequests {
+        &self.requests
+    }
+
+    fn inspect(&self, command: &str, payload: &[u8]) -> Result<()> {
+        if command == "block" {
+            let block: Block = deserialize(payload)
+                .map_err(|e| anyhow!("Target sent an undecodable block: {e}"))?;
+            self.stats.lock().unwrap().add(&block);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use crate::generator::{BlockTxnRequests, Inspector};
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::network::message_compact_blocks::BlockTxn;
+use bitcoin::{Block, BlockHash, Txid};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// How the blocktxn responses received compare to the transactions we requested.
+#[derive(Clone, Debug, Default)]
+pub struct BlockTxnReport {
+    /// Responses carrying exactly the requested transactions, in order.
+    pub valid: usize,
+    /// Responses carrying fewer transactions than requested.
+    pub short: usize,
+    /// Responses carrying the requested transactions in another order.
+    pub reordered: usize,
+    /// Responses carrying other transactions, too many, or for a block we did not request.
+    pub mismatched: usize,
+}
+
+/// Requests the transactions at `indexes` of blocks via getblocktxn and checks each blocktxn
+/// response against the txids at those indexes of the blocks.
+#[derive(Debug)]
+pub struct BlockTxnVerifier {
+    requests: BlockTxnRequests,
+    expected: HashMap<BlockHash, Vec<Txid>>,
+    report: Mutex<BlockTxnReport>,
+}
+
+impl BlockTxnVerifier {
+    /// Expects the transactions at `indexes` of `blocks`, which must hold every block requested.
+    pub fn new(indexes: Vec<u64>, blocks: &[Block]) -> Result<Self> {
+        let expected = blocks
+            .iter()
+            .map(|block| {
+                let txids = indexes
+                    .iter()
+                    .map(|index| {
+                        let tx = block.txdata.get(*index as usize).ok_or_else(|| {
+                            anyhow!(
+                                "Block {} has no transaction at index {index}",
+                                block.block_hash()
+                            )
+                        })?;
+                        Ok(tx.txid())
+                    })
+                    .collect::<Result<_>>()?;
+                Ok((block.block_hash(), txids))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            requests: BlockTxnRequests::new(indexes),
+            expected,
+            report: Mutex::default(),
+        })
+    }
+
+    pub fn report(&self) -> BlockTxnReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn verify(&self, block_txn: &BlockTxn) {
+        let received: Vec<_> = block_txn
+            .transactions
+            .transactions
+            .iter()
+            .map(|tx| tx.txid())
+            .collect();
+        let mut report = self.report.lock().unwrap();
+        let Some(expected) = self.expected.get(&block_txn.transactions.block_hash) else {
+            report.mismatched += 1;
+            return;
+        };
+        let expected_set: HashSet<_> = expected.iter().collect();
+        let all_requested = received.iter().all(|txid| expected_set.contains(txid));
+        if received == *expected {
+            report.valid += 1;
+        } else if !all_requested || received.len() > expected.len() {
+            report.mismatched += 1;
+        } else if received.len() < expected.len() {
+            report.short += 1;
+        } else {
+            report.reordered += 1;
+        }
+    }
+}
+
+impl Inspector for BlockTxnVerifier {
+    type Inner = BlockTxnRequests;
+
+    fn inner(&self) -> &BlockTxnRequests {
+        &self.requests
+    }
+
+    fn inspect(&self, _command: &str, payload: &[u8]) -> Result<()> {
+        let block_txn: BlockTxn = deserialize(payload)
+            .map_err(|e| anyhow!("Target sent an undecodable blocktxn: {e}"))?;
+        self.verify(&block_txn);
+        Ok(())
+    }
+}
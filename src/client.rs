@@ -0,0 +1,158 @@
+use crate::frames::Frames;
+use crate::handler::MessageHandler;
+use crate::transport::{Connection, Transport};
+use crate::version::VersionBuilder;
+use crate::{check_magic, perform_handshake};
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::serialize;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_network::VersionMessage;
+use std::io::{Read, Write};
+
+/// A plain connection to one peer, for reusing the tool's connection and handshake logic to
+/// exchange arbitrary messages, e.g. to probe or monitor a node.
+///
+/// Received messages are answered like a load-generating connection answers them, so pings keep
+/// the peer from dropping us, and are then returned all the same.
+#[derive(Debug)]
+pub struct P2pClient<S = Connection> {
+    stream: S,
+    handler: MessageHandler,
+    peer: Option<VersionMessage>,
+}
+
+impl P2pClient {
+    /// Connects to `address` over `transport`, without performing the handshake yet.
+    pub fn connect(transport: &Transport, address: &str, magic: u32) -> Result<Self> {
+        Ok(Self::new(transport.connect(address)?, magic))
+    }
+}
+
+impl<S: Read + Write> P2pClient<S> {
+    /// Wraps an already connected stream.
+    pub fn new(stream: S, magic: u32) -> Self {
+        Self {
+            stream,
+            handler: MessageHandler::new(magic, None),
+            peer: None,
+        }
+    }
+
+    /// Opens the handshake with `version` instead of the tool's own version message.
+    pub fn with_version(mut self, version: VersionBuilder) -> Self {
+        self.handler = self.handler.with_version(version);
+        self
+    }
+
+    /// Announces support for addrv2 messages in the handshake.
+    pub fn with_addrv2(mut self) -> Self {
+        self.handler = self.handler.with_addrv2();
+        self
+    }
+
+    /// Exchanges version and verack messages, returning the peer's version message.
+    pub fn handshake(&mut self) -> Result<&VersionMessage> {
+        let peer = perform_handshake(&mut self.stream, &self.handler)?;
+        Ok(self.peer.insert(peer))
+    }
+
+    /// The peer's version message, once the handshake completed.
+    pub fn peer(&self) -> Option<&VersionMessage> {
+        self.peer.as_ref()
+    }
+
+    pub fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        let message = RawNetworkMessage {
+            magic: self.handler.magic(),
+            payload,
+        };
+        self.stream.write_all(&serialize(&message))?;
+        Ok(())
+    }
+
+    /// Waits for the next message from the peer, answering it first if it is a request.
+    pub fn recv(&mut self) -> Result<NetworkMessage> {
+        self.messages()
+            .next()
+            .unwrap_or_else(|| Err(anyhow!("Target closed the connection")))
+    }
+
+    /// The messages the peer sends from now on, each answered as [`recv`](Self::recv) does.
+    pub fn messages(&mut self) -> Messages<'_, S> {
+        Messages {
+            client: self,
+            failed: false,
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<NetworkMessage>> {
+        let Some(frame) = Frames::new(&mut self.stream).next().transpose()? else {
+            return Ok(None);
+        };
+        check_magic(&frame.header, self.handler.magic())?;
+        let message = frame.decode()?;
+        self.handler.handle(&mut self.stream, &message.payload)?;
+        Ok(Some(message.payload))
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Iterator over the messages a [`P2pClient`]'s peer sends. Ends when the peer closes the
+/// connection between messages; any other failure is returned as an error item, after which it
+/// ends as well.
+#[derive(Debug)]
+pub struct Messages<'a, S> {
+    client: &'a mut P2pClient<S>,
+    failed: bool,
+}
+
+impl<S: Read + Write> Iterator for Messages<'_, S> {
+    type Item = Result<NetworkMessage>;
+
+    fn next(&mut self) -> Option<Result<NetworkMessage>> {
+        if self.failed {
+            return None;
+        }
+        let message = self.client.read_message().transpose();
+        self.failed = matches!(message, Some(Err(_)));
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{sent_messages, MockStream, HANDSHAKE};
+    use bitcoin::Network;
+
+    #[test]
+    fn client_answers_pings_and_returns_every_message() {
+        let mut client = P2pClient::new(MockStream::new(HANDSHAKE), Network::Bitcoin.magic());
+        let peer = client.handshake().unwrap();
+        assert_eq!(peer.user_agent, "/Satoshi:25.0.0/");
+
+        assert_eq!(client.recv().unwrap().cmd(), "sendcmpct");
+        assert_eq!(
+            client.recv().unwrap(),
+            NetworkMessage::Ping(0x0123456789abcdef)
+        );
+        client.send(NetworkMessage::GetAddr).unwrap();
+        let rest: Vec<_> = client
+            .messages()
+            .map(|message| message.unwrap().cmd())
+            .collect();
+        assert_eq!(rest, ["feefilter"]);
+
+        let sent = sent_messages(&client.into_inner().output);
+        assert_eq!(
+            sent[2..],
+            [
+                NetworkMessage::Pong(0x0123456789abcdef),
+                NetworkMessage::GetAddr
+            ]
+        );
+    }
+}
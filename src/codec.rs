@@ -0,0 +1,85 @@
+use crate::{check_magic, parse_header, MESSAGE_HEADER_SIZE};
+use anyhow::{Error, Result};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bytes::BytesMut;
+use futures::{Stream, TryStreamExt};
+use tokio::io::AsyncRead;
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+/// Frames P2P messages of one network for tokio-util's `Framed`. Decoding fails on a message for
+/// another network, on a length over the size limit before any of its payload is buffered, and on
+/// a bad checksum.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageCodec {
+    magic: u32,
+}
+
+impl MessageCodec {
+    pub fn new(magic: u32) -> Self {
+        Self { magic }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = RawNetworkMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RawNetworkMessage>> {
+        let Some(header) = src.get(..MESSAGE_HEADER_SIZE) else {
+            return Ok(None);
+        };
+        let header: &[u8; MESSAGE_HEADER_SIZE] = header.try_into().unwrap();
+        check_magic(header, self.magic)?;
+        let (_, len) = parse_header(header)?;
+        let size = MESSAGE_HEADER_SIZE + len;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(size);
+        Ok(Some(deserialize(&frame)?))
+    }
+}
+
+impl Encoder<NetworkMessage> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, payload: NetworkMessage, dst: &mut BytesMut) -> Result<()> {
+        let message = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        dst.extend_from_slice(&serialize(&message));
+        Ok(())
+    }
+}
+
+/// The messages read from `reader`, framed with a [`MessageCodec`] for the network of `magic`.
+/// Ends when `reader` does, with an error item if it ends mid message.
+pub fn messages<R: AsyncRead>(reader: R, magic: u32) -> impl Stream<Item = Result<NetworkMessage>> {
+    FramedRead::new(reader, MessageCodec::new(magic)).map_ok(|message| message.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+
+    #[test]
+    fn codec_frames_split_messages_and_rejects_other_networks() {
+        let mut codec = MessageCodec::new(Network::Bitcoin.magic());
+        let mut buf = BytesMut::new();
+        codec.encode(NetworkMessage::Ping(7), &mut buf).unwrap();
+        let mut rest = buf.split_off(MESSAGE_HEADER_SIZE + 3);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.unsplit(rest.split());
+        let message = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(message.payload, NetworkMessage::Ping(7));
+        assert!(buf.is_empty());
+
+        let mut other = MessageCodec::new(Network::Testnet.magic());
+        other.encode(NetworkMessage::Ping(7), &mut buf).unwrap();
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Length of the windows responses are counted in.
+pub const WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of consecutive windows averaged to establish the steady-state throughput.
+const STEADY_WINDOWS: usize = 5;
+
+/// Notices when the response rate stays below a fraction of its steady state, which happens
+/// when the target is overloaded or has deprioritized us, so a run need not wait for every
+/// remaining response to trickle in.
+pub struct CollapseDetector {
+    fraction: f64,
+    /// Number of windows the rate must stay low for.
+    sustain: usize,
+    window_start: Instant,
+    responses: usize,
+    /// Responses per closed window, most recent last, as many as needed for the averages.
+    windows: VecDeque<usize>,
+    closed: usize,
+    /// Highest throughput averaged over [`STEADY_WINDOWS`] windows so far.
+    steady: f64,
+}
+
+/// Why a run was deemed collapsed.
+pub struct Collapse {
+    /// Responses per second over the sustain period.
+    pub recent: f64,
+    /// Responses per second at steady state.
+    pub steady: f64,
+    pub sustained: Duration,
+}
+
+impl fmt::Display for Collapse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "throughput collapsed to {:.1} responses/s for {:.0?}, down from a steady state of \
+             {:.1} responses/s; the target is overloaded or deprioritizing us",
+            self.recent, self.sustained, self.steady
+        )
+    }
+}
+
+impl CollapseDetector {
+    pub fn new(fraction: f64, sustain: Duration) -> Self {
+        let sustain = (sustain.as_secs_f64() / WINDOW.as_secs_f64())
+            .ceil()
+            .max(1.0) as usize;
+        CollapseDetector {
+            fraction,
+            sustain,
+            window_start: Instant::now(),
+            responses: 0,
+            windows: VecDeque::new(),
+            closed: 0,
+            steady: 0.0,
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.close_windows();
+        self.responses += 1;
+    }
+
+    /// Returns the diagnosis once the response rate has collapsed.
+    pub fn check(&mut self) -> Option<Collapse> {
+        self.close_windows();
+        // The steady state must have been measured before the sustain period started.
+        if self.closed < STEADY_WINDOWS + self.sustain || self.steady == 0.0 {
+            return None;
+        }
+        let recent = self.average(self.sustain);
+        (recent < self.fraction * self.steady).then(|| Collapse {
+            recent,
+            steady: self.steady,
+            sustained: WINDOW * self.sustain as u32,
+        })
+    }
+
+    fn close_windows(&mut self) {
+        while self.window_start.elapsed() >= WINDOW {
+            self.window_start += WINDOW;
+            self.windows.push_back(self.responses);
+            self.responses = 0;
+            self.closed += 1;
+            if self.windows.len() > STEADY_WINDOWS.max(self.sustain) {
+                self.windows.pop_front();
+            }
+            if self.closed >= STEADY_WINDOWS {
+                self.steady = self.steady.max(self.average(STEADY_WINDOWS));
+            }
+        }
+    }
+
+    /// Responses per second over the last `windows` closed windows.
+    fn average(&self, windows: usize) -> f64 {
+        let responses: usize = self.windows.iter().rev().take(windows).sum();
+        responses as f64 / (WINDOW.as_secs_f64() * windows as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Closes the current window as if `responses` arrived in it and it had ended.
+    fn pass_window(detector: &mut CollapseDetector, responses: usize) {
+        detector.responses = responses;
+        detector.window_start -= WINDOW;
+        detector.close_windows();
+    }
+
+    #[test]
+    fn collapse_is_a_sustained_drop_below_the_steady_state() {
+        let mut detector = CollapseDetector::new(0.2, Duration::from_secs(2));
+        for _ in 0..STEADY_WINDOWS {
+            pass_window(&mut detector, 100);
+        }
+        pass_window(&mut detector, 10);
+        // One low window is not yet sustained.
+        assert!(detector.check().is_none());
+        pass_window(&mut detector, 10);
+        let collapse = detector.check().unwrap();
+        assert_eq!((collapse.recent, collapse.steady), (10.0, 100.0));
+        assert_eq!(collapse.sustained, Duration::from_secs(2));
+
+        // Recovering ends the collapse, and a drop to half is no collapse.
+        pass_window(&mut detector, 50);
+        pass_window(&mut detector, 50);
+        assert!(detector.check().is_none());
+    }
+
+    #[test]
+    fn no_collapse_without_a_steady_state() {
+        let mut detector = CollapseDetector::new(0.2, Duration::from_secs(1));
+        for _ in 0..10 {
+            pass_window(&mut detector, 0);
+        }
+        assert!(detector.check().is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{anyhow, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use serde_json::{Map, Value};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the config file is read from when `--config` is not given:
+/// `$XDG_CONFIG_HOME/spam-block-reqs/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("spam-block-reqs").join("config.toml"))
+}
+
+/// Reads the settings in the TOML file at `path`.
+pub fn read(path: &Path) -> Result<Map<String, Value>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Could not read config file {}: {e}", path.display()))?;
+    toml::from_str(&contents).map_err(|e| anyhow!("Invalid config file {}: {e}", path.display()))
+}
+
+/// Turns `settings` into arguments for `command`. Keys are argument names, e.g.
+/// `block-hash = ["..."]` or `check_ban = true`, and a table named after a subcommand holds that
+/// subcommand's settings. Given `matches`, only the tables of the subcommands being run are used
+/// and settings for arguments given on the command line are left out, so those take precedence.
+///
+/// The arguments of a subcommand follow those of its parent, so they can be appended to a command
+/// line that ends within the subcommand.
+pub fn arguments(
+    settings: &Map<String, Value>,
+    command: &Command,
+    matches: Option<&ArgMatches>,
+) -> Result<Vec<OsString>> {
+    let mut arguments = Vec::new();
+    let mut subcommands = Vec::new();
+    for (key, value) in settings {
+        if let Some(subcommand) = command.find_subcommand(key) {
+            let Value::Object(settings) = value else {
+                return Err(anyhow!("Setting {key} must be a table of {key} settings"));
+            };
+            match matches.map(|matches| matches.subcommand_matches(key)) {
+                Some(Some(matches)) => subcommands.push((subcommand, settings, Some(matches))),
+                Some(None) => {}
+                None => subcommands.push((subcommand, settings, None)),
+            }
+            continue;
+        }
+
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some())
+            .ok_or_else(|| anyhow!("Unknown setting {key}"))?;
+        if matches.and_then(|matches| matches.value_source(&id)) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let long = format!("--{}", arg.get_long().unwrap_or_default());
+        let values = match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Bool(set)) => {
+                if *set {
+                    arguments.push(long.into());
+                }
+                continue;
+            }
+            (ArgAction::Append, Value::Array(values)) => values.iter().collect(),
+            (_, Value::Array(_) | Value::Object(_)) => {
+                return Err(anyhow!("Setting {key} takes a single value"))
+            }
+            (_, value) => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Null | Value::Array(_) | Value::Object(_) => {
+                    return Err(anyhow!("Invalid value for {key}"))
+                }
+                value => value.to_string(),
+            };
+            arguments.push(format!("{long}={value}").into());
+        }
+    }
+    for (subcommand, settings, matches) in subcommands {
+        arguments.extend(self::arguments(settings, subcommand, matches)?);
+    }
+    Ok(arguments)
+}
+
+/// `cli` with the config file's `settings` appended to it, before any `--` so that they are not
+/// taken for the trailing arguments it introduces, such as a controller's workload.
+pub fn merge(cli: &[OsString], settings: Vec<OsString>) -> Vec<OsString> {
+    let end = cli.iter().position(|arg| arg == "--").unwrap_or(cli.len());
+    let mut merged = cli[..end].to_vec();
+    merged.extend(settings);
+    merged.extend_from_slice(&cli[end..]);
+    merged
+}
+
+/// The subcommands `settings` holds tables for, outermost first, e.g.
+/// `["broadcast", "orphan-headers"]`.
+pub fn subcommand_path(settings: &Map<String, Value>, command: &Command) -> Vec<String> {
+    for (key, value) in settings {
+        if let (Some(subcommand), Value::Object(settings)) = (command.find_subcommand(key), value) {
+            let mut path = vec![key.clone()];
+            path.extend(subcommand_path(settings, subcommand));
+            return path;
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("spam-block-reqs")
+            .arg(Arg::new("network").long("network").global(true))
+            .arg(
+                Arg::new("check_ban")
+                    .long("check-ban")
+                    .action(ArgAction::SetTrue),
+            )
+            .subcommand(
+                Command::new("spam").arg(
+                    Arg::new("block_hash")
+                        .long("block-hash")
+                        .action(ArgAction::Append),
+                ),
+            )
+            .subcommand(Command::new("crawl"))
+            .subcommand(
+                Command::new("controller")
+                    .arg(Arg::new("workload").last(true).action(ArgAction::Append)),
+            )
+    }
+
+    #[test]
+    fn settings_become_arguments_unless_given_on_the_command_line() {
+        let settings: Map<String, Value> = toml::from_str(
+            r#"
+            network = "signet"
+            check_ban = true
+            [spam]
+            block-hash = ["00aa", "00bb"]
+            "#,
+        )
+        .unwrap();
+        let command = command();
+        let arguments = |matches| -> Vec<_> {
+            arguments(&settings, &command, matches)
+                .unwrap()
+                .into_iter()
+                .map(|argument| argument.into_string().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            arguments(None),
+            [
+                "--check-ban",
+                "--network=signet",
+                "--block-hash=00aa",
+                "--block-hash=00bb"
+            ]
+        );
+
+        let matches =
+            command
+                .clone()
+                .get_matches_from(["spam-block-reqs", "--network", "regtest", "crawl"]);
+        assert_eq!(arguments(Some(&matches)), ["--check-ban"]);
+        assert_eq!(subcommand_path(&settings, &command), ["spam"]);
+
+        let unknown: Map<String, Value> = toml::from_str("bits = 1").unwrap();
+        assert!(self::arguments(&unknown, &command, None).is_err());
+        let listed: Map<String, Value> = toml::from_str("network = [\"a\"]").unwrap();
+        assert!(self::arguments(&listed, &command, None).is_err());
+    }
+
+    #[test]
+    fn settings_go_before_a_controller_workload() {
+        let settings: Map<String, Value> = toml::from_str("network = \"signet\"").unwrap();
+        let command = command();
+        let cli: Vec<OsString> = ["spam-block-reqs", "controller", "--", "spam", "--network=x"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let matches = command.clone().get_matches_from(&cli);
+        let settings = arguments(&settings, &command, Some(&matches)).unwrap();
+        let merged = command.clone().get_matches_from(merge(&cli, settings));
+        assert_eq!(
+            merged.get_one::<String>("network").map(String::as_str),
+            Some("signet")
+        );
+        let workload: Vec<_> = merged
+            .subcommand_matches("controller")
+            .unwrap()
+            .get_many::<String>("workload")
+            .unwrap()
+            .collect();
+        assert_eq!(workload, ["spam", "--network=x"]);
+
+        let plain = [OsString::from("spam-block-reqs")];
+        assert_eq!(merge(&plain, vec![OsString::from("--check-ban")]).len(), 2);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::client::P2pClient;
+use crate::frames::{timed_out, Frames};
+use crate::handler::{MessageHandler, RelayPreferences};
+use crate::headers::{block_depth, is_pruned, NETWORK_LIMITED_BLOCKS};
+use crate::mine::unknown_block_hash;
+use crate::perform_handshake;
+use crate::transport::{Connection, Transport};
+use crate::tx::unknown_txid;
+use crate::version::VersionBuilder;
+use crate::PeerVersion;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::PROTOCOL_VERSION;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_INV_SIZE};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::BlockHash;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+/// Protocol version from which nodes send every relay preference, wtxidrelay being the latest.
+const WTXID_RELAY_VERSION: u32 = 70016;
+
+/// How long to listen after the handshake for relay preferences, which nodes send right away.
+const RELAY_PREFERENCES_WAIT: Duration = Duration::from_secs(1);
+
+/// Outcome of a single conformance check.
+pub struct CheckResult {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// How a peer answered the single block request of a [`fingerprint`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Serve {
+    /// The block arrived this long after the request.
+    Served(Duration),
+    NotFound,
+    /// The peer is pruned and the block is not among the last [`NETWORK_LIMITED_BLOCKS`] of its
+    /// chain, so the peer would disconnect us for it and none was requested.
+    Pruned,
+    TimedOut,
+    Disconnected,
+}
+
+impl fmt::Display for Serve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Serve::Served(latency) => write!(f, "{latency:.2?}"),
+            Serve::NotFound => write!(f, "notfound"),
+            Serve::Pruned => write!(f, "pruned"),
+            Serve::TimedOut => write!(f, "timed out"),
+            Serve::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// What a peer tells about itself in its handshake, and how fast it connects, handshakes and
+/// serves a block.
+#[derive(Clone, Debug)]
+pub struct Fingerprint {
+    pub peer: PeerVersion,
+    pub connect: Duration,
+    pub handshake: Duration,
+    pub serve: Serve,
+}
+
+/// Connects to `address`, handshakes and requests `block_hash` once, timing each step. A pruned
+/// peer is first asked for the headers on top of the block, and only asked for the block if it is
+/// recent enough to keep it. Every step gives up after `timeout`.
+pub fn fingerprint(
+    transport: &Transport,
+    address: &str,
+    magic: u32,
+    block_hash: BlockHash,
+    timeout: Duration,
+) -> Result<Fingerprint> {
+    let handler = MessageHandler::new(magic, None);
+    let start = Instant::now();
+    let mut stream = transport.connect_timeout(address, timeout)?;
+    let connect = start.elapsed();
+    stream.set_read_timeout(Some(timeout))?;
+    let start = Instant::now();
+    let peer = PeerVersion::from(&perform_handshake(&mut stream, &handler)?);
+    let handshake = start.elapsed();
+    let recent = |depth| matches!(depth, Some(depth) if depth < NETWORK_LIMITED_BLOCKS);
+    if is_pruned(peer.services)
+        && !recent(block_depth(
+            &mut P2pClient::new(&mut stream, magic),
+            block_hash,
+        )?)
+    {
+        return Ok(Fingerprint {
+            peer,
+            connect,
+            handshake,
+            serve: Serve::Pruned,
+        });
+    }
+
+    let start = Instant::now();
+    let request = Inventory::WitnessBlock(block_hash);
+    send(
+        &mut stream,
+        &handler,
+        NetworkMessage::GetData(vec![request]),
+    )?;
+    let deadline = start + timeout;
+    let mut frames = Frames::new(stream.try_clone()?);
+    let serve = loop {
+        match receive(&mut frames, deadline)? {
+            Reply::Message(NetworkMessage::Block(block)) if block.block_hash() == block_hash => {
+                break Serve::Served(start.elapsed())
+            }
+            Reply::Message(NetworkMessage::NotFound(inventory)) if inventory.contains(&request) => {
+                break Serve::NotFound
+            }
+            Reply::Message(message) => {
+                handler.handle(&mut stream, &message)?;
+            }
+            Reply::Timeout => break Serve::TimedOut,
+            Reply::Disconnected => break Serve::Disconnected,
+        }
+    };
+    Ok(Fingerprint {
+        peer,
+        connect,
+        handshake,
+        serve,
+    })
+}
+
+/// What a check observed while waiting for a reply.
+pub(crate) enum Reply {
+    Message(NetworkMessage),
+    Timeout,
+    Disconnected,
+}
+
+type Check = fn(&mut Connection, &MessageHandler, BlockHash, Duration) -> Result<(bool, String)>;
+
+const CHECKS: [(&str, Check); 5] = [
+    ("handshake-order", check_handshake_order),
+    ("ping-pong", check_ping_pong),
+    ("getdata-unknown-notfound", check_getdata_unknown),
+    ("getheaders-from-genesis", check_getheaders),
+    ("oversized-inv-rejected", check_oversized_inv),
+];
+
+/// Runs every check against `address` on a fresh connection each, so a check that gets us
+/// disconnected does not affect the others.
+pub fn run_checks(
+    transport: &Transport,
+    address: &str,
+    magic: u32,
+    genesis: BlockHash,
+    timeout: Duration,
+) -> Vec<CheckResult> {
+    let handler = MessageHandler::new(magic, None);
+    CHECKS
+        .iter()
+        .map(|(name, check)| {
+            let result = transport.connect(address).and_then(|mut stream| {
+                stream.set_read_timeout(Some(timeout))?;
+                check(&mut stream, &handler, genesis, timeout)
+            });
+            let (passed, detail) = result.unwrap_or_else(|e| (false, format!("error: {e:#}")));
+            CheckResult {
+                name,
+                passed,
+                detail,
+            }
+        })
+        .collect()
+}
+
+/// Handshakes announcing a protocol version recent enough to be sent every relay preference, and
+/// collects those the target states around the handshake.
+pub fn relay_preferences(
+    transport: &Transport,
+    address: &str,
+    magic: u32,
+    timeout: Duration,
+) -> Result<RelayPreferences> {
+    let handler = MessageHandler::new(magic, None)
+        .with_version(VersionBuilder::default().with_version(WTXID_RELAY_VERSION));
+    let mut stream = transport.connect(address)?;
+    stream.set_read_timeout(Some(timeout))?;
+    perform_handshake(&mut stream, &handler)?;
+    let deadline = Instant::now() + RELAY_PREFERENCES_WAIT.min(timeout);
+    let mut frames = Frames::new(stream.try_clone()?);
+    while let Reply::Message(message) = receive(&mut frames, deadline)? {
+        handler.handle(&mut stream, &message)?;
+    }
+    Ok(handler.relay_preferences())
+}
+
+/// The target must send its version before its verack.
+fn check_handshake_order(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    _genesis: BlockHash,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    let version = VersionBuilder::default().build()?;
+    send(stream, handler, NetworkMessage::Version(version))?;
+    let mut order = Vec::new();
+    let deadline = Instant::now() + timeout;
+    let mut frames = Frames::new(stream.try_clone()?);
+    while !order.contains(&"verack") {
+        match receive(&mut frames, deadline)? {
+            Reply::Message(NetworkMessage::Version(_)) => {
+                order.push("version");
+                send(stream, handler, NetworkMessage::Verack)?;
+            }
+            Reply::Message(NetworkMessage::Verack) => order.push("verack"),
+            Reply::Message(_) => {}
+            Reply::Timeout => return Ok((false, format!("timed out after {order:?}"))),
+            Reply::Disconnected => return Ok((false, format!("disconnected after {order:?}"))),
+        }
+    }
+    Ok((order == ["version", "verack"], order.join(" then ")))
+}
+
+/// A ping must be answered by a pong with the same nonce.
+fn check_ping_pong(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    _genesis: BlockHash,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    perform_handshake(stream, handler)?;
+    let nonce = 0x5eed;
+    send(stream, handler, NetworkMessage::Ping(nonce))?;
+    expect(stream, handler, timeout, |message| match message {
+        NetworkMessage::Pong(n) if *n == nonce => Some("pong with matching nonce".to_string()),
+        _ => None,
+    })
+}
+
+/// A getdata for a transaction the target does not have must be answered with notfound.
+fn check_getdata_unknown(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    _genesis: BlockHash,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    perform_handshake(stream, handler)?;
+    let txid = unknown_txid();
+    send(
+        stream,
+        handler,
+        NetworkMessage::GetData(vec![Inventory::Transaction(txid)]),
+    )?;
+    expect(stream, handler, timeout, |message| match message {
+        NetworkMessage::NotFound(inventory)
+            if inventory.contains(&Inventory::Transaction(txid)) =>
+        {
+            Some("notfound for unknown tx".to_string())
+        }
+        _ => None,
+    })
+}
+
+/// A getheaders starting at genesis must be answered with a non-empty headers message.
+fn check_getheaders(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    genesis: BlockHash,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    perform_handshake(stream, handler)?;
+    send(
+        stream,
+        handler,
+        NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: vec![genesis],
+            stop_hash: BlockHash::all_zeros(),
+        }),
+    )?;
+    expect(stream, handler, timeout, |message| match message {
+        NetworkMessage::Headers(headers) if !headers.is_empty() => {
+            Some(format!("{} headers", headers.len()))
+        }
+        _ => None,
+    })
+}
+
+/// An inv with more than the maximum number of entries must get us disconnected.
+fn check_oversized_inv(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    _genesis: BlockHash,
+    timeout: Duration,
+) -> Result<(bool, String)> {
+    perform_handshake(stream, handler)?;
+    let inventory = (0..=MAX_INV_SIZE)
+        .map(|_| Inventory::Block(unknown_block_hash()))
+        .collect();
+    if send(stream, handler, NetworkMessage::Inv(inventory)).is_err() {
+        return Ok((true, "disconnected while sending".to_string()));
+    }
+    let deadline = Instant::now() + timeout;
+    let mut frames = Frames::new(stream.try_clone()?);
+    loop {
+        match receive(&mut frames, deadline)? {
+            Reply::Message(message) => {
+                handler.handle(stream, &message)?;
+            }
+            Reply::Timeout => return Ok((false, "connection kept open".to_string())),
+            Reply::Disconnected => return Ok((true, "disconnected".to_string())),
+        }
+    }
+}
+
+/// Waits for a message `matches` accepts, answering anything else with `handler`.
+fn expect(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    timeout: Duration,
+    matches: impl Fn(&NetworkMessage) -> Option<String>,
+) -> Result<(bool, String)> {
+    let deadline = Instant::now() + timeout;
+    let mut frames = Frames::new(stream.try_clone()?);
+    loop {
+        match receive(&mut frames, deadline)? {
+            Reply::Message(message) => {
+                if let Some(detail) = matches(&message) {
+                    return Ok((true, detail));
+                }
+                handler.handle(stream, &message)?;
+            }
+            Reply::Timeout => return Ok((false, "timed out".to_string())),
+            Reply::Disconnected => return Ok((false, "disconnected".to_string())),
+        }
+    }
+}
+
+pub(crate) fn send(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    payload: NetworkMessage,
+) -> Result<()> {
+    let message = RawNetworkMessage {
+        magic: handler.magic(),
+        payload,
+    };
+    stream.write_all(&serialize(&message))?;
+    Ok(())
+}
+
+/// Reads a single message off the connection `frames` reads, keeping what arrived of one cut
+/// short by the deadline for the next call.
+pub(crate) fn receive(frames: &mut Frames<Connection>, deadline: Instant) -> Result<Reply> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Ok(Reply::Timeout);
+    }
+    frames.get_ref().set_read_timeout(Some(remaining))?;
+    let Some(frame) = frames.next() else {
+        return Ok(Reply::Disconnected);
+    };
+    match frame.and_then(|frame| frame.decode()) {
+        Ok(message) => Ok(Reply::Message(message.payload)),
+        Err(e) if timed_out(&e) => Ok(Reply::Timeout),
+        Err(e)
+            if e.downcast_ref::<io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+                )
+            }) =>
+        {
+            Ok(Reply::Disconnected)
+        }
+        Err(e) => Err(anyhow!("Invalid message from target: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{BLOCK_RESPONSES, HANDSHAKE};
+    use crate::mine::mine_chain;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::{BlockHeader, Network};
+    use std::io::Read;
+    use std::thread;
+
+    #[test]
+    fn fingerprint_times_the_handshake_and_the_block_request() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let replies = Vec::<u8>::from_hex(&format!("{HANDSHAKE}{BLOCK_RESPONSES}")).unwrap();
+            stream.write_all(&replies).unwrap();
+            // Hold the connection open until the client is done with it.
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        let genesis = genesis_block(Network::Bitcoin).block_hash();
+        let fingerprint = fingerprint(
+            &Transport::default(),
+            &address,
+            Network::Bitcoin.magic(),
+            genesis,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        peer.join().unwrap();
+
+        assert_eq!(fingerprint.peer.user_agent, "/Satoshi:25.0.0/");
+        assert!(matches!(fingerprint.serve, Serve::Served(_)));
+        assert_eq!(Serve::NotFound.to_string(), "notfound");
+    }
+
+    /// Fingerprints a pruned peer that answers the headers request with `headers`, then serves
+    /// the genesis block.
+    fn fingerprint_pruned(headers: Vec<BlockHeader>) -> Serve {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let magic = Network::Bitcoin.magic();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let version = VersionBuilder::default()
+                .with_services(ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS)
+                .build()
+                .unwrap();
+            for payload in [
+                NetworkMessage::Version(version),
+                NetworkMessage::Verack,
+                NetworkMessage::Headers(headers),
+            ] {
+                stream
+                    .write_all(&serialize(&RawNetworkMessage { magic, payload }))
+                    .unwrap();
+            }
+            stream
+                .write_all(&Vec::<u8>::from_hex(BLOCK_RESPONSES).unwrap())
+                .unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        let genesis = genesis_block(Network::Bitcoin).block_hash();
+        let fingerprint = fingerprint(
+            &Transport::default(),
+            &address,
+            magic,
+            genesis,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        peer.join().unwrap();
+        fingerprint.serve
+    }
+
+    #[test]
+    fn pruned_peers_are_only_asked_for_recent_blocks() {
+        let genesis = genesis_block(Network::Bitcoin).block_hash();
+        let recent = mine_chain(genesis, 5, 0x207fffff, 0);
+        assert!(matches!(fingerprint_pruned(recent), Serve::Served(_)));
+        // Headers not building on the block say the peer's chain has it too deep to return.
+        let unrelated = mine_chain(unknown_block_hash(), 1, 0x207fffff, 0);
+        assert_eq!(fingerprint_pruned(unrelated), Serve::Pruned);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::generator::{Inspector, InventoryRequests};
+use crate::InventoryType;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{Block, Transaction};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// How the responses received for the same block or transaction compare with each other.
+#[derive(Clone, Debug, Default)]
+pub struct ConsistencyReport {
+    /// Responses decoded and compared.
+    pub checked: usize,
+    /// Distinct blocks and transactions received.
+    pub distinct: usize,
+    /// Blocks and transactions received in more than one form, e.g. `block 0f91…`, with the
+    /// number of responses carrying each form, the form received first first.
+    pub divergent: Vec<(String, Vec<usize>)>,
+}
+
+/// What a response carries: the command, the block hash or txid, and whether witness data is
+/// included, as a block or transaction requested with and without witnesses legitimately differs.
+type Key = (&'static str, sha256d::Hash, bool);
+
+#[derive(Debug, Default)]
+struct Seen {
+    checked: usize,
+    /// The digest of each form a response was received in, and how many responses carried it.
+    forms: HashMap<Key, Vec<(sha256d::Hash, usize)>>,
+}
+
+/// Requests inventory like [`InventoryRequests`] and checks that every block and transaction
+/// received, on any connection, matches the others received for the same hash. The target serves
+/// the same data to every connection, so a divergence points to a man-in-the-middle, a corrupting
+/// proxy or a misbehaving target. Compact blocks are not compared, as their short IDs depend on a
+/// nonce drawn for each one.
+#[derive(Debug)]
+pub struct ConsistencyChecker {
+    requests: InventoryRequests,
+    seen: Mutex<Seen>,
+}
+
+impl ConsistencyChecker {
+    pub fn new(template: Vec<InventoryType>) -> Self {
+        Self {
+            requests: InventoryRequests::new(template),
+            seen: Mutex::default(),
+        }
+    }
+
+    pub fn report(&self) -> ConsistencyReport {
+        let seen = self.seen.lock().unwrap();
+        let mut divergent: Vec<_> = seen
+            .forms
+            .iter()
+            .filter(|(_, forms)| forms.len() > 1)
+            .map(|((command, hash, _), forms)| {
+                (
+                    format!("{command} {hash}"),
+                    forms.iter().map(|(_, count)| *count).collect(),
+                )
+            })
+            .collect();
+        divergent.sort();
+        ConsistencyReport {
+            checked: seen.checked,
+            distinct: seen.forms.len(),
+            divergent,
+        }
+    }
+
+    fn key(command: &str, payload: &[u8]) -> Result<Option<Key>> {
+        Ok(match command {
+            "block" => {
+                let block: Block = deserialize(payload)
+                    .map_err(|e| anyhow!("Target sent an undecodable block: {e}"))?;
+                let witness = block.txdata.iter().any(has_witness);
+                Some(("block", block.block_hash().as_hash(), witness))
+            }
+            "tx" => {
+                let tx: Transaction = deserialize(payload)
+                    .map_err(|e| anyhow!("Target sent an undecodable transaction: {e}"))?;
+                Some(("tx", tx.txid().as_hash(), has_witness(&tx)))
+            }
+            _ => None,
+        })
+    }
+}
+
+fn has_witness(tx: &Transaction) -> bool {
+    tx.input.iter().any(|input| !input.witness.is_empty())
+}
+
+impl Inspector for ConsistencyChecker {
+    type Inner = InventoryRequests;
+
+    fn inner(&self) -> &InventoryRequests {
+        &self.requests
+    }
+
+    fn inspect(&self, command: &str, payload: &[u8]) -> Result<()> {
+        if let Some(key) = Self::key(command, payload)? {
+            let digest = sha256d::Hash::hash(payload);
+            let mut seen = self.seen.lock().unwrap();
+            seen.checked += 1;
+            let forms = seen.forms.entry(key).or_default();
+            match forms.iter_mut().find(|(form, _)| *form == digest) {
+                Some((_, count)) => *count += 1,
+                None => forms.push((digest, 1)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::RequestGenerator;
+    use bitcoin::consensus::serialize;
+    use bitcoin::Network;
+
+    #[test]
+    fn consistency_checker_flags_blocks_served_in_different_forms() {
+        let block = bitcoin::blockdata::constants::genesis_block(Network::Regtest);
+        // Same header, so same hash, but a different coinbase, as a corrupting proxy would serve.
+        let mut tampered = block.clone();
+        tampered.txdata[0].lock_time = bitcoin::PackedLockTime(1);
+        let checker = ConsistencyChecker::new(vec![InventoryType::Block]);
+        for block in [&block, &block, &tampered] {
+            assert!(checker.is_response("block", &serialize(block)).unwrap());
+        }
+        assert!(!checker.is_response("inv", &[]).unwrap());
+
+        let report = checker.report();
+        assert_eq!((report.checked, report.distinct), (3, 1));
+        assert_eq!(
+            report.divergent,
+            vec![(format!("block {}", block.block_hash()), vec![2, 1])]
+        );
+    }
+}
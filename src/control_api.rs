@@ -0,0 +1,294 @@
+use crate::stats::percentile;
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Responses whose latencies the live percentiles are taken over.
+const RECENT_LATENCIES: usize = 1000;
+
+/// How long a client may take to send its request before it is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a run controlled through the API stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunState {
+    /// Waiting for a `POST /start` before connecting.
+    Waiting,
+    Running,
+    /// Connections hold back their next request until the run is resumed.
+    Paused,
+    /// Stopped through the API; the run ends with the responses received so far.
+    Stopped,
+    Finished,
+}
+
+impl RunState {
+    fn name(self) -> &'static str {
+        match self {
+            RunState::Waiting => "waiting",
+            RunState::Running => "running",
+            RunState::Paused => "paused",
+            RunState::Stopped => "stopped",
+            RunState::Finished => "finished",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    state: RunState,
+    started: Option<Instant>,
+    /// When the run was last resumed, so the time spent paused does not count as a stall.
+    resumed: Option<Instant>,
+}
+
+/// Live numbers of the run, as `GET /stats` reports them.
+#[derive(Debug, Default)]
+struct LiveStats {
+    connections: usize,
+    /// Responses the run waits for, unless it goes on forever.
+    expected: Option<usize>,
+    received: usize,
+    bytes: usize,
+    recent: VecDeque<Duration>,
+}
+
+/// A run's state shared between the run, its connections and the control API, which starts,
+/// pauses, resumes and stops it.
+#[derive(Debug)]
+pub struct RunControl {
+    state: Mutex<State>,
+    changed: Condvar,
+    stats: Mutex<LiveStats>,
+}
+
+impl RunControl {
+    /// A run that starts right away, or once started through the API if `wait` is set.
+    pub fn new(wait: bool, connections: usize, expected: Option<usize>) -> Self {
+        let (state, started) = if wait {
+            (RunState::Waiting, None)
+        } else {
+            (RunState::Running, Some(Instant::now()))
+        };
+        Self {
+            state: Mutex::new(State {
+                state,
+                started,
+                resumed: None,
+            }),
+            changed: Condvar::new(),
+            stats: Mutex::new(LiveStats {
+                connections,
+                expected,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn state(&self) -> RunState {
+        self.state.lock().unwrap().state
+    }
+
+    /// When the run was last resumed after a pause.
+    pub fn resumed(&self) -> Option<Instant> {
+        self.state.lock().unwrap().resumed
+    }
+
+    /// Moves the run from `from` to `to`, failing if it is elsewhere.
+    fn transition(&self, from: &[RunState], to: RunState) -> Result<RunState> {
+        let mut state = self.state.lock().unwrap();
+        if !from.contains(&state.state) {
+            return Err(anyhow!(
+                "Cannot go from {} to {}",
+                state.state.name(),
+                to.name()
+            ));
+        }
+        match (state.state, to) {
+            (RunState::Waiting, RunState::Running) => state.started = Some(Instant::now()),
+            (RunState::Paused, RunState::Running) => state.resumed = Some(Instant::now()),
+            _ => {}
+        }
+        state.state = to;
+        self.changed.notify_all();
+        Ok(to)
+    }
+
+    pub fn start(&self) -> Result<RunState> {
+        self.transition(&[RunState::Waiting], RunState::Running)
+    }
+
+    pub fn pause(&self) -> Result<RunState> {
+        self.transition(&[RunState::Running], RunState::Paused)
+    }
+
+    pub fn resume(&self) -> Result<RunState> {
+        self.transition(&[RunState::Paused], RunState::Running)
+    }
+
+    pub fn stop(&self) -> Result<RunState> {
+        self.transition(
+            &[RunState::Waiting, RunState::Running, RunState::Paused],
+            RunState::Stopped,
+        )
+    }
+
+    /// Marks the run as over, whichever state it was in.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().state = RunState::Finished;
+        self.changed.notify_all();
+    }
+
+    /// Blocks while the run waits to be started or is paused, returning false if it was stopped
+    /// instead of going on.
+    pub fn wait_until_running(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let state = self
+            .changed
+            .wait_while(state, |state| {
+                matches!(state.state, RunState::Waiting | RunState::Paused)
+            })
+            .unwrap();
+        state.state == RunState::Running
+    }
+
+    pub fn record_response(&self, latency: Duration, bytes: usize) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.received += 1;
+        stats.bytes += bytes;
+        if stats.recent.len() == RECENT_LATENCIES {
+            stats.recent.pop_front();
+        }
+        stats.recent.push_back(latency);
+    }
+
+    /// The run's state and numbers so far, latency percentiles over the last
+    /// [`RECENT_LATENCIES`] responses.
+    pub fn stats(&self) -> Value {
+        let (state, elapsed) = {
+            let state = self.state.lock().unwrap();
+            let elapsed = state.started.map(|started| started.elapsed());
+            (state.state, elapsed.unwrap_or_default())
+        };
+        let stats = self.stats.lock().unwrap();
+        let mut recent: Vec<_> = stats.recent.iter().copied().collect();
+        recent.sort();
+        let recent_percentile = |percent: f64| {
+            (!recent.is_empty()).then(|| percentile(&recent, percent).as_micros() as u64)
+        };
+        json!({
+            "state": state.name(),
+            "elapsed_us": elapsed.as_micros() as u64,
+            "connections": stats.connections,
+            "expected": stats.expected,
+            "received": stats.received,
+            "bytes": stats.bytes,
+            "rate": stats.received as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            "latency_p50_us": recent_percentile(50.0),
+            "latency_p99_us": recent_percentile(99.0),
+        })
+    }
+}
+
+/// Serves the control API for `control` on `listener` from a thread of its own:
+///
+/// - `GET /stats` returns the run's state and live numbers as JSON
+/// - `POST /start` starts a run waiting for it
+/// - `POST /pause` and `POST /resume` hold back and let go of the connections' requests
+/// - `POST /stop` ends the run with the responses received so far
+///
+/// A `POST` answers with the run's new state, or 409 if the run is not in a state to take it.
+pub fn serve(listener: TcpListener, control: Arc<RunControl>) -> Result<()> {
+    thread::Builder::new()
+        .name("control-api".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(Into::into)
+                    .and_then(|stream| handle(stream, &control));
+                if let Err(e) = result {
+                    debug!("Control API request failed: {e}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, control: &RunControl) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers, and any body, are of no interest.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let action = match path {
+        "/start" => RunControl::start,
+        "/pause" => RunControl::pause,
+        "/resume" => RunControl::resume,
+        "/stop" => RunControl::stop,
+        "/stats" if method == "GET" => return respond(&mut stream, 200, &control.stats()),
+        "/stats" => return respond(&mut stream, 405, &json!({ "error": "Use GET" })),
+        _ => return respond(&mut stream, 404, &json!({ "error": "Not found" })),
+    };
+    if method != "POST" {
+        return respond(&mut stream, 405, &json!({ "error": "Use POST" }));
+    }
+    match action(control) {
+        Ok(state) => respond(&mut stream, 200, &json!({ "state": state.name() })),
+        Err(e) => respond(&mut stream, 409, &json!({ "error": e.to_string() })),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Conflict",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_control_only_takes_transitions_from_the_right_state() {
+        let control = Arc::new(RunControl::new(true, 1, Some(10)));
+        assert!(control.pause().is_err());
+        let waiting = {
+            let control = control.clone();
+            thread::spawn(move || control.wait_until_running())
+        };
+        control.start().unwrap();
+        assert!(waiting.join().unwrap());
+        control.pause().unwrap();
+        assert!(control.start().is_err());
+        control.resume().unwrap();
+        assert!(control.resumed().is_some());
+        control.record_response(Duration::from_millis(5), 100);
+        assert_eq!(control.stats()["received"], 1);
+        assert_eq!(control.stats()["latency_p99_us"], 5000);
+        control.stop().unwrap();
+        assert_eq!(control.state(), RunState::Stopped);
+        assert!(!control.wait_until_running());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::stats::percentile;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the controller looks at the latencies measured since its last adjustment.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// How far a single adjustment may scale the rate up.
+const MAX_INCREASE: f64 = 1.5;
+
+/// How far a single adjustment may scale the rate down.
+const MAX_DECREASE: f64 = 0.5;
+
+/// Fraction of the measured throughput the rate backs off to at most when over the target, so
+/// requests queued at the target drain without the rate collapsing while they do.
+const DRAIN_FRACTION: f64 = 0.8;
+
+/// Request messages per second each connection starts out sending.
+pub const INITIAL_RATE: f64 = 10.0;
+
+/// Request rate every connection paces itself to, adjusted by a [`Controller`].
+#[derive(Debug)]
+pub struct SharedRate(AtomicU64);
+
+impl SharedRate {
+    pub fn new(rate: f64) -> Self {
+        SharedRate(AtomicU64::new(rate.to_bits()))
+    }
+
+    /// Request messages per second for each connection.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, rate: f64) {
+        self.0.store(rate.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// What happened during one window of the controlled run.
+pub struct Window {
+    /// Total request rate across connections during the window.
+    pub rate: f64,
+    /// Responses per second received during the window.
+    pub throughput: f64,
+    /// None when no response arrived during the window.
+    pub p99: Option<Duration>,
+    /// Total request rate for the next window.
+    pub next_rate: f64,
+}
+
+/// Holds the p99 latency at a target by scaling the request rate after every window by how far
+/// the window's p99 was off, so the rate climbs while latency is below the target and backs off
+/// once the target is overloaded, but not far below what the target was still serving. A window
+/// without any response backs off as far as a single adjustment may.
+pub struct Controller {
+    target: Duration,
+    rate: Arc<SharedRate>,
+    connections: usize,
+    window_start: Instant,
+    latencies: Vec<Duration>,
+    windows: Vec<Window>,
+}
+
+impl Controller {
+    pub fn new(target: Duration, rate: Arc<SharedRate>, connections: usize) -> Self {
+        Controller {
+            target,
+            rate,
+            connections,
+            window_start: Instant::now(),
+            latencies: Vec::new(),
+            windows: Vec::new(),
+        }
+    }
+
+    /// Records a response's latency, returning the window it closed, if any.
+    pub fn record(&mut self, latency: Duration) -> Option<&Window> {
+        self.latencies.push(latency);
+        self.tick()
+    }
+
+    /// Closes the window if it is over, returning it. Must be called periodically, so a window
+    /// in which no response arrived closes too.
+    pub fn tick(&mut self) -> Option<&Window> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < WINDOW {
+            return None;
+        }
+
+        self.latencies.sort();
+        let p99 = (!self.latencies.is_empty()).then(|| percentile(&self.latencies, 99.0));
+        let throughput = self.latencies.len() as f64 / elapsed.as_secs_f64();
+        let per_connection = self.rate.get();
+        let next = match p99 {
+            Some(p99) => {
+                let step = (self.target.as_secs_f64() / p99.as_secs_f64().max(f64::EPSILON))
+                    .sqrt()
+                    .clamp(MAX_DECREASE, MAX_INCREASE);
+                let next = per_connection * step;
+                if p99 > self.target {
+                    let draining = DRAIN_FRACTION * throughput / self.connections as f64;
+                    next.max(draining).min(per_connection)
+                } else {
+                    next
+                }
+            }
+            None => per_connection * MAX_DECREASE,
+        };
+        self.rate.set(next);
+
+        self.windows.push(Window {
+            rate: per_connection * self.connections as f64,
+            throughput,
+            p99,
+            next_rate: next * self.connections as f64,
+        });
+        self.latencies.clear();
+        self.window_start = Instant::now();
+        self.windows.last()
+    }
+
+    /// Mean throughput of the windows in the second half of the run that held the target, once
+    /// the controller had time to converge.
+    pub fn sustained_throughput(&self) -> Option<f64> {
+        let settled: Vec<_> = self.windows[self.windows.len() / 2..]
+            .iter()
+            .filter(|window| window.p99.is_some_and(|p99| p99 <= self.target))
+            .map(|window| window.throughput)
+            .collect();
+        (!settled.is_empty()).then(|| settled.iter().sum::<f64>() / settled.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(target: Duration) -> (Controller, Arc<SharedRate>) {
+        let rate = Arc::new(SharedRate::new(INITIAL_RATE));
+        let mut controller = Controller::new(target, rate.clone(), 2);
+        controller.window_start -= WINDOW;
+        (controller, rate)
+    }
+
+    #[test]
+    fn windows_stay_open_until_over() {
+        let (mut controller, rate) = controller(Duration::from_millis(100));
+        controller.window_start = Instant::now();
+        assert!(controller.record(Duration::from_millis(10)).is_none());
+        assert!(controller.tick().is_none());
+        assert_eq!(rate.get(), INITIAL_RATE);
+    }
+
+    #[test]
+    fn rate_climbs_while_under_the_target() {
+        let (mut controller, rate) = controller(Duration::from_millis(100));
+        let window = controller.record(Duration::from_millis(1)).unwrap();
+        assert_eq!(window.rate, 2.0 * INITIAL_RATE);
+        assert_eq!(window.next_rate, 2.0 * INITIAL_RATE * MAX_INCREASE);
+        assert_eq!(rate.get(), INITIAL_RATE * MAX_INCREASE);
+    }
+
+    #[test]
+    fn rate_backs_off_over_the_target() {
+        let (mut controller, rate) = controller(Duration::from_millis(100));
+        let window = controller.record(Duration::from_millis(400)).unwrap();
+        assert_eq!(window.p99, Some(Duration::from_millis(400)));
+        assert!(rate.get() < INITIAL_RATE);
+        assert!(rate.get() >= INITIAL_RATE * MAX_DECREASE);
+    }
+
+    #[test]
+    fn rate_backs_off_on_a_window_without_responses() {
+        let (mut controller, rate) = controller(Duration::from_millis(100));
+        let window = controller.tick().unwrap();
+        assert_eq!(window.p99, None);
+        assert_eq!(window.throughput, 0.0);
+        assert_eq!(rate.get(), INITIAL_RATE * MAX_DECREASE);
+        assert_eq!(controller.sustained_throughput(), None);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::conformance::{receive, send, Reply};
+use crate::frames::Frames;
+use crate::gossip::{gossiped_addresses, GossipedAddress};
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Transport;
+use crate::PeerVersion;
+use anyhow::Result;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::Network;
+use log::debug;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// DNS seeds Bitcoin Core bootstraps from on each network.
+pub fn dns_seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Bitcoin => &[
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.net",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+            "seed.bitcoin.wiz.biz",
+        ],
+        Network::Testnet => &[
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.net",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me",
+        ],
+        Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+        Network::Regtest => &[],
+    }
+}
+
+/// Port nodes listen on by default on each network.
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Signet => 38333,
+        Network::Regtest => 18444,
+    }
+}
+
+/// Addresses of the nodes the DNS seeds of `network` return. Seeds that fail to resolve are
+/// skipped.
+pub fn resolve_dns_seeds(network: Network) -> Vec<String> {
+    let port = default_port(network);
+    let mut addresses = Vec::new();
+    for seed in dns_seeds(network) {
+        match (*seed, port).to_socket_addrs() {
+            Ok(resolved) => addresses.extend(resolved.map(|address| address.to_string())),
+            Err(e) => eprintln!("Could not resolve {seed}: {e}"),
+        }
+    }
+    addresses
+}
+
+/// A peer the crawl reached: what it announced and the addresses it answered getaddr with.
+#[derive(Clone, Debug)]
+pub struct CrawledPeer {
+    pub address: String,
+    /// Hops from the seeds: 0 for a seed, 1 for a peer a seed gossiped, and so on.
+    pub depth: usize,
+    pub peer: PeerVersion,
+    pub handshake: Duration,
+    pub addresses: Vec<GossipedAddress>,
+}
+
+impl CrawledPeer {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "address": self.address,
+            "depth": self.depth,
+            "version": self.peer.version,
+            "services": self.peer.services.to_u64(),
+            "user_agent": self.peer.user_agent,
+            "start_height": self.peer.start_height,
+            "handshake_us": self.handshake.as_micros() as u64,
+            "addresses": self.addresses.len(),
+        })
+    }
+}
+
+/// How far a crawl goes.
+#[derive(Clone, Copy, Debug)]
+pub struct CrawlLimits {
+    /// Hops from the seeds past which discovered peers are not probed.
+    pub depth: usize,
+    /// Most peers probed, reachable or not.
+    pub peers: usize,
+    /// Peers probed at once.
+    pub concurrency: usize,
+    /// How long each peer gets to connect and handshake, and to answer getaddr if longer than
+    /// [`GETADDR_WAIT`].
+    pub timeout: Duration,
+}
+
+/// What a crawl found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CrawlSummary {
+    pub probed: usize,
+    pub reachable: usize,
+    /// Addresses discovered, probed or not.
+    pub discovered: usize,
+}
+
+/// Least time a peer gets to answer getaddr, however short the probe timeout: nodes may take a
+/// while to answer, and gossip other addresses meanwhile.
+pub const GETADDR_WAIT: Duration = Duration::from_secs(60);
+
+/// Fewest addresses in a message taken as the answer to getaddr. Nodes relay gossip in addr
+/// messages of at most 10 addresses, and answer getaddr with up to 1000.
+const GETADDR_ANSWER: usize = 100;
+
+/// A peer's version, how long its handshake took and the addresses it gossiped.
+type ProbeResult = Result<(PeerVersion, Duration, Vec<GossipedAddress>)>;
+
+/// Handshakes with `address` announcing addrv2 and asks for its addresses, collecting those it
+/// gossips until it answers getaddr or [`GETADDR_WAIT`], or `timeout` if longer, passes.
+pub fn probe(transport: &Transport, address: &str, magic: u32, timeout: Duration) -> ProbeResult {
+    let handler = MessageHandler::new(magic, None).with_addrv2();
+    let mut stream = transport.connect_timeout(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let start = Instant::now();
+    let peer = PeerVersion::from(&perform_handshake(&mut stream, &handler)?);
+    let handshake = start.elapsed();
+
+    send(&mut stream, &handler, NetworkMessage::GetAddr)?;
+    let deadline = Instant::now() + timeout.max(GETADDR_WAIT);
+    let mut addresses = Vec::new();
+    let mut frames = Frames::new(stream.try_clone()?);
+    while let Reply::Message(message) = receive(&mut frames, deadline)? {
+        let gossiped = gossiped_addresses(&message, SystemTime::now());
+        // Nodes answer getaddr with a single large message; smaller ones are self-announcements
+        // and relayed gossip. A peer knowing fewer addresses is waited on until the deadline.
+        let answered = gossiped.len() >= GETADDR_ANSWER;
+        addresses.extend(gossiped);
+        if answered {
+            break;
+        }
+        handler.handle(&mut stream, &message)?;
+    }
+    Ok((peer, handshake, addresses))
+}
+
+/// The connectable addresses among `addresses` not in `seen`, which they are added to.
+pub fn unseen_addresses(addresses: &[GossipedAddress], seen: &mut HashSet<String>) -> Vec<String> {
+    addresses
+        .iter()
+        .filter_map(GossipedAddress::socket_address)
+        .filter(|address| seen.insert(address.clone()))
+        .collect()
+}
+
+/// Probes `seeds`, then the peers they gossip, then the peers those gossip, and so on up to
+/// `limits`, calling `on_peer` with each peer reached, depth by depth.
+pub fn crawl(
+    transport: &Transport,
+    magic: u32,
+    seeds: Vec<String>,
+    limits: CrawlLimits,
+    mut on_peer: impl FnMut(&CrawledPeer) -> Result<()>,
+) -> Result<CrawlSummary> {
+    let mut seen: HashSet<String> = seeds.iter().cloned().collect();
+    let mut frontier = seeds;
+    let mut summary = CrawlSummary::default();
+    for depth in 0..=limits.depth {
+        frontier.truncate(limits.peers - summary.probed);
+        if frontier.is_empty() {
+            break;
+        }
+        summary.probed += frontier.len();
+        let mut next = Vec::new();
+        for (address, result) in probe_all(transport, magic, frontier, limits) {
+            let (peer, handshake, addresses) = match result {
+                Ok(probed) => probed,
+                Err(e) => {
+                    debug!("Could not crawl {address}: {e:#}");
+                    continue;
+                }
+            };
+            next.extend(unseen_addresses(&addresses, &mut seen));
+            summary.reachable += 1;
+            on_peer(&CrawledPeer {
+                address,
+                depth,
+                peer,
+                handshake,
+                addresses,
+            })?;
+        }
+        frontier = next;
+    }
+    summary.discovered = seen.len();
+    Ok(summary)
+}
+
+/// Probes `addresses`, `limits.concurrency` at a time, returning the results in their order.
+fn probe_all(
+    transport: &Transport,
+    magic: u32,
+    addresses: Vec<String>,
+    limits: CrawlLimits,
+) -> Vec<(String, ProbeResult)> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = channel();
+    thread::scope(|scope| {
+        for _ in 0..limits.concurrency.min(addresses.len()) {
+            let (addresses, next, tx) = (&addresses, &next, tx.clone());
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(address) = addresses.get(index) else {
+                    return;
+                };
+                let result = probe(transport, address, magic, limits.timeout);
+                if tx.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+    });
+    drop(tx);
+    let mut results: Vec<_> = rx.iter().collect();
+    results.sort_by_key(|(index, _)| *index);
+    results
+        .into_iter()
+        .map(|(index, result)| (addresses[index].clone(), result))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::HANDSHAKE;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::network::message::RawNetworkMessage;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn crawl_probes_the_connectable_addresses_peers_gossip() {
+        use bitcoin::network::address::{AddrV2, AddrV2Message};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let seed = listener.local_addr().unwrap();
+        let entry = |addr, port| AddrV2Message {
+            time: 1_700_000_000,
+            services: ServiceFlags::NETWORK,
+            addr,
+            port,
+        };
+        let mut answer = vec![
+            // Itself, which the crawl already probed.
+            entry(AddrV2::Ipv4("127.0.0.1".parse().unwrap()), seed.port()),
+            // Nothing listens on port 1.
+            entry(AddrV2::Ipv4("127.0.0.1".parse().unwrap()), 1),
+        ];
+        // Enough onion addresses, which the crawl cannot connect to, to be a getaddr answer.
+        answer.extend((0..98).map(|key| entry(AddrV2::TorV3([key; 32]), 8333)));
+        let answer = NetworkMessage::AddrV2(answer);
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut replies = Vec::<u8>::from_hex(HANDSHAKE).unwrap();
+            replies.extend(serialize(&RawNetworkMessage {
+                magic: Network::Bitcoin.magic(),
+                payload: answer,
+            }));
+            stream.write_all(&replies).unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        let limits = CrawlLimits {
+            depth: 3,
+            peers: 100,
+            concurrency: 4,
+            timeout: Duration::from_secs(5),
+        };
+        let mut reached = Vec::new();
+        let summary = crawl(
+            &Transport::default(),
+            Network::Bitcoin.magic(),
+            vec![seed.to_string()],
+            limits,
+            |peer| {
+                reached.push((peer.address.clone(), peer.depth, peer.addresses.len()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        peer.join().unwrap();
+
+        assert_eq!(reached, [(seed.to_string(), 0, 100)]);
+        assert_eq!(
+            summary,
+            CrawlSummary {
+                probed: 2,
+                reachable: 1,
+                discovered: 2,
+            }
+        );
+    }
+}
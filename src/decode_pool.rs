@@ -0,0 +1,170 @@
+use crate::generator::RequestGenerator;
+use crate::memory::{MemoryBudget, Reservation};
+use crate::panic_message;
+use anyhow::{anyhow, Error, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// How deep the queue of responses waiting for a worker got.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueDepth {
+    pub max: usize,
+    /// Mean depth seen by each response as it was queued, itself included.
+    pub mean: f64,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    /// Responses queued or being decoded.
+    pending: usize,
+    max: usize,
+    /// Sum of `pending` after each submission.
+    total: usize,
+    submitted: usize,
+    /// The first error a worker hit.
+    error: Option<Error>,
+}
+
+/// Hands response payloads to worker threads that pass them to a generator's
+/// [`is_response`](RequestGenerator::is_response) for its decoding and verification, so the
+/// connection reading them can go straight back to the socket.
+#[derive(Debug)]
+pub struct DecodePool {
+    sender: Sender<Job>,
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+/// A response's command and payload, and the memory reserved for the payload.
+type Job = (String, Vec<u8>, Option<Reservation>);
+
+impl DecodePool {
+    /// Starts `workers` threads, reserving the payloads queued from `memory` if given.
+    pub fn new(
+        generator: Arc<dyn RequestGenerator>,
+        workers: usize,
+        memory: Option<Arc<MemoryBudget>>,
+    ) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        for worker in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let generator = generator.clone();
+            let queue = queue.clone();
+            // Named like the connections' threads, for the log lines.
+            let thread = thread::Builder::new().name(format!("decode-{worker}"));
+            let spawned = thread.spawn(move || loop {
+                let Ok((command, payload, reservation)) = receiver.lock().unwrap().recv() else {
+                    return;
+                };
+                // A panicking generator fails the run like any other error, instead of leaving
+                // the response pending forever.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    generator.is_response(&command, &payload)
+                }))
+                .unwrap_or_else(|panic| {
+                    Err(anyhow!(
+                        "Decoding a {command} response panicked: {}",
+                        panic_message(panic.as_ref())
+                    ))
+                });
+                let (lock, idle) = &*queue;
+                {
+                    let mut queue = lock.lock().unwrap();
+                    queue.pending -= 1;
+                    if let Err(e) = result {
+                        queue.error.get_or_insert(e);
+                    }
+                    idle.notify_all();
+                }
+                // Released once no longer pending, so a submission waiting on it sees both.
+                drop((payload, reservation));
+            });
+            // As `thread::spawn` would.
+            spawned.expect("failed to spawn thread");
+        }
+        Self {
+            sender,
+            queue,
+            memory,
+        }
+    }
+
+    /// Queues a response for decoding, failing if a worker failed on an earlier one. Waits for
+    /// room under the memory cap while other responses are queued.
+    pub fn submit(&self, command: String, payload: Vec<u8>) -> Result<()> {
+        // With nothing queued, no worker is left to release memory for us to wait on.
+        let reservation = self.memory.as_ref().map(|memory| {
+            memory.reserve_while(payload.len(), || self.queue.0.lock().unwrap().pending > 0)
+        });
+        {
+            let mut queue = self.queue.0.lock().unwrap();
+            if let Some(e) = queue.error.take() {
+                return Err(e);
+            }
+            queue.pending += 1;
+            queue.max = queue.max.max(queue.pending);
+            queue.total += queue.pending;
+            queue.submitted += 1;
+        }
+        self.sender
+            .send((command, payload, reservation))
+            .map_err(|_| anyhow!("Decode workers exited"))
+    }
+
+    /// Waits until every queued response was decoded, so reports cover all of them, returning
+    /// the error a worker hit that no submission reported yet.
+    pub fn wait_idle(&self) -> Result<()> {
+        let (lock, idle) = &*self.queue;
+        let mut queue = idle
+            .wait_while(lock.lock().unwrap(), |queue| queue.pending > 0)
+            .unwrap();
+        queue.error.take().map_or(Ok(()), Err)
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        let queue = self.queue.0.lock().unwrap();
+        QueueDepth {
+            max: queue.max,
+            mean: queue.total as f64 / queue.submitted.max(1) as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestConfig;
+    use bitcoin::network::message::NetworkMessage;
+
+    #[test]
+    fn decode_pool_reports_a_panicking_generator_as_an_error() {
+        #[derive(Debug)]
+        struct Panicking;
+
+        impl RequestGenerator for Panicking {
+            fn requests(&self, _: &RequestConfig) -> Result<Vec<NetworkMessage>> {
+                Ok(vec![])
+            }
+
+            fn is_response(&self, _: &str, _: &[u8]) -> Result<bool> {
+                panic!("bad block");
+            }
+
+            fn is_response_command(&self, _: &str) -> bool {
+                false
+            }
+        }
+
+        let pool = DecodePool::new(Arc::new(Panicking), 1, None);
+        pool.submit("block".to_string(), vec![0; 80]).unwrap();
+        let err = pool.wait_idle().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Decoding a block response panicked: bad block"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::generator::RequestGenerator;
+use crate::handler::MessageHandler;
+use crate::{answer, parse_header, EventSender, Timeline, MESSAGE_HEADER_SIZE, RECEIVE_LOG};
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::network::message::RawNetworkMessage;
+use log::trace;
+use std::io::{ErrorKind, Read, Write};
+
+/// Size of the chunks the socket is read in.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Where in the stream the scanner is.
+enum State {
+    /// Reading a message header, of which the first `len` bytes arrived.
+    Header {
+        header: [u8; MESSAGE_HEADER_SIZE],
+        len: usize,
+    },
+    /// Skipping the rest of a response's payload, counting it once it is all read.
+    Skip { remaining: usize, bytes: usize },
+    /// Collecting the payload of a message that is not a response, to answer it.
+    Keep {
+        header: [u8; MESSAGE_HEADER_SIZE],
+        payload: Vec<u8>,
+        len: usize,
+    },
+}
+
+impl State {
+    fn header() -> Self {
+        State::Header {
+            header: [0; MESSAGE_HEADER_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Whether the message is all read, so the state moves on without more input.
+    fn complete(&self) -> bool {
+        match self {
+            State::Header { .. } => false,
+            State::Skip { remaining, .. } => *remaining == 0,
+            State::Keep { payload, len, .. } => payload.len() == *len,
+        }
+    }
+}
+
+/// Receives like `receive_responses`, but reads the socket in large chunks into one reused
+/// buffer and only looks at message headers, counting each response by its command once its
+/// payload went by, without ever copying or checking the payload. Messages that are not
+/// responses are still collected and answered.
+pub(crate) fn discard_responses<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    handler: &MessageHandler,
+    generator: &dyn RequestGenerator,
+    timeline: &Timeline,
+    events: &EventSender,
+) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut state = State::header();
+    let mut received = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Err(anyhow!("Target closed the connection")),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut chunk = &buf[..read];
+        while !chunk.is_empty() || state.complete() {
+            state = match state {
+                State::Header {
+                    mut header,
+                    mut len,
+                } => {
+                    let take = (MESSAGE_HEADER_SIZE - len).min(chunk.len());
+                    header[len..len + take].copy_from_slice(&chunk[..take]);
+                    chunk = &chunk[take..];
+                    len += take;
+                    if len < MESSAGE_HEADER_SIZE {
+                        State::Header { header, len }
+                    } else {
+                        let (command, len) = parse_header(&header)?;
+                        if generator.is_response_command(command.as_ref()) {
+                            State::Skip {
+                                remaining: len,
+                                bytes: MESSAGE_HEADER_SIZE + len,
+                            }
+                        } else {
+                            State::Keep {
+                                header,
+                                payload: Vec::with_capacity(len),
+                                len,
+                            }
+                        }
+                    }
+                }
+                State::Skip {
+                    remaining: 0,
+                    bytes,
+                } => {
+                    if !events.send(timeline.response(received, bytes, None)) {
+                        trace!(target: RECEIVE_LOG, "Finished receiving");
+                        return Ok(());
+                    }
+                    received += 1;
+                    State::header()
+                }
+                State::Skip { remaining, bytes } => {
+                    let take = remaining.min(chunk.len());
+                    chunk = &chunk[take..];
+                    State::Skip {
+                        remaining: remaining - take,
+                        bytes,
+                    }
+                }
+                State::Keep {
+                    header,
+                    mut payload,
+                    len,
+                } => {
+                    let take = (len - payload.len()).min(chunk.len());
+                    payload.extend_from_slice(&chunk[..take]);
+                    chunk = &chunk[take..];
+                    if payload.len() < len {
+                        State::Keep {
+                            header,
+                            payload,
+                            len,
+                        }
+                    } else {
+                        // Checked like `receive_responses` does, so messages ruling out a
+                        // response, such as a block answering a too deep compact block request,
+                        // fail the run instead of leaving it waiting.
+                        let (command, _) = parse_header(&header)?;
+                        if generator.is_response(command.as_ref(), &payload)? {
+                            State::Skip {
+                                remaining: 0,
+                                bytes: MESSAGE_HEADER_SIZE + len,
+                            }
+                        } else {
+                            let message: RawNetworkMessage =
+                                deserialize(&[&header[..], &payload].concat())?;
+                            answer(writer, handler, &message.payload, events)?;
+                            State::header()
+                        }
+                    }
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::analyze::RecordedResponse;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Round trips timed to estimate an agent's clock offset, the fastest of which is used.
+pub const SYNC_ROUNDS: usize = 5;
+
+/// How long a connecting agent gets to say hello and answer the clock sync, so a stray
+/// connection cannot hold up the agents after it.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes `message` as one line of JSON, the framing controller and agents talk in.
+pub fn send(writer: &mut impl Write, message: &Value) -> Result<()> {
+    // In a single write, as a line split over two would wait out Nagle's algorithm and throw off
+    // the clock sync.
+    writer.write_all(format!("{message}\n").as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next message, failing if the other side closed the connection.
+pub fn recv(reader: &mut impl BufRead) -> Result<Value> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(anyhow!("Connection closed"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Wall clock time, in microseconds since the Unix epoch.
+pub fn now_us() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
+
+/// One clock sync round trip: the controller's clock when it asked and when the answer arrived,
+/// and the agent's clock when it answered.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncSample {
+    pub sent_us: u64,
+    pub agent_us: u64,
+    pub received_us: u64,
+}
+
+/// How far the agent's clock is ahead of the controller's, in microseconds, taken from the
+/// fastest round trip and assuming it took as long both ways.
+pub fn clock_offset(samples: &[SyncSample]) -> Option<i64> {
+    samples
+        .iter()
+        .min_by_key(|sample| sample.received_us - sample.sent_us)
+        .map(|sample| {
+            let midpoint = (sample.sent_us + sample.received_us) / 2;
+            sample.agent_us as i64 - midpoint as i64
+        })
+}
+
+/// An agent connected to the controller, its clock synced.
+#[derive(Debug)]
+pub struct Agent {
+    pub peer: SocketAddr,
+    /// See [`clock_offset`].
+    pub offset_us: i64,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Agent {
+    /// Waits for the next agent to connect, checks it runs the same version as the controller
+    /// and measures its clock offset. Fails if the agent does not say hello and answer within
+    /// [`HELLO_TIMEOUT`].
+    pub fn accept(listener: &TcpListener) -> Result<Self> {
+        let (writer, peer) = listener.accept()?;
+        writer.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        let mut reader = BufReader::new(writer.try_clone()?);
+        let hello =
+            recv(&mut reader).map_err(|e| anyhow!("Agent at {peer} did not say hello: {e}"))?;
+        let version = env!("CARGO_PKG_VERSION");
+        if hello["type"] != "hello" || hello["version"] != version {
+            return Err(anyhow!(
+                "Agent at {peer} runs version {}, the controller {version}",
+                hello["version"]
+            ));
+        }
+        let mut agent = Self {
+            peer,
+            offset_us: 0,
+            reader,
+            writer,
+        };
+        let mut samples = Vec::with_capacity(SYNC_ROUNDS);
+        for _ in 0..SYNC_ROUNDS {
+            let sent_us = now_us()?;
+            send(&mut agent.writer, &json!({ "type": "sync" }))?;
+            let reply = recv(&mut agent.reader)?;
+            let received_us = now_us()?;
+            let agent_us = reply["agent_us"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Agent at {peer} sent an invalid sync reply"))?;
+            samples.push(SyncSample {
+                sent_us,
+                agent_us,
+                received_us,
+            });
+        }
+        agent.offset_us = clock_offset(&samples).unwrap_or_default();
+        // The run's output may be minutes apart.
+        agent.writer.set_read_timeout(None)?;
+        Ok(agent)
+    }
+
+    /// Tells the agent to run the tool with `arguments` once the controller's clock reads
+    /// `start_us`, then forwards what it reports to `messages`, tagged with `index`, on a thread
+    /// of its own until the connection closes.
+    pub fn start(
+        mut self,
+        index: usize,
+        arguments: &[String],
+        start_us: u64,
+        messages: Sender<(usize, Result<Value>)>,
+    ) -> Result<()> {
+        let start_us = start_us.saturating_add_signed(self.offset_us);
+        let run = json!({ "type": "run", "arguments": arguments, "start_us": start_us });
+        send(&mut self.writer, &run)?;
+        thread::spawn(move || loop {
+            let message = recv(&mut self.reader);
+            let failed = message.is_err();
+            if messages.send((index, message)).is_err() || failed {
+                return;
+            }
+        });
+        Ok(())
+    }
+}
+
+/// What an agent reported of its run.
+#[derive(Clone, Debug, Default)]
+pub struct AgentResults {
+    pub responses: usize,
+    pub bytes: usize,
+    /// Latency of every response, in the order they arrived.
+    pub latencies: Vec<Duration>,
+    /// Length of the run, once its summary arrived.
+    pub elapsed: Option<Duration>,
+    /// Exit code of the agent's run, once it ended, or `None` if it was killed by a signal.
+    pub exit_code: Option<Option<i32>>,
+}
+
+impl AgentResults {
+    /// Takes in a message from the agent, returning the line its run printed if the message
+    /// forwards one other than an event.
+    pub fn record<'a>(&mut self, message: &'a Value) -> Result<Option<&'a str>> {
+        match message["type"].as_str() {
+            Some("output") => {
+                let line = message["line"].as_str().unwrap_or_default();
+                let Ok(event) = serde_json::from_str::<Value>(line) else {
+                    return Ok(Some(line));
+                };
+                if let Some(response) = RecordedResponse::from_event(&event)? {
+                    self.responses += 1;
+                    self.bytes += response.bytes.unwrap_or_default();
+                    self.latencies.extend(response.latency);
+                } else if event["event"] == "summary" {
+                    self.elapsed = event["elapsed_us"].as_u64().map(Duration::from_micros);
+                }
+            }
+            Some("exit") => self.exit_code = Some(message["code"].as_i64().map(|c| c as i32)),
+            _ => return Err(anyhow!("Unexpected message from agent: {message}")),
+        }
+        Ok(None)
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(Some(0))
+    }
+}
+
+/// Connects to the controller at `address` as an agent, waits for a run and runs `program` with
+/// its arguments and `--events jsonl`, streaming the run's output back, events included. Returns
+/// the exit code of the run.
+///
+/// The controller is trusted with any arguments, including options writing files such as
+/// `--log-file`, `--response-log` or `--pipe-to`, and the connection is neither authenticated
+/// nor encrypted, so agents only belong on trusted networks.
+pub fn run_agent(address: &str, program: &Path) -> Result<Option<i32>> {
+    let stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = Arc::new(Mutex::new(stream));
+    let hello = json!({ "type": "hello", "version": env!("CARGO_PKG_VERSION") });
+    send(&mut *writer.lock().unwrap(), &hello)?;
+    let run = loop {
+        let message = recv(&mut reader)?;
+        match message["type"].as_str() {
+            Some("sync") => {
+                let reply = json!({ "type": "sync", "agent_us": now_us()? });
+                send(&mut *writer.lock().unwrap(), &reply)?;
+            }
+            Some("run") => break message,
+            _ => return Err(anyhow!("Unexpected message from controller: {message}")),
+        }
+    };
+    let mut arguments: Vec<String> = serde_json::from_value(run["arguments"].clone())?;
+    arguments.extend(["--events".to_string(), "jsonl".to_string()]);
+    let start_us = run["start_us"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Controller sent a run without a start time"))?;
+    thread::sleep(Duration::from_micros(start_us.saturating_sub(now_us()?)));
+
+    let mut child = Command::new(program)
+        .args(&arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let forward = |output: Box<dyn std::io::Read + Send>| {
+        let writer = writer.clone();
+        thread::spawn(move || -> Result<()> {
+            for line in BufReader::new(output).lines() {
+                let message = json!({ "type": "output", "line": line? });
+                send(&mut *writer.lock().unwrap(), &message)?;
+            }
+            Ok(())
+        })
+    };
+    let stdout = forward(Box::new(child.stdout.take().unwrap()));
+    let stderr = forward(Box::new(child.stderr.take().unwrap()));
+    let status = child.wait()?;
+    for forwarding in [stdout, stderr] {
+        forwarding
+            .join()
+            .map_err(|_| anyhow!("Forwarding output panicked"))??;
+    }
+    let exit = json!({ "type": "exit", "code": status.code() });
+    send(&mut *writer.lock().unwrap(), &exit)?;
+    Ok(status.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn clock_offset_uses_the_fastest_round_trip() {
+        let samples = [
+            SyncSample {
+                sent_us: 1000,
+                agent_us: 5000,
+                received_us: 3000,
+            },
+            SyncSample {
+                sent_us: 4000,
+                agent_us: 2100,
+                received_us: 4200,
+            },
+        ];
+        assert_eq!(clock_offset(&samples), Some(-2000));
+        assert_eq!(clock_offset(&[]), None);
+
+        // The agent is 500us ahead; the slow round trip was delayed on the way back only.
+        let sample = |sent_us, agent_us, received_us| SyncSample {
+            sent_us,
+            agent_us,
+            received_us,
+        };
+        let samples = [sample(1_000, 1_550, 1_100), sample(2_000, 2_550, 2_900)];
+        assert_eq!(clock_offset(&samples), Some(500));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn agents_run_what_the_controller_sends_and_report_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let agent = thread::spawn(move || run_agent(&address, Path::new("/bin/sh")));
+        let controller = Agent::accept(&listener).unwrap();
+
+        // The agent appends `--events jsonl`, which `sh -c` takes as its own arguments.
+        let script = concat!(
+            r#"echo '{"event":"response","ts_us":1,"conn":0,"bytes":285,"latency_us":1500}'; "#,
+            r#"echo '{"event":"summary","elapsed_us":2000000}'; "#,
+            "echo done; exit 3"
+        );
+        let arguments = ["-c".to_string(), script.to_string()];
+        let (sender, messages) = channel();
+        controller
+            .start(0, &arguments, now_us().unwrap(), sender)
+            .unwrap();
+        let mut results = AgentResults::default();
+        let mut printed = Vec::new();
+        for (index, message) in messages {
+            assert_eq!(index, 0);
+            let Ok(message) = message else { break };
+            printed.extend(results.record(&message).unwrap().map(str::to_string));
+        }
+
+        assert_eq!(agent.join().unwrap().unwrap(), Some(3));
+        assert_eq!(printed, ["done"]);
+        assert_eq!(results.responses, 1);
+        assert_eq!(results.bytes, 285);
+        assert_eq!(results.latencies, [Duration::from_micros(1500)]);
+        assert_eq!(results.elapsed, Some(Duration::from_secs(2)));
+        assert_eq!(results.exit_code, Some(Some(3)));
+        assert!(!results.succeeded());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::version::VersionBuilder;
+use anyhow::{anyhow, Error};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_compact_blocks::SendCmpct;
+use std::fmt;
+use std::str::FromStr;
+
+/// First protocol version negotiating wtxidrelay and addrv2 during the handshake.
+const FEATURE_NEGOTIATION_VERSION: u32 = 70016;
+
+/// A well-known implementation whose handshake ours imitates: its user agent, protocol version,
+/// services and the messages it sends around verack, so the target's treatment of it can be
+/// compared under an otherwise identical workload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emulation {
+    /// Bitcoin Core 25.0 running with -blocksonly.
+    Core,
+    /// btcd 0.24.
+    Btcd,
+    /// The neutrino light client used by lnd.
+    Neutrino,
+}
+
+impl Emulation {
+    /// The version message the implementation opens its outbound connections with.
+    pub fn version(self) -> VersionBuilder {
+        let (version, services, user_agent) = match self {
+            Emulation::Core => (
+                70016,
+                ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED,
+                "/Satoshi:25.0.0/",
+            ),
+            Emulation::Btcd => (
+                70016,
+                ServiceFlags::NETWORK | ServiceFlags::BLOOM | ServiceFlags::WITNESS,
+                "/btcwire:0.5.0/btcd:0.24.0/",
+            ),
+            Emulation::Neutrino => (
+                70013,
+                ServiceFlags::WITNESS,
+                "/btcwire:0.5.0/neutrino:0.16.0/",
+            ),
+        };
+        VersionBuilder::default()
+            .with_version(version)
+            .with_services(services)
+            .with_user_agent(user_agent)
+    }
+
+    /// What the implementation sends on receiving the version of a peer at `peer_version`,
+    /// before its verack.
+    pub fn before_verack(self, peer_version: u32) -> Vec<NetworkMessage> {
+        if peer_version < FEATURE_NEGOTIATION_VERSION {
+            return Vec::new();
+        }
+        match self {
+            Emulation::Core => vec![NetworkMessage::WtxidRelay, NetworkMessage::SendAddrV2],
+            Emulation::Btcd => vec![NetworkMessage::SendAddrV2],
+            Emulation::Neutrino => Vec::new(),
+        }
+    }
+
+    /// What the implementation sends once the peer's verack arrives.
+    pub fn after_verack(self) -> Vec<NetworkMessage> {
+        match self {
+            Emulation::Core => vec![
+                NetworkMessage::SendHeaders,
+                NetworkMessage::SendCmpct(SendCmpct {
+                    send_compact: false,
+                    version: 2,
+                }),
+            ],
+            Emulation::Btcd => vec![NetworkMessage::SendHeaders],
+            Emulation::Neutrino => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Emulation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "core" => Ok(Emulation::Core),
+            "btcd" => Ok(Emulation::Btcd),
+            "neutrino" => Ok(Emulation::Neutrino),
+            _ => Err(anyhow!(
+                "Unknown implementation {s}, expected core, btcd or neutrino"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Emulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Emulation::Core => "core",
+            Emulation::Btcd => "btcd",
+            Emulation::Neutrino => "neutrino",
+        })
+    }
+}
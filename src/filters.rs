@@ -0,0 +1,226 @@
+use crate::client::P2pClient;
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_filter::{CFilter, GetCFilters};
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::{BlockHash, Script};
+use log::trace;
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The most filters a peer serves for a single getcfilters, per BIP157.
+pub const MAX_GETCFILTERS_SIZE: usize = 1000;
+
+/// Type of the basic BIP158 filter, the only one defined.
+pub const BASIC_FILTER: u8 = 0;
+
+/// Number of blocks requested at once when fetching matching blocks.
+const BLOCK_DOWNLOAD_WINDOW: usize = 16;
+
+/// What a rescan cost: the filters it downloaded and matched, and the blocks it then fetched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RescanReport {
+    pub filters: usize,
+    pub filter_bytes: usize,
+    /// Blocks whose filter matched one of the scripts, in chain order.
+    pub matched: Vec<BlockHash>,
+    pub block_bytes: usize,
+    /// Time spent waiting for filters.
+    pub filters_time: Duration,
+    /// Time spent matching filters against the scripts.
+    pub match_time: Duration,
+    /// Time spent waiting for the matching blocks.
+    pub blocks_time: Duration,
+}
+
+impl RescanReport {
+    pub fn total_time(&self) -> Duration {
+        self.filters_time + self.match_time + self.blocks_time
+    }
+}
+
+/// Loads scripts from a file with one hex encoded scriptPubKey per line, as listed by
+/// `bitcoin-cli getaddressinfo <address>`.
+pub fn read_scripts(path: impl AsRef<Path>) -> Result<Vec<Script>> {
+    let scripts = read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(Script::from(Vec::<u8>::from_hex(line)?)))
+        .collect::<Result<Vec<Script>>>()?;
+    if scripts.is_empty() {
+        return Err(anyhow!("Script file contains no scripts"));
+    }
+    Ok(scripts)
+}
+
+/// Fails unless the peer advertises serving compact block filters.
+pub fn check_filter_service<S: Read + Write>(client: &P2pClient<S>) -> Result<()> {
+    match client.peer() {
+        Some(peer) if peer.services.has(ServiceFlags::COMPACT_FILTERS) => Ok(()),
+        _ => Err(anyhow!(
+            "Target does not serve compact block filters (run it with -peerblockfilters)"
+        )),
+    }
+}
+
+/// Downloads the basic filters of `block_hashes`, the blocks from `start_height` on in chain
+/// order, in batches of [`MAX_GETCFILTERS_SIZE`].
+pub fn download_filters<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    start_height: u32,
+    block_hashes: &[BlockHash],
+) -> Result<Vec<CFilter>> {
+    let mut filters = Vec::with_capacity(block_hashes.len());
+    for (i, chunk) in block_hashes.chunks(MAX_GETCFILTERS_SIZE).enumerate() {
+        client.send(NetworkMessage::GetCFilters(GetCFilters {
+            filter_type: BASIC_FILTER,
+            start_height: start_height + (i * MAX_GETCFILTERS_SIZE) as u32,
+            stop_hash: *chunk.last().expect("chunks are not empty"),
+        }))?;
+        let mut expected = chunk.iter();
+        while let Some(block_hash) = expected.as_slice().first() {
+            match client.recv()? {
+                NetworkMessage::CFilter(filter) if filter.block_hash == *block_hash => {
+                    expected.next();
+                    filters.push(filter);
+                }
+                NetworkMessage::CFilter(filter) => {
+                    return Err(anyhow!(
+                        "Target sent the filter of {} instead of {block_hash}",
+                        filter.block_hash
+                    ));
+                }
+                _ => {}
+            }
+        }
+        trace!(
+            "Downloaded {} of {} filters",
+            filters.len(),
+            block_hashes.len()
+        );
+    }
+    Ok(filters)
+}
+
+/// The blocks whose filter matches any of `scripts`, in the order of `filters`.
+pub fn matching_blocks(filters: &[CFilter], scripts: &[Script]) -> Result<Vec<BlockHash>> {
+    let mut matched = Vec::new();
+    for filter in filters {
+        let mut query = scripts.iter().map(Script::as_bytes);
+        if BlockFilter::new(&filter.filter).match_any(&filter.block_hash, &mut query)? {
+            matched.push(filter.block_hash);
+        }
+    }
+    Ok(matched)
+}
+
+/// Downloads `block_hashes` and returns the bytes they took.
+pub fn fetch_blocks_by_hash<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    block_hashes: &[BlockHash],
+) -> Result<usize> {
+    let mut bytes = 0;
+    for chunk in block_hashes.chunks(BLOCK_DOWNLOAD_WINDOW) {
+        client.send(NetworkMessage::GetData(
+            chunk.iter().copied().map(Inventory::WitnessBlock).collect(),
+        ))?;
+        let mut pending: HashSet<_> = chunk.iter().copied().collect();
+        while !pending.is_empty() {
+            match client.recv()? {
+                NetworkMessage::Block(block) if pending.remove(&block.block_hash()) => {
+                    bytes += block.size();
+                }
+                NetworkMessage::NotFound(_) => {
+                    return Err(anyhow!("Target does not have all matching blocks"));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Downloads the filters of `block_hashes`, the blocks from `start_height` on in chain order,
+/// matches them against `scripts` and fetches the blocks that match, as a wallet rescanning
+/// the range would.
+pub fn rescan<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    start_height: u32,
+    block_hashes: &[BlockHash],
+    scripts: &[Script],
+) -> Result<RescanReport> {
+    let start = Instant::now();
+    let filters = download_filters(client, start_height, block_hashes)?;
+    let filters_time = start.elapsed();
+
+    let start = Instant::now();
+    let matched = matching_blocks(&filters, scripts)?;
+    let match_time = start.elapsed();
+
+    let start = Instant::now();
+    let block_bytes = fetch_blocks_by_hash(client, &matched)?;
+    Ok(RescanReport {
+        filters: filters.len(),
+        filter_bytes: filters.iter().map(|filter| filter.filter.len()).sum(),
+        matched,
+        block_bytes,
+        filters_time,
+        match_time,
+        blocks_time: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{sent_messages, MockStream, BLOCK_RESPONSES};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::network::message::RawNetworkMessage;
+    use bitcoin::Network;
+
+    #[test]
+    fn rescan_fetches_only_the_blocks_whose_filter_matches() {
+        let magic = Network::Bitcoin.magic();
+        let genesis = genesis_block(Network::Bitcoin);
+        let cfilter = CFilter {
+            filter_type: 0,
+            block_hash: genesis.block_hash(),
+            filter: BlockFilter::new_script_filter(&genesis, |_| unreachable!())
+                .unwrap()
+                .content,
+        };
+        let message = serialize(&RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::CFilter(cfilter.clone()),
+        });
+        let fixture = message.to_hex() + BLOCK_RESPONSES;
+        let mut client = P2pClient::new(MockStream::new(&fixture), magic);
+        let wallet = [
+            bitcoin::Script::new_op_return(&[1]),
+            genesis.txdata[0].output[0].script_pubkey.clone(),
+        ];
+        let report = rescan(&mut client, 0, &[genesis.block_hash()], &wallet).unwrap();
+        assert_eq!(report.filters, 1);
+        assert_eq!(report.matched, [genesis.block_hash()]);
+        assert_eq!(report.block_bytes, genesis.size());
+        let sent = sent_messages(&client.into_inner().output);
+        assert!(matches!(
+            &sent[0],
+            NetworkMessage::GetCFilters(request)
+                if request.start_height == 0 && request.stop_hash == genesis.block_hash()
+        ));
+        assert!(matches!(&sent[1], NetworkMessage::GetData(inventory) if inventory.len() == 1));
+
+        let unrelated = [bitcoin::Script::new_op_return(&[1])];
+        assert!(matching_blocks(&[cfilter], &unrelated).unwrap().is_empty());
+    }
+}
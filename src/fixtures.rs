@@ -0,0 +1,174 @@
+//! Synthetic byte streams of mainnet messages, built by hand after what Bitcoin Core 25.0 sends
+//! rather than captured from a node, for exercising the handshake and receive logic without a
+//! network.
+
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use std::io::{self, Cursor, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// What a node sends to a new inbound peer announcing protocol version 70016, as ours do by
+/// default: version, wtxidrelay, sendaddrv2 and verack, followed by the sendcmpct, ping and
+/// feefilter it sends once it received our verack. A peer announcing an older version would get
+/// neither wtxidrelay nor sendaddrv2.
+pub const HANDSHAKE: &str = concat!(
+    "f9beb4d976657273696f6e000000000066000000a23947e780110100090400000000000000e816650000000000000000",
+    "0000000000000000000000000000ffff7f000001c8220904000000000000000000000000000000000000000000000000",
+    "10447e2b9c5a3f1d102f5361746f7368693a32352e302e302f105c0c0001f9beb4d9777478696472656c617900000000",
+    "00005df6e0e2f9beb4d973656e646164647276320000000000005df6e0e2f9beb4d976657261636b0000000000000000",
+    "00005df6e0e2f9beb4d973656e64636d70637400000009000000e92f5ef8000200000000000000f9beb4d970696e6700",
+    "000000000000000800000033bc15e5efcdab8967452301f9beb4d966656566696c74657200000008000000e80fd19fe8",
+    "03000000000000",
+);
+
+/// Two `block` responses carrying the genesis block, with a ping from the node in between.
+pub const BLOCK_RESPONSES: &str = concat!(
+    "f9beb4d9626c6f636b000000000000001d010000f71a2403010000000000000000000000000000000000000000000000",
+    "0000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49",
+    "ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffff",
+    "ffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e2062",
+    "72696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104",
+    "678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de",
+    "5c384df7ba0b8d578a4c702b6bf11d5fac00000000f9beb4d970696e67000000000000000008000000f27162782a0000",
+    "0000000000f9beb4d9626c6f636b000000000000001d010000f71a240301000000000000000000000000000000000000",
+    "00000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e",
+    "4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000",
+    "000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72",
+    "206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a0100",
+    "0000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504",
+    "e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+);
+
+/// Nonce of the ping in [`BLOCK_RESPONSES`].
+pub const BLOCK_RESPONSES_PING_NONCE: u64 = 42;
+
+/// A failure the peer exhibits at the message with the given index in a fixture.
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// Stalls before the message is readable.
+    Delay(usize, Duration),
+    /// Never sends the message.
+    Drop(usize),
+    /// Sends the message's payload under another command.
+    WrongCommand(usize, &'static str),
+    /// Closes the connection halfway through the message.
+    Disconnect(usize),
+    /// Sends a frame with a bad checksum and a non-ASCII command before the message.
+    Garbage(usize),
+    /// Sends the message with a checksum that does not match its payload.
+    BadChecksum(usize),
+}
+
+/// Splits a fixture into its messages by the payload length in each header.
+fn frames(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut rest = bytes;
+    while rest.len() >= 24 {
+        let len = u32::from_le_bytes(rest[16..20].try_into().unwrap()) as usize;
+        let (frame, tail) = rest.split_at(24 + len);
+        frames.push(frame.to_vec());
+        rest = tail;
+    }
+    frames
+}
+
+/// The messages in `output`, e.g. what was written to a [`MockStream`].
+pub fn sent_messages(output: &[u8]) -> Vec<NetworkMessage> {
+    let mut output = Cursor::new(output);
+    let mut messages = Vec::new();
+    while (output.position() as usize) < output.get_ref().len() {
+        messages.push(
+            RawNetworkMessage::consensus_decode(&mut output)
+                .unwrap()
+                .payload,
+        );
+    }
+    messages
+}
+
+/// A stream that reads from a fixture and records everything written to it.
+pub struct MockStream {
+    input: Cursor<Vec<u8>>,
+    /// Input positions to stall at, in order.
+    delays: Vec<(u64, Duration)>,
+    pub output: Vec<u8>,
+}
+
+impl MockStream {
+    pub fn new(fixture: &str) -> Self {
+        Self::with_faults(fixture, &[])
+    }
+
+    /// Replays `fixture` as a misbehaving peer would, applying each fault to its message.
+    pub fn with_faults(fixture: &str, faults: &[Fault]) -> Self {
+        let mut input = Vec::new();
+        let mut delays = Vec::new();
+        for (index, mut frame) in frames(&Vec::from_hex(fixture).unwrap())
+            .into_iter()
+            .enumerate()
+        {
+            for fault in faults {
+                match *fault {
+                    Fault::Delay(i, delay) if i == index => {
+                        delays.push((input.len() as u64, delay));
+                    }
+                    Fault::Drop(i) if i == index => frame.clear(),
+                    Fault::WrongCommand(i, command) if i == index => {
+                        frame[4..16].fill(0);
+                        frame[4..4 + command.len()].copy_from_slice(command.as_bytes());
+                    }
+                    Fault::Disconnect(i) if i == index => frame.truncate(frame.len() / 2),
+                    Fault::Garbage(i) if i == index => {
+                        input.extend_from_slice(&frame[..4]);
+                        input.extend_from_slice(&[0xff; 12]);
+                        input.extend_from_slice(&4u32.to_le_bytes());
+                        input.extend_from_slice(&[0; 8]);
+                    }
+                    Fault::BadChecksum(i) if i == index => frame[20] ^= 0xff,
+                    _ => {}
+                }
+            }
+            let disconnected = faults
+                .iter()
+                .any(|fault| matches!(fault, Fault::Disconnect(i) if *i == index));
+            input.append(&mut frame);
+            if disconnected {
+                break;
+            }
+        }
+        Self {
+            input: Cursor::new(input),
+            delays,
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.input.position();
+        let mut len = buf.len();
+        if let Some(&(at, delay)) = self.delays.first() {
+            if at == position {
+                thread::sleep(delay);
+                self.delays.remove(0);
+            } else {
+                // Stop at the delayed message, so the next read stalls before returning it.
+                len = len.min((at - position) as usize);
+            }
+        }
+        self.input.read(&mut buf[..len])
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
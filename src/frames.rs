@@ -0,0 +1,186 @@
+use crate::{parse_header, MESSAGE_HEADER_SIZE};
+use anyhow::Result;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::message::{CommandString, RawNetworkMessage};
+use bitcoin::BlockHash;
+use std::io::{self, ErrorKind, Read};
+
+/// A message as read off the wire, its length checked but its payload not yet decoded, so
+/// callers only pay for decoding the messages they need to look into.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub header: [u8; MESSAGE_HEADER_SIZE],
+    pub command: CommandString,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Whether the payload matches the checksum in the header.
+    pub fn checksum_ok(&self) -> bool {
+        sha256d::Hash::hash(&self.payload)[..4] == self.header[20..]
+    }
+
+    /// Bytes the message took on the wire, header included.
+    pub fn size(&self) -> usize {
+        MESSAGE_HEADER_SIZE + self.payload.len()
+    }
+
+    /// The block a `block` or `cmpctblock` message carries, hashed from the header leading its
+    /// payload, or a `blocktxn` message answers, without decoding the rest.
+    pub fn block_hash(&self) -> Option<BlockHash> {
+        match self.command.as_ref() {
+            "block" | "cmpctblock" => self
+                .payload
+                .get(..80)
+                .map(|header| BlockHash::from_hash(sha256d::Hash::hash(header))),
+            "blocktxn" => self
+                .payload
+                .get(..32)
+                .and_then(|hash| BlockHash::from_slice(hash).ok()),
+            _ => None,
+        }
+    }
+
+    /// Decodes the whole message, checksum included.
+    pub fn decode(&self) -> Result<RawNetworkMessage> {
+        Ok(deserialize(&[&self.header[..], &self.payload].concat())?)
+    }
+}
+
+/// The messages read from a stream, one frame at a time. Ends when the stream closes between
+/// messages; any other failure is returned as an error item, after which it ends as well.
+///
+/// A read timing out, see [`timed_out`], is the exception: what was read of the frame so far is
+/// kept and the next item resumes it, so a stream read with a short timeout stays in sync.
+#[derive(Debug)]
+pub struct Frames<R> {
+    reader: R,
+    failed: bool,
+    header: [u8; MESSAGE_HEADER_SIZE],
+    /// Bytes of the header read so far.
+    header_read: usize,
+    /// The payload the header announced, once it was read, and how much of it arrived.
+    payload: Option<(Vec<u8>, usize)>,
+}
+
+impl<R: Read> Frames<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            failed: false,
+            header: [0; MESSAGE_HEADER_SIZE],
+            header_read: 0,
+            payload: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame>> {
+        while self.header_read < MESSAGE_HEADER_SIZE {
+            match self.reader.read(&mut self.header[self.header_read..]) {
+                Ok(0) if self.header_read == 0 => return Ok(None),
+                Ok(0) => return Err(closed_mid_message()),
+                Ok(n) => self.header_read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let (command, len) = parse_header(&self.header)?;
+        let (payload, read) = self.payload.get_or_insert_with(|| (vec![0; len], 0));
+        while *read < len {
+            match self.reader.read(&mut payload[*read..]) {
+                Ok(0) => return Err(closed_mid_message()),
+                Ok(n) => *read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let (payload, _) = self.payload.take().unwrap_or_default();
+        self.header_read = 0;
+        Ok(Some(Frame {
+            header: self.header,
+            command,
+            payload,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Frames<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Result<Frame>> {
+        if self.failed {
+            return None;
+        }
+        let frame = self.read_frame().transpose();
+        self.failed = matches!(&frame, Some(Err(e)) if !timed_out(e));
+        frame
+    }
+}
+
+fn closed_mid_message() -> anyhow::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "Target closed the connection mid message",
+    )
+    .into()
+}
+
+/// Whether `error` is a read timing out, after which [`Frames`] can go on reading.
+pub fn timed_out(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::BLOCK_RESPONSES;
+    use bitcoin::hashes::hex::FromHex;
+    use std::collections::VecDeque;
+
+    /// Hands out `chunks` one per read, timing out between them.
+    struct Trickle(VecDeque<Vec<u8>>, bool);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let Some(mut chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            if len < chunk.len() {
+                self.0.push_front(chunk.split_off(len));
+            }
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn frames_resume_after_a_timeout_mid_message() {
+        let bytes = Vec::from_hex(BLOCK_RESPONSES).unwrap();
+        let chunks = bytes.chunks(7).map(<[u8]>::to_vec).collect();
+        let frames = Frames::new(Trickle(chunks, false));
+        let mut commands = Vec::new();
+        let mut timeouts = 0;
+        for frame in frames {
+            match frame {
+                Ok(frame) => commands.push(frame.decode().unwrap().cmd().to_string()),
+                Err(e) if timed_out(&e) => timeouts += 1,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        assert_eq!(commands, ["block", "ping", "block"]);
+        // Most of them mid message.
+        assert!(timeouts > bytes.len() / 7);
+    }
+}
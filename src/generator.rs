@@ -0,0 +1,356 @@
+use crate::mine::unknown_block_hash;
+use crate::tx::unknown_txid;
+use crate::{InventoryType, RequestConfig};
+use anyhow::{anyhow, Result};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_compact_blocks::GetBlockTxn;
+use bitcoin::util::bip152::BlockTransactionsRequest;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Builds the requests a connection sends and recognizes the responses to them, leaving the
+/// handshake, pacing, answering the target and reporting to [`crate::request_with`].
+pub trait RequestGenerator: fmt::Debug + Send + Sync {
+    /// The request messages for `config.number` requests.
+    fn requests(&self, config: &RequestConfig) -> Result<Vec<NetworkMessage>>;
+
+    /// Number of responses `request` asks for.
+    fn expected_responses(&self, request: &NetworkMessage) -> usize {
+        match request {
+            NetworkMessage::GetData(inventory) => inventory.len(),
+            _ => 1,
+        }
+    }
+
+    /// Whether a message with `command` and the serialized `payload` answers one of our
+    /// requests. Other messages are decoded and answered as the target's own requests. An error
+    /// ends the run.
+    fn is_response(&self, command: &str, payload: &[u8]) -> Result<bool>;
+
+    /// Whether a message with `command` answers one of our requests, judged without its payload
+    /// because the payload failed its checksum.
+    fn is_response_command(&self, command: &str) -> bool;
+
+    /// Services the target must advertise to answer the requests built from `config`, checked
+    /// with [`check_services`](crate::check_services) right after the handshake so a target that
+    /// cannot answer fails the run instead of leaving it waiting.
+    fn required_services(&self, _config: &RequestConfig) -> ServiceFlags {
+        ServiceFlags::NONE
+    }
+}
+
+/// Inspects the responses to another generator's requests, e.g. to verify them or collect
+/// statistics on them. An inspector is a [`RequestGenerator`] sending those requests, leaving
+/// everything but the inspection to [`inner`](Inspector::inner).
+pub trait Inspector: fmt::Debug + Send + Sync {
+    type Inner: RequestGenerator;
+
+    fn inner(&self) -> &Self::Inner;
+
+    /// Inspects a message with `command` and the serialized `payload` that the inner generator
+    /// counted as a response. An error ends the run.
+    fn inspect(&self, command: &str, payload: &[u8]) -> Result<()>;
+}
+
+impl<T: Inspector> RequestGenerator for T {
+    fn requests(&self, config: &RequestConfig) -> Result<Vec<NetworkMessage>> {
+        self.inner().requests(config)
+    }
+
+    fn expected_responses(&self, request: &NetworkMessage) -> usize {
+        self.inner().expected_responses(request)
+    }
+
+    fn is_response(&self, command: &str, payload: &[u8]) -> Result<bool> {
+        let counted = self.inner().is_response(command, payload)?;
+        if counted {
+            self.inspect(command, payload)?;
+        }
+        Ok(counted)
+    }
+
+    fn is_response_command(&self, command: &str) -> bool {
+        self.inner().is_response_command(command)
+    }
+
+    fn required_services(&self, config: &RequestConfig) -> ServiceFlags {
+        self.inner().required_services(config)
+    }
+}
+
+/// getdata requests for inventory entries whose types cycle through a template.
+#[derive(Clone, Debug)]
+pub struct InventoryRequests {
+    template: Vec<InventoryType>,
+    commands: Vec<&'static str>,
+}
+
+impl InventoryRequests {
+    pub fn new(template: Vec<InventoryType>) -> Self {
+        let mut commands: Vec<_> = template
+            .iter()
+            .map(InventoryType::response_command)
+            .collect();
+        commands.sort();
+        commands.dedup();
+        Self { template, commands }
+    }
+}
+
+impl RequestGenerator for InventoryRequests {
+    fn requests(&self, config: &RequestConfig) -> Result<Vec<NetworkMessage>> {
+        if self.template.is_empty() {
+            return Err(anyhow!("Inventory template is empty"));
+        }
+        if self.template.iter().any(InventoryType::is_tx) && config.txids.is_empty() {
+            return Err(anyhow!(
+                "Inventory template contains transaction entries but no txids were given"
+            ));
+        }
+        Ok(getdata_messages(&self.template, config))
+    }
+
+    fn required_services(&self, config: &RequestConfig) -> ServiceFlags {
+        self.template
+            .iter()
+            .fold(ServiceFlags::NONE, |services, inventory_type| {
+                services
+                    | match inventory_type {
+                        InventoryType::Block | InventoryType::CompactBlock => {
+                            block_services(config)
+                        }
+                        InventoryType::WitnessBlock => {
+                            block_services(config) | ServiceFlags::WITNESS
+                        }
+                        InventoryType::Tx => ServiceFlags::NONE,
+                        InventoryType::WitnessTx => ServiceFlags::WITNESS,
+                    }
+            })
+    }
+
+    fn is_response(&self, command: &str, _payload: &[u8]) -> Result<bool> {
+        if self.commands.contains(&command) {
+            Ok(true)
+        } else if command == "block" && self.commands.contains(&"cmpctblock") {
+            Err(too_deep(&self.commands))
+        } else if command == "notfound" {
+            Err(anyhow!(
+                "Target sent notfound: it does not have the requested {}",
+                self.commands.join("/")
+            ))
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn is_response_command(&self, command: &str) -> bool {
+        self.commands.contains(&command)
+    }
+}
+
+/// getblocktxn requests for the transactions at `indexes` of each block.
+#[derive(Clone, Debug)]
+pub struct BlockTxnRequests {
+    indexes: Vec<u64>,
+}
+
+impl BlockTxnRequests {
+    pub fn new(indexes: Vec<u64>) -> Self {
+        Self { indexes }
+    }
+}
+
+impl RequestGenerator for BlockTxnRequests {
+    fn requests(&self, config: &RequestConfig) -> Result<Vec<NetworkMessage>> {
+        Ok(config
+            .block_hashes
+            .iter()
+            .cycle()
+            .take(config.number)
+            .map(|block_hash| {
+                NetworkMessage::GetBlockTxn(GetBlockTxn {
+                    txs_request: BlockTransactionsRequest {
+                        block_hash: *block_hash,
+                        indexes: self.indexes.clone(),
+                    },
+                })
+            })
+            .collect())
+    }
+
+    fn is_response(&self, command: &str, _payload: &[u8]) -> Result<bool> {
+        match command {
+            "blocktxn" => Ok(true),
+            "block" => Err(too_deep(&["blocktxn"])),
+            _ => Ok(false),
+        }
+    }
+
+    fn is_response_command(&self, command: &str) -> bool {
+        command == "blocktxn"
+    }
+
+    fn required_services(&self, config: &RequestConfig) -> ServiceFlags {
+        block_services(config)
+    }
+}
+
+/// Which lookups the requests of [`MissRequests`] miss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Miss {
+    /// Transactions the target does not have, answered with notfound.
+    Tx,
+    /// Blocks the target does not have, which Core ignores.
+    Block,
+}
+
+/// getdata requests of `config.batch` entries the target cannot serve, one per request, to
+/// load the lookup-miss path rather than the serving one.
+///
+/// A transaction miss is answered with notfound. Block misses are followed by a ping, as Core
+/// answers unknown blocks with nothing, so the pong marks the target being done with them.
+/// Blocks a pruned target no longer has are not covered: Core disconnects peers asking for them.
+#[derive(Clone, Debug)]
+pub struct MissRequests {
+    miss: Miss,
+}
+
+impl MissRequests {
+    pub fn new(miss: Miss) -> Self {
+        Self { miss }
+    }
+}
+
+impl RequestGenerator for MissRequests {
+    fn requests(&self, config: &RequestConfig) -> Result<Vec<NetworkMessage>> {
+        let mut requests = Vec::new();
+        for nonce in 0..config.number as u64 {
+            let inventory = (0..config.batch.max(1))
+                .map(|_| match self.miss {
+                    Miss::Tx => Inventory::Transaction(unknown_txid()),
+                    Miss::Block => Inventory::WitnessBlock(unknown_block_hash()),
+                })
+                .collect();
+            requests.push(NetworkMessage::GetData(inventory));
+            if self.miss != Miss::Tx {
+                requests.push(NetworkMessage::Ping(nonce));
+            }
+        }
+        Ok(requests)
+    }
+
+    fn expected_responses(&self, request: &NetworkMessage) -> usize {
+        match (self.miss, request) {
+            (Miss::Tx, NetworkMessage::GetData(_)) | (_, NetworkMessage::Ping(_)) => 1,
+            _ => 0,
+        }
+    }
+
+    fn is_response(&self, command: &str, _payload: &[u8]) -> Result<bool> {
+        match (self.miss, command) {
+            (Miss::Tx, "notfound") | (Miss::Block, "pong") => Ok(true),
+            (_, "tx" | "block") => Err(anyhow!("Target served an entry it should not have")),
+            _ => Ok(false),
+        }
+    }
+
+    fn is_response_command(&self, command: &str) -> bool {
+        matches!(
+            (self.miss, command),
+            (Miss::Tx, "notfound") | (Miss::Block, "pong")
+        )
+    }
+}
+
+/// Services serving the blocks `config` requests: `NETWORK_LIMITED` if they are all recent enough
+/// for a pruned peer to serve, `NETWORK` otherwise.
+fn block_services(config: &RequestConfig) -> ServiceFlags {
+    if config.recent_blocks {
+        ServiceFlags::NETWORK_LIMITED
+    } else {
+        ServiceFlags::NETWORK
+    }
+}
+
+pub(crate) fn too_deep(commands: &[&str]) -> anyhow::Error {
+    anyhow!("Received block response instead of expected {}. Requested block is too deep in the chain. Try with a block that is <10 blocks deep from chain tip.", commands.join("/"))
+}
+
+/// Builds getdata messages for `config.number` inventory entries whose types cycle through
+/// `template`, rotating through the configured hashes and carrying up to `config.batch`
+/// entries per message.
+fn getdata_messages(template: &[InventoryType], config: &RequestConfig) -> Vec<NetworkMessage> {
+    let mut block_hashes = config.block_hashes.iter().cycle();
+    let mut txids = config.txids.iter().cycle();
+    let entries: Vec<_> = template
+        .iter()
+        .cycle()
+        .take(config.number)
+        .map(|inventory_type| match inventory_type {
+            InventoryType::Block => Inventory::Block(*block_hashes.next().unwrap()),
+            InventoryType::WitnessBlock => Inventory::WitnessBlock(*block_hashes.next().unwrap()),
+            InventoryType::CompactBlock => Inventory::CompactBlock(*block_hashes.next().unwrap()),
+            InventoryType::Tx => Inventory::Transaction(*txids.next().unwrap()),
+            InventoryType::WitnessTx => Inventory::WitnessTransaction(*txids.next().unwrap()),
+        })
+        .collect();
+    entries
+        .chunks(config.batch.max(1))
+        .map(|chunk| NetworkMessage::GetData(chunk.to_vec()))
+        .collect()
+}
+
+/// Request generators by name, so a request type chosen by name can come from another crate.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    generators: BTreeMap<String, Arc<dyn RequestGenerator>>,
+}
+
+impl Registry {
+    /// A registry holding the request types this crate provides: `witness-block`,
+    /// `compact-block`, `block-transactions`, `legacy-block`, `missing-tx` and `missing-block`.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry.register(
+            "witness-block",
+            InventoryRequests::new(vec![InventoryType::WitnessBlock]),
+        );
+        registry.register(
+            "compact-block",
+            InventoryRequests::new(vec![InventoryType::CompactBlock]),
+        );
+        registry.register("block-transactions", BlockTxnRequests::new(vec![1]));
+        registry.register(
+            "legacy-block",
+            InventoryRequests::new(vec![InventoryType::Block]),
+        );
+        registry.register("missing-tx", MissRequests::new(Miss::Tx));
+        registry.register("missing-block", MissRequests::new(Miss::Block));
+        registry
+    }
+
+    /// Registers `generator` under `name`, replacing any generator already registered there.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        generator: impl RequestGenerator + 'static,
+    ) {
+        self.generators.insert(name.into(), Arc::new(generator));
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn RequestGenerator>> {
+        self.generators.get(name).cloned().ok_or_else(|| {
+            anyhow!(
+                "Unknown request type {name}, expected one of {}",
+                self.names().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.generators.keys().map(String::as_str)
+    }
+}
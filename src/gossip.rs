@@ -0,0 +1,189 @@
+use anyhow::Result;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::network::address::AddrV2;
+use bitcoin::network::message::NetworkMessage;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An address the target gossiped to us in an addr or addrv2 message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GossipedAddress {
+    /// When the message carrying it was received.
+    pub received: SystemTime,
+    /// `addr` or `addrv2`.
+    pub message: &'static str,
+    /// BIP155 name of the address's network, e.g. `ipv4` or `torv3`.
+    pub network: &'static str,
+    /// The IP address, or the hex encoded key of overlay network addresses.
+    pub address: String,
+    pub port: u16,
+    pub services: u64,
+    /// When the address was last seen, as the target advertised it, in seconds since the Unix
+    /// epoch.
+    pub last_seen: u32,
+}
+
+impl GossipedAddress {
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(json!({
+            "ts_us": self.received.duration_since(UNIX_EPOCH)?.as_micros() as u64,
+            "message": self.message,
+            "network": self.network,
+            "address": self.address,
+            "port": self.port,
+            "services": self.services,
+            "last_seen": self.last_seen,
+        }))
+    }
+
+    /// The `ip:port` to connect to the address at, if it is an IP address.
+    pub fn socket_address(&self) -> Option<String> {
+        match self.network {
+            "ipv4" => Some(format!("{}:{}", self.address, self.port)),
+            "ipv6" => Some(format!("[{}]:{}", self.address, self.port)),
+            _ => None,
+        }
+    }
+}
+
+/// The addresses `message` gossips, none unless it is an addr or addrv2 message.
+pub fn gossiped_addresses(message: &NetworkMessage, received: SystemTime) -> Vec<GossipedAddress> {
+    match message {
+        NetworkMessage::Addr(addresses) => addresses
+            .iter()
+            .map(|(last_seen, address)| {
+                let (network, ip) = match address.socket_addr().map(|addr| addr.ip()) {
+                    Ok(IpAddr::V4(ip)) => ("ipv4", ip.to_string()),
+                    Ok(IpAddr::V6(ip)) => ("ipv6", ip.to_string()),
+                    // Onion addresses in the legacy encoding.
+                    Err(_) => (
+                        "torv2",
+                        address.address.map(u16::to_be_bytes).concat().to_hex(),
+                    ),
+                };
+                GossipedAddress {
+                    received,
+                    message: "addr",
+                    network,
+                    address: ip,
+                    port: address.port,
+                    services: address.services.to_u64(),
+                    last_seen: *last_seen,
+                }
+            })
+            .collect(),
+        NetworkMessage::AddrV2(addresses) => addresses
+            .iter()
+            .map(|entry| {
+                let (network, address) = match &entry.addr {
+                    AddrV2::Ipv4(ip) => ("ipv4", ip.to_string()),
+                    AddrV2::Ipv6(ip) => ("ipv6", ip.to_string()),
+                    AddrV2::TorV2(key) => ("torv2", key.to_hex()),
+                    AddrV2::TorV3(key) => ("torv3", key.to_hex()),
+                    AddrV2::I2p(key) => ("i2p", key.to_hex()),
+                    AddrV2::Cjdns(ip) => ("cjdns", ip.to_string()),
+                    AddrV2::Unknown(_, bytes) => ("unknown", bytes.to_hex()),
+                };
+                GossipedAddress {
+                    received,
+                    message: "addrv2",
+                    network,
+                    address,
+                    port: entry.port,
+                    services: entry.services.to_u64(),
+                    last_seen: entry.time,
+                }
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Gossip received over a run: every address, one JSON object per line, and counts per network.
+pub struct GossipLog {
+    writer: Box<dyn Write + Send>,
+    pub messages: usize,
+    pub by_network: BTreeMap<&'static str, usize>,
+}
+
+impl GossipLog {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer,
+            messages: 0,
+            by_network: BTreeMap::new(),
+        }
+    }
+
+    /// Records the addresses of one message.
+    pub fn record(&mut self, addresses: &[GossipedAddress]) -> Result<()> {
+        self.messages += 1;
+        for address in addresses {
+            *self.by_network.entry(address.network).or_default() += 1;
+            writeln!(self.writer, "{}", address.to_json()?)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn addresses(&self) -> usize {
+        self.by_network.values().sum()
+    }
+
+    /// Addresses received per minute over `elapsed`.
+    pub fn rate(&self, elapsed: Duration) -> f64 {
+        self.addresses() as f64 / elapsed.as_secs_f64().max(f64::EPSILON) * 60.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::network::address::{AddrV2Message, Address};
+    use bitcoin::network::constants::ServiceFlags;
+
+    #[test]
+    fn gossiped_addresses_carry_their_network() {
+        let received = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let addr = NetworkMessage::Addr(vec![(
+            1_699_999_000,
+            Address::new(&"10.0.0.2:8333".parse().unwrap(), ServiceFlags::NETWORK),
+        )]);
+        let addrv2 = NetworkMessage::AddrV2(vec![
+            AddrV2Message {
+                time: 1_699_999_500,
+                services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                addr: AddrV2::TorV3([0xab; 32]),
+                port: 8333,
+            },
+            AddrV2Message {
+                time: 1_699_999_600,
+                services: ServiceFlags::NONE,
+                addr: AddrV2::Ipv6("2001:db8::1".parse().unwrap()),
+                port: 8333,
+            },
+        ]);
+        let addresses = gossiped_addresses(&addr, received);
+        assert_eq!(
+            (addresses[0].network, addresses[0].address.as_str()),
+            ("ipv4", "10.0.0.2")
+        );
+        let addresses = gossiped_addresses(&addrv2, received);
+        let networks: Vec<_> = addresses.iter().map(|address| address.network).collect();
+        assert_eq!(networks, ["torv3", "ipv6"]);
+        assert_eq!(addresses[0].address, "ab".repeat(32));
+        assert!(gossiped_addresses(&NetworkMessage::Verack, received).is_empty());
+
+        let mut log = GossipLog::new(Box::new(std::io::sink()));
+        log.record(&addresses).unwrap();
+        assert_eq!((log.messages, log.addresses()), (1, 2));
+        assert_eq!(log.rate(Duration::from_secs(30)), 4.0);
+        assert_eq!(
+            addresses[1].to_json().unwrap()["ts_us"],
+            1_700_000_000_000_000u64
+        );
+    }
+}
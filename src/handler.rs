@@ -0,0 +1,333 @@
+use crate::emulate::Emulation;
+use crate::version::VersionBuilder;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_compact_blocks::SendCmpct;
+use bitcoin::{Block, BlockHash, BlockHeader};
+use log::trace;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The most headers we send in a single headers message.
+const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// Blocks we can serve to the target.
+pub trait BlockSource: fmt::Debug + Send + Sync {
+    fn block(&self, block_hash: &BlockHash) -> Option<Block>;
+
+    /// Headers following the first hash in `locator` we know, up to and including `stop`.
+    fn headers_after(&self, locator: &[BlockHash], stop: &BlockHash) -> Vec<BlockHeader>;
+
+    /// Height of our best block, advertised in our version message.
+    fn height(&self) -> i32;
+}
+
+/// Block source holding a chain of blocks in memory.
+#[derive(Debug, Default)]
+pub struct MemoryBlockSource {
+    chain: Vec<Block>,
+    index: HashMap<BlockHash, usize>,
+    start_height: i32,
+}
+
+impl MemoryBlockSource {
+    pub fn len(&self) -> usize {
+        self.chain.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    /// Builds a source from consecutive blocks, where the first block is at `start_height`.
+    pub fn new(chain: Vec<Block>, start_height: i32) -> Self {
+        let index = chain
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.block_hash(), i))
+            .collect();
+        Self {
+            chain,
+            index,
+            start_height,
+        }
+    }
+
+    /// Loads consecutive blocks from a file with one hex encoded block per line, as returned by
+    /// `bitcoin-cli getblock <hash> 0`.
+    pub fn from_hex_file(path: impl AsRef<Path>, start_height: i32) -> Result<Self> {
+        let chain = read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(deserialize(&Vec::<u8>::from_hex(line)?)?))
+            .collect::<Result<Vec<Block>>>()?;
+        if chain.is_empty() {
+            return Err(anyhow!("Block file contains no blocks"));
+        }
+        Ok(Self::new(chain, start_height))
+    }
+}
+
+impl BlockSource for MemoryBlockSource {
+    fn block(&self, block_hash: &BlockHash) -> Option<Block> {
+        self.index.get(block_hash).map(|i| self.chain[*i].clone())
+    }
+
+    fn headers_after(&self, locator: &[BlockHash], stop: &BlockHash) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.index.get(hash))
+            .map_or(0, |i| i + 1);
+        let mut headers = Vec::new();
+        for block in self.chain.iter().skip(start).take(MAX_HEADERS_RESULTS) {
+            headers.push(block.header);
+            if block.block_hash() == *stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    fn height(&self) -> i32 {
+        self.start_height + self.chain.len() as i32 - 1
+    }
+}
+
+/// How the target asked us to relay to it, from the messages it sends around the handshake.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelayPreferences {
+    /// Its last sendcmpct: whether it wants new blocks as high-bandwidth compact blocks, and the
+    /// compact block version.
+    pub compact_blocks: Option<SendCmpct>,
+    /// Minimum fee rate of transactions to announce to it, in sat/kvB.
+    pub fee_filter: Option<i64>,
+    /// Whether it wants new blocks announced with headers rather than inv.
+    pub send_headers: bool,
+    /// Whether it announces and requests transactions by wtxid.
+    pub wtxid_relay: bool,
+}
+
+impl RelayPreferences {
+    /// Whether `message` states a preference.
+    pub fn is_preference(message: &NetworkMessage) -> bool {
+        matches!(
+            message,
+            NetworkMessage::SendCmpct(_)
+                | NetworkMessage::FeeFilter(_)
+                | NetworkMessage::SendHeaders
+                | NetworkMessage::WtxidRelay
+        )
+    }
+
+    /// Records `message` if it states a preference.
+    pub fn record(&mut self, message: &NetworkMessage) {
+        match message {
+            NetworkMessage::SendCmpct(send_compact) => self.compact_blocks = Some(*send_compact),
+            NetworkMessage::FeeFilter(fee_rate) => self.fee_filter = Some(*fee_rate),
+            NetworkMessage::SendHeaders => self.send_headers = true,
+            NetworkMessage::WtxidRelay => self.wtxid_relay = true,
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RelayPreferences {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut preferences = Vec::new();
+        if let Some(send_compact) = &self.compact_blocks {
+            let mode = match send_compact.send_compact {
+                true => "high-bandwidth",
+                false => "low-bandwidth",
+            };
+            preferences.push(format!("sendcmpct {mode} v{}", send_compact.version));
+        }
+        if let Some(fee_rate) = self.fee_filter {
+            preferences.push(format!("feefilter {fee_rate} sat/kvB"));
+        }
+        if self.send_headers {
+            preferences.push("sendheaders".to_string());
+        }
+        if self.wtxid_relay {
+            preferences.push("wtxidrelay".to_string());
+        }
+        match preferences.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", preferences.join(", ")),
+        }
+    }
+}
+
+/// Answers the requests the target sends us, so we look like a regular peer while applying load.
+///
+/// Pings are always answered. With a block source, getheaders and getdata for blocks are served
+/// from it; without one, we have no blocks, so getheaders gets an empty reply and getdata a
+/// notfound.
+#[derive(Clone, Debug)]
+pub struct MessageHandler {
+    magic: u32,
+    block_source: Option<Arc<dyn BlockSource>>,
+    /// Version message to open handshakes with, instead of one advertising our block source.
+    version: Option<VersionBuilder>,
+    /// What the target asked for so far, shared by clones answering the same connection.
+    relay_preferences: Arc<Mutex<RelayPreferences>>,
+    /// Whether handshakes announce support for addrv2 messages, per BIP155.
+    addrv2: bool,
+    /// Implementation whose handshake ours imitates.
+    emulation: Option<Emulation>,
+}
+
+impl MessageHandler {
+    pub fn new(magic: u32, block_source: Option<Arc<dyn BlockSource>>) -> Self {
+        Self {
+            magic,
+            block_source,
+            version: None,
+            relay_preferences: Arc::default(),
+            addrv2: false,
+            emulation: None,
+        }
+    }
+
+    pub fn with_version(mut self, version: VersionBuilder) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Announces support for addrv2 messages during handshakes, so the target gossips addresses
+    /// of every network, not just those an addr message can carry.
+    pub fn with_addrv2(mut self) -> Self {
+        self.addrv2 = true;
+        self
+    }
+
+    /// Handshakes like `emulation`, opening with its version message unless one was set.
+    pub fn with_emulation(mut self, emulation: Emulation) -> Self {
+        self.version.get_or_insert_with(|| emulation.version());
+        self.emulation = Some(emulation);
+        self
+    }
+
+    /// Answers `message` if it is a request, returning the number of blocks served.
+    pub fn handle<W: Write>(&self, writer: &mut W, message: &NetworkMessage) -> Result<usize> {
+        let replies = self.replies(message);
+        for reply in &replies {
+            writer.write_all(&serialize(reply))?;
+        }
+        Ok(served(&replies))
+    }
+
+    /// The messages answering `message`, none if it is not a request, for callers doing their
+    /// own writing.
+    pub fn replies(&self, message: &NetworkMessage) -> Vec<RawNetworkMessage> {
+        self.relay_preferences.lock().unwrap().record(message);
+        let mut replies = Vec::new();
+        match message {
+            NetworkMessage::Ping(nonce) => {
+                trace!("Received ping, sending pong");
+                replies.push(NetworkMessage::Pong(*nonce));
+            }
+            NetworkMessage::GetHeaders(request) => {
+                let headers = self
+                    .block_source
+                    .as_ref()
+                    .map(|source| source.headers_after(&request.locator_hashes, &request.stop_hash))
+                    .unwrap_or_default();
+                trace!("Received getheaders, sending {} headers", headers.len());
+                replies.push(NetworkMessage::Headers(headers));
+            }
+            NetworkMessage::GetData(inventory) => {
+                let mut not_found = Vec::new();
+                for entry in inventory {
+                    let block = match entry {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => self
+                            .block_source
+                            .as_ref()
+                            .and_then(|source| source.block(hash)),
+                        _ => None,
+                    };
+                    match block {
+                        Some(block) => replies.push(NetworkMessage::Block(block)),
+                        None => not_found.push(*entry),
+                    }
+                }
+                trace!(
+                    "Received getdata for {} entries, {} not found",
+                    inventory.len(),
+                    not_found.len()
+                );
+                if !not_found.is_empty() {
+                    replies.push(NetworkMessage::NotFound(not_found));
+                }
+            }
+            message => trace!("Received message {:?}", message),
+        }
+        replies
+            .into_iter()
+            .map(|payload| RawNetworkMessage {
+                magic: self.magic,
+                payload,
+            })
+            .collect()
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    /// Services advertised in our version message. We only claim to serve blocks if we have a
+    /// block source.
+    pub fn services(&self) -> ServiceFlags {
+        match self.block_source {
+            Some(_) => ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            None => ServiceFlags::WITNESS,
+        }
+    }
+
+    /// Height advertised in our version message.
+    pub fn start_height(&self) -> i32 {
+        self.block_source
+            .as_ref()
+            .map_or(0, |source| source.height())
+    }
+
+    /// Whether handshakes announce support for addrv2 messages.
+    pub fn addrv2(&self) -> bool {
+        self.addrv2
+    }
+
+    /// Implementation whose handshake ours imitates, if any.
+    pub fn emulation(&self) -> Option<Emulation> {
+        self.emulation
+    }
+
+    /// What the target asked for in the messages answered so far.
+    pub fn relay_preferences(&self) -> RelayPreferences {
+        self.relay_preferences.lock().unwrap().clone()
+    }
+
+    /// The version message handshakes open with.
+    pub fn version(&self) -> VersionBuilder {
+        self.version.clone().unwrap_or_else(|| {
+            VersionBuilder::default()
+                .with_services(self.services())
+                .with_start_height(self.start_height())
+        })
+    }
+}
+
+/// Number of blocks among `replies`.
+pub(crate) fn served(replies: &[RawNetworkMessage]) -> usize {
+    replies
+        .iter()
+        .filter(|reply| matches!(reply.payload, NetworkMessage::Block(_)))
+        .count()
+}
@@ -0,0 +1,244 @@
+use crate::handler::MessageHandler;
+use crate::headers::{get_headers, MAX_HEADERS_RESULTS};
+use crate::locator::locator_from_headers;
+use crate::perform_handshake;
+use crate::rng;
+use crate::transport::Connection;
+use anyhow::{anyhow, Result};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::network::message::MAX_MSG_SIZE;
+use bitcoin::{BlockHash, BlockHeader, Network};
+use log::trace;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of a serialized block header.
+const HEADER_SIZE: usize = 80;
+
+/// A headers chain from genesis cached in a flat file of consecutive serialized headers, so
+/// later runs only sync the headers the peer gained since.
+#[derive(Debug)]
+pub struct HeaderStore {
+    path: PathBuf,
+    headers: Vec<BlockHeader>,
+    index: HashMap<BlockHash, usize>,
+}
+
+impl HeaderStore {
+    /// Loads the headers stored at `path`, starting with just the genesis header of `network`
+    /// if the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>, network: Network) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let genesis = genesis_block(network).header;
+        let mut store = Self {
+            path,
+            headers: Vec::new(),
+            index: HashMap::new(),
+        };
+        let bytes = match File::open(&store.path) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                bytes
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                store.append(&[genesis])?;
+                return Ok(store);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let display = store.path.display().to_string();
+        let invalid = || anyhow!("Invalid header store {display}");
+        if bytes.len() % HEADER_SIZE != 0 {
+            return Err(invalid());
+        }
+        for chunk in bytes.chunks(HEADER_SIZE) {
+            let header: BlockHeader = deserialize(chunk)?;
+            let connects = match store.headers.last() {
+                Some(last) => header.prev_blockhash == last.block_hash(),
+                None => header == genesis,
+            };
+            if !connects {
+                return Err(invalid());
+            }
+            store.push(header);
+        }
+        if store.headers.is_empty() {
+            return Err(invalid());
+        }
+        Ok(store)
+    }
+
+    /// Fetches the headers the peer has beyond ours, replacing ours from where the peer's chain
+    /// forks off, and returns the number of headers added.
+    pub fn sync(&mut self, stream: &mut Connection, magic: u32) -> Result<usize> {
+        let handler = MessageHandler::new(magic, None);
+        perform_handshake(stream, &handler)?;
+
+        let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+        let mut added = 0;
+        loop {
+            let locator = locator_from_headers(&self.headers);
+            let headers = get_headers(stream, &mut reader, &handler, locator)?;
+            let Some(first) = headers.first() else {
+                break;
+            };
+            let full = headers.len() == MAX_HEADERS_RESULTS;
+            let fork = *self.index.get(&first.prev_blockhash).ok_or_else(|| {
+                anyhow!("Peer sent headers that do not connect to the stored chain")
+            })?;
+            if headers
+                .windows(2)
+                .any(|pair| pair[1].prev_blockhash != pair[0].block_hash())
+            {
+                return Err(anyhow!("Peer sent headers that are not consecutive"));
+            }
+            // A peer behind us resends headers we have, which must not cost us our later ones.
+            let known = headers
+                .iter()
+                .zip(&self.headers[fork + 1..])
+                .take_while(|(theirs, ours)| theirs == ours)
+                .count();
+            let headers = &headers[known..];
+            if headers.is_empty() {
+                break;
+            }
+            let fork = fork + known;
+            if fork + 1 < self.headers.len() {
+                trace!("Peer's chain forks off at height {fork}");
+                self.truncate(fork + 1)?;
+            }
+            self.append(headers)?;
+            added += headers.len();
+            if !full {
+                break;
+            }
+        }
+        trace!(
+            "Synced {added} headers, tip at height {}",
+            self.tip_height()
+        );
+        Ok(added)
+    }
+
+    pub fn tip_height(&self) -> usize {
+        self.headers.len() - 1
+    }
+
+    pub fn height(&self, block_hash: &BlockHash) -> Option<usize> {
+        self.index.get(block_hash).copied()
+    }
+
+    pub fn hash_at(&self, height: usize) -> Option<BlockHash> {
+        self.headers.get(height).map(BlockHeader::block_hash)
+    }
+
+    /// Hashes of the last `count` blocks, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<BlockHash> {
+        let start = self.headers.len().saturating_sub(count);
+        self.headers[start..]
+            .iter()
+            .map(BlockHeader::block_hash)
+            .collect()
+    }
+
+    /// Hashes of `block_hash` and the up to `count` blocks following it, or `None` if the block
+    /// is not stored.
+    pub fn following(&self, block_hash: &BlockHash, count: usize) -> Option<Vec<BlockHash>> {
+        let height = self.height(block_hash)?;
+        let end = self.headers.len().min(height + count + 1);
+        Some(
+            self.headers[height..end]
+                .iter()
+                .map(BlockHeader::block_hash)
+                .collect(),
+        )
+    }
+
+    /// Hashes of `count` blocks spread uniformly over the chain after genesis, one drawn at
+    /// random from each of `count` equal stretches of it, oldest first.
+    pub fn scattered(&self, count: usize) -> Result<Vec<BlockHash>> {
+        let blocks = self.tip_height();
+        if count == 0 || count > blocks {
+            return Err(anyhow!(
+                "Cannot pick {count} blocks from a chain of {blocks} blocks after genesis"
+            ));
+        }
+        Ok((0..count)
+            .map(|i| {
+                let start = 1 + i * blocks / count;
+                let end = 1 + (i + 1) * blocks / count;
+                let height = start + rng::with_rng(|rng| rng.next_u64()) as usize % (end - start);
+                self.headers[height].block_hash()
+            })
+            .collect())
+    }
+
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    fn push(&mut self, header: BlockHeader) {
+        self.index.insert(header.block_hash(), self.headers.len());
+        self.headers.push(header);
+    }
+
+    fn append(&mut self, headers: &[BlockHeader]) -> Result<()> {
+        let bytes: Vec<u8> = headers.iter().flat_map(serialize).collect();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&bytes)?;
+        for header in headers {
+            self.push(*header);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)?
+            .set_len((len * HEADER_SIZE) as u64)?;
+        for header in self.headers.drain(len..) {
+            self.index.remove(&header.block_hash());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mine::mine_chain;
+
+    #[test]
+    fn scattered_blocks_cover_the_whole_chain() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let chain = mine_chain(genesis.block_hash(), 100, 0x207fffff, genesis.time + 1);
+        let path = std::env::temp_dir().join(format!("scattered-{}.headers", std::process::id()));
+        let bytes: Vec<u8> = std::iter::once(&genesis)
+            .chain(&chain)
+            .flat_map(serialize)
+            .collect();
+        std::fs::write(&path, bytes).unwrap();
+        let store = HeaderStore::open(&path, Network::Regtest);
+        std::fs::remove_file(&path).unwrap();
+        let store = store.unwrap();
+
+        let heights: Vec<_> = store
+            .scattered(10)
+            .unwrap()
+            .iter()
+            .map(|block_hash| store.height(block_hash).unwrap())
+            .collect();
+        for (i, height) in heights.iter().enumerate() {
+            assert!((i * 10 + 1..=i * 10 + 10).contains(height), "{heights:?}");
+        }
+        assert!(store.scattered(101).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::client::P2pClient;
+use crate::handler::MessageHandler;
+use crate::locator::locator_from_hashes;
+use crate::perform_handshake;
+use crate::transport::Connection;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::{ServiceFlags, PROTOCOL_VERSION};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::network::message_blockdata::GetHeadersMessage;
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::{Block, BlockHash, BlockHeader};
+use log::trace;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, Read, Write};
+
+/// The most headers a peer sends in a single headers message.
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// Blocks below the tip a node advertising `NETWORK_LIMITED` without `NETWORK` serves, per
+/// BIP159. Older blocks may have been pruned.
+pub const NETWORK_LIMITED_BLOCKS: usize = 288;
+
+/// Number of blocks requested at once when fetching blocks.
+const BLOCK_DOWNLOAD_WINDOW: usize = 16;
+
+/// Fetches the hashes of the last `count` blocks of the peer's best chain, oldest first.
+///
+/// `locator` should contain hashes the peer already knows, most recent first, so that headers
+/// sync starts close to the tip instead of at genesis.
+pub fn recent_block_hashes(
+    stream: &mut Connection,
+    magic: u32,
+    locator: Vec<BlockHash>,
+    count: usize,
+) -> Result<Vec<BlockHash>> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    let mut hashes = VecDeque::with_capacity(count + 1);
+    let mut locator = locator;
+    loop {
+        let headers = get_headers(stream, &mut reader, &handler, locator)?;
+        if hashes.is_empty() {
+            // The first header builds on the locator hash the peer recognized.
+            if let Some(first) = headers.first() {
+                hashes.push_back(first.prev_blockhash);
+            }
+        }
+        for header in &headers {
+            hashes.push_back(header.block_hash());
+            if hashes.len() > count {
+                hashes.pop_front();
+            }
+        }
+        match hashes.back() {
+            Some(last) if headers.len() == MAX_HEADERS_RESULTS => locator = vec![*last],
+            _ => break,
+        }
+    }
+
+    if hashes.is_empty() {
+        return Err(anyhow!(
+            "Peer sent no headers after the locator. Try an older --block-hash"
+        ));
+    }
+    trace!("Resolved {} recent blocks", hashes.len());
+    Ok(hashes.into())
+}
+
+/// Whether a peer advertising `services` only serves its last [`NETWORK_LIMITED_BLOCKS`] blocks.
+pub fn is_pruned(services: ServiceFlags) -> bool {
+    services.has(ServiceFlags::NETWORK_LIMITED) && !services.has(ServiceFlags::NETWORK)
+}
+
+/// Number of blocks the peer's best chain has on top of `block_hash`, or `None` if the block is
+/// not on it or is at least [`MAX_HEADERS_RESULTS`] deep.
+pub fn block_depth<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    block_hash: BlockHash,
+) -> Result<Option<usize>> {
+    client.send(NetworkMessage::GetHeaders(GetHeadersMessage {
+        version: PROTOCOL_VERSION,
+        locator_hashes: vec![block_hash],
+        stop_hash: BlockHash::all_zeros(),
+    }))?;
+    loop {
+        if let NetworkMessage::Headers(headers) = client.recv()? {
+            // A peer not knowing the block sends headers from genesis instead.
+            return Ok(match headers.first() {
+                None => Some(0),
+                Some(first) if first.prev_blockhash != block_hash => None,
+                Some(_) if headers.len() == MAX_HEADERS_RESULTS => None,
+                Some(_) => Some(headers.len()),
+            });
+        }
+    }
+}
+
+/// Fetches the hashes of `block_hash` and the up to `count` blocks following it on the peer's best
+/// chain, in chain order.
+pub fn following_block_hashes(
+    stream: &mut Connection,
+    magic: u32,
+    block_hash: BlockHash,
+    count: usize,
+) -> Result<Vec<BlockHash>> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    let mut hashes = vec![block_hash];
+    while hashes.len() <= count {
+        let headers = get_headers(stream, &mut reader, &handler, vec![*hashes.last().unwrap()])?;
+        match headers.first() {
+            None => break,
+            Some(first) if first.prev_blockhash != *hashes.last().unwrap() => {
+                return Err(anyhow!(
+                    "Block {block_hash} is not on the peer's best chain"
+                ));
+            }
+            Some(_) => {}
+        }
+        let full = headers.len() == MAX_HEADERS_RESULTS;
+        hashes.extend(
+            headers
+                .iter()
+                .take(count + 1 - hashes.len())
+                .map(BlockHeader::block_hash),
+        );
+        if !full {
+            break;
+        }
+    }
+    trace!(
+        "Resolved {} blocks following {block_hash}",
+        hashes.len() - 1
+    );
+    Ok(hashes)
+}
+
+/// Downloads the `count` blocks following the first hash in `locator` the peer knows, in chain
+/// order.
+pub fn fetch_blocks(
+    stream: &mut Connection,
+    magic: u32,
+    locator: Vec<BlockHash>,
+    count: usize,
+) -> Result<Vec<Block>> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    let mut hashes = Vec::with_capacity(count);
+    let mut locator = locator;
+    while hashes.len() < count {
+        let headers = get_headers(stream, &mut reader, &handler, locator)?;
+        hashes.extend(
+            headers
+                .iter()
+                .take(count - hashes.len())
+                .map(BlockHeader::block_hash),
+        );
+        if headers.len() < MAX_HEADERS_RESULTS || hashes.is_empty() {
+            break;
+        }
+        locator = locator_from_hashes(&hashes);
+    }
+
+    let mut blocks = Vec::with_capacity(hashes.len());
+    for chunk in hashes.chunks(BLOCK_DOWNLOAD_WINDOW) {
+        let message = RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::GetData(
+                chunk
+                    .iter()
+                    .map(|hash| Inventory::WitnessBlock(*hash))
+                    .collect(),
+            ),
+        };
+        stream.write_all(&serialize(&message))?;
+        let mut received = HashMap::with_capacity(chunk.len());
+        while received.len() < chunk.len() {
+            let reply = RawNetworkMessage::consensus_decode(&mut reader)?;
+            match reply.payload {
+                NetworkMessage::Block(block) => {
+                    received.insert(block.block_hash(), block);
+                }
+                NetworkMessage::NotFound(_) => {
+                    return Err(anyhow!("Peer does not have all requested blocks"));
+                }
+                payload => {
+                    handler.handle(stream, &payload)?;
+                }
+            }
+        }
+        blocks.extend(chunk.iter().filter_map(|hash| received.remove(hash)));
+        trace!("Fetched {} of {} blocks", blocks.len(), hashes.len());
+    }
+    Ok(blocks)
+}
+
+/// Downloads a single block from the peer.
+pub fn fetch_block(stream: &mut Connection, magic: u32, block_hash: BlockHash) -> Result<Block> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    let message = RawNetworkMessage {
+        magic,
+        payload: NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)]),
+    };
+    stream.write_all(&serialize(&message))?;
+    loop {
+        let reply = RawNetworkMessage::consensus_decode(&mut reader)?;
+        match reply.payload {
+            NetworkMessage::Block(block) if block.block_hash() == block_hash => return Ok(block),
+            NetworkMessage::NotFound(_) => {
+                return Err(anyhow!("Peer does not have block {block_hash}"));
+            }
+            payload => {
+                handler.handle(stream, &payload)?;
+            }
+        }
+    }
+}
+
+/// Sends a getheaders message and waits for the peer's headers reply, letting `handler` answer
+/// anything else the peer sends meanwhile.
+pub(crate) fn get_headers(
+    stream: &mut Connection,
+    reader: &mut BufReader<Connection>,
+    handler: &MessageHandler,
+    locator: Vec<BlockHash>,
+) -> Result<Vec<BlockHeader>> {
+    let message = RawNetworkMessage {
+        magic: handler.magic(),
+        payload: NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: locator,
+            stop_hash: BlockHash::all_zeros(),
+        }),
+    };
+    stream.write_all(&serialize(&message))?;
+    trace!("Sent getheaders message");
+
+    loop {
+        let reply = RawNetworkMessage::consensus_decode(reader)?;
+        match reply.payload {
+            NetworkMessage::Headers(headers) => {
+                trace!("Received {} headers", headers.len());
+                return Ok(headers);
+            }
+            payload => {
+                handler.handle(stream, &payload)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MockStream;
+    use crate::mine::mine_chain;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::Network;
+
+    #[test]
+    fn block_depth_counts_the_headers_on_top_of_a_block() {
+        let magic = Network::Bitcoin.magic();
+        let block_hash = BlockHash::hash(&[1]);
+        let replies = [
+            mine_chain(block_hash, 5, 0x207fffff, 0),
+            // Headers from genesis, sent by a peer that does not know the block.
+            mine_chain(BlockHash::all_zeros(), 5, 0x207fffff, 0),
+            vec![],
+        ];
+        let fixture: String = replies
+            .into_iter()
+            .map(|headers| {
+                serialize(&RawNetworkMessage {
+                    magic,
+                    payload: NetworkMessage::Headers(headers),
+                })
+                .to_hex()
+            })
+            .collect();
+        let mut client = P2pClient::new(MockStream::new(&fixture), magic);
+        let depths: Vec<_> = (0..3)
+            .map(|_| block_depth(&mut client, block_hash).unwrap())
+            .collect();
+        assert_eq!(depths, [Some(5), None, Some(0)]);
+        assert!(is_pruned(
+            ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS
+        ));
+        assert!(!is_pruned(
+            ServiceFlags::NETWORK | ServiceFlags::NETWORK_LIMITED
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the first Ctrl-C while an [`Interrupts`] is held.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C until dropped, so a run can wind down as if it had finished instead of being
+/// killed before printing its summary, recording it and flushing its logs. A second Ctrl-C exits
+/// at once, for a run that does not wind down.
+pub struct Interrupts(());
+
+impl Interrupts {
+    pub fn catch() -> Self {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        set_handler(true);
+        Self(())
+    }
+
+    /// Whether Ctrl-C was pressed since [`catch`](Self::catch).
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        set_handler(false);
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: _exit is async-signal-safe, unlike exit.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_handler(catch: bool) {
+    let handler = match catch {
+        true => on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        false => libc::SIG_DFL,
+    };
+    // SAFETY: the handler only touches an atomic and calls _exit, both async-signal-safe.
+    unsafe { libc::signal(libc::SIGINT, handler) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_handler(_catch: bool) {}
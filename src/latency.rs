@@ -0,0 +1,218 @@
+use crate::rng::with_rng;
+use crate::transport::Connection;
+use anyhow::{anyhow, Error, Result};
+use bitcoin::secp256k1::rand::Rng;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Size of the chunks the delay line reads from the socket.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// One-way delay added to everything we send and receive, given as `base[:jitter]`, e.g. `50ms`
+/// or `50ms:10ms`.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    base: Duration,
+    /// Upper bound of a uniformly distributed extra delay.
+    jitter: Duration,
+}
+
+impl FromStr for Latency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (base, jitter) = match s.split_once(':') {
+            Some((base, jitter)) => (parse_duration(base)?, parse_duration(jitter)?),
+            None => (parse_duration(s)?, Duration::ZERO),
+        };
+        Ok(Latency { base, jitter })
+    }
+}
+
+impl Latency {
+    fn sample(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.base;
+        }
+        self.base + with_rng(|rng| self.jitter.mul_f64(rng.gen::<f64>()))
+    }
+}
+
+/// Parses a duration with a unit suffix, e.g. `250us`, `50ms`, `2s`, `1.5s`, `10m`, `6h` or `1d`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(|| anyhow!("Duration {s} has no unit"))?;
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| anyhow!("Invalid duration {s}"))?;
+    let seconds = match unit {
+        "us" => value / 1_000_000.0,
+        "ms" => value / 1_000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        "d" => value * 86_400.0,
+        _ => return Err(anyhow!("Invalid duration unit {unit} in {s}")),
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Schedules chunks for release after a sampled latency, never before an earlier chunk, so
+/// jitter cannot reorder the stream.
+struct Schedule {
+    latency: Latency,
+    last: Instant,
+}
+
+impl Schedule {
+    fn new(latency: Latency) -> Self {
+        Schedule {
+            latency,
+            last: Instant::now(),
+        }
+    }
+
+    fn next(&mut self) -> Instant {
+        self.last = self.last.max(Instant::now() + self.latency.sample());
+        self.last
+    }
+}
+
+fn sleep_until(at: Instant) {
+    thread::sleep(at.saturating_duration_since(Instant::now()));
+}
+
+/// Holds back traffic on a connection like a long link would: a thread reads from the socket
+/// and releases each chunk to readers after the latency, and another writes each chunk to the
+/// socket after the latency. Throughput is unaffected.
+pub struct DelayLine {
+    inner: Connection,
+    incoming: Mutex<Incoming>,
+    outgoing: Mutex<Outgoing>,
+    writer: Option<JoinHandle<()>>,
+    /// Error the writer thread hit, reported by the next write.
+    write_error: Arc<Mutex<Option<io::Error>>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+struct Incoming {
+    chunks: Receiver<(Instant, io::Result<Vec<u8>>)>,
+    /// Released bytes not yet read.
+    pending: VecDeque<u8>,
+}
+
+struct Outgoing {
+    chunks: Option<Sender<(Instant, Vec<u8>)>>,
+    schedule: Schedule,
+}
+
+impl DelayLine {
+    pub(crate) fn new(inner: Connection, latency: Latency) -> Result<Self> {
+        let (incoming, chunks) = channel();
+        let mut reader = inner.try_clone()?;
+        thread::spawn(move || {
+            let mut schedule = Schedule::new(latency);
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let chunk = reader.read(&mut buf).map(|len| buf[..len].to_vec());
+                let done = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+                if incoming.send((schedule.next(), chunk)).is_err() || done {
+                    return;
+                }
+            }
+        });
+
+        let (outgoing, chunks_out) = channel::<(Instant, Vec<u8>)>();
+        let mut writer = inner.try_clone()?;
+        let write_error = Arc::new(Mutex::new(None));
+        let writer_error = write_error.clone();
+        let writer = thread::spawn(move || {
+            for (release_at, chunk) in chunks_out {
+                sleep_until(release_at);
+                if let Err(e) = writer.write_all(&chunk) {
+                    *writer_error.lock().unwrap() = Some(e);
+                    return;
+                }
+            }
+        });
+
+        Ok(DelayLine {
+            inner,
+            incoming: Mutex::new(Incoming {
+                chunks,
+                pending: VecDeque::new(),
+            }),
+            outgoing: Mutex::new(Outgoing {
+                chunks: Some(outgoing),
+                schedule: Schedule::new(latency),
+            }),
+            writer: Some(writer),
+            write_error,
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    pub(crate) fn inner(&self) -> &Connection {
+        &self.inner
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.read_timeout.lock().unwrap() = timeout;
+    }
+
+    pub(crate) fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.pending.is_empty() {
+            let timeout = *self.read_timeout.lock().unwrap();
+            let received = match timeout {
+                Some(timeout) => incoming.chunks.recv_timeout(timeout),
+                None => incoming
+                    .chunks
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let (release_at, chunk) = match received {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::WouldBlock.into()),
+                // The reader thread already passed on the end of the stream.
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            sleep_until(release_at);
+            incoming.pending.extend(chunk?);
+        }
+        let len = buf.len().min(incoming.pending.len());
+        for (byte, pending) in buf.iter_mut().zip(incoming.pending.drain(..len)) {
+            *byte = pending;
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.write_error.lock().unwrap().take() {
+            return Err(e);
+        }
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let release_at = outgoing.schedule.next();
+        match &outgoing.chunks {
+            Some(chunks) if chunks.send((release_at, buf.to_vec())).is_ok() => Ok(buf.len()),
+            _ => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for DelayLine {
+    /// Lets the writer thread deliver what is still held back, then closes the socket so the
+    /// reader thread stops too.
+    fn drop(&mut self) {
+        self.outgoing.get_mut().unwrap().chunks.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        self.inner.shutdown();
+    }
+}
@@ -1,194 +1,1855 @@
+pub mod advertise;
+pub mod analyze;
+#[cfg(feature = "async")]
+pub mod async_handler;
+pub mod ban;
+pub mod bandwidth;
+pub mod block_stats;
+pub mod blocktxn;
+pub mod client;
+#[cfg(feature = "async")]
+pub mod codec;
+pub mod collapse;
+pub mod config_file;
+pub mod conformance;
+pub mod consistency;
+pub mod control_api;
+pub mod controller;
+pub mod crawl;
+pub mod decode_pool;
+mod discard;
+pub mod distributed;
+pub mod emulate;
+pub mod filters;
+#[cfg(test)]
+mod fixtures;
+pub mod frames;
+pub mod generator;
+pub mod gossip;
+pub mod handler;
+pub mod header_store;
+pub mod headers;
+pub mod history;
+mod http;
+pub mod interrupt;
+pub mod latency;
+pub mod light_client;
+pub mod limits;
+pub mod locator;
+pub mod log_file;
+pub mod memory;
+pub mod metrics;
+pub mod mine;
+pub mod observe;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pipe;
+pub mod plan;
+pub mod prefill;
+pub mod profile;
+pub mod report;
+pub mod rng;
+pub mod rpc;
+pub mod schedule;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod shell;
+pub mod shortid;
+pub mod stall;
+pub mod stats;
+pub mod tcp_info;
+pub mod throttle;
+pub mod transport;
+pub mod tx;
+pub mod version;
+pub mod webhook;
+
 use anyhow::{anyhow, Error, Result};
-use bitcoin::consensus::encode::CheckedData;
-use bitcoin::consensus::{serialize, Decodable};
-use bitcoin::network::address::Address;
+use bitcoin::consensus::{self, deserialize, serialize, Decodable};
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{CommandString, NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
-use bitcoin::network::message_blockdata::Inventory;
-use bitcoin::network::message_compact_blocks::GetBlockTxn;
 use bitcoin::network::message_network::VersionMessage;
 use bitcoin::secp256k1::rand::Rng;
-use bitcoin::util::bip152::BlockTransactionsRequest;
-use bitcoin::{secp256k1, BlockHash};
+use bitcoin::{Block, BlockHash, Txid};
+use control_api::RunControl;
+use controller::SharedRate;
+use decode_pool::DecodePool;
+use discard::discard_responses;
+use emulate::Emulation;
+use frames::Frames;
+use generator::{BlockTxnRequests, InventoryRequests, RequestGenerator};
+use handler::{BlockSource, MessageHandler, RelayPreferences};
 use log::trace;
-use std::io::{BufReader, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use memory::{MemoryBudget, Reservation};
+use pipe::BlockPipe;
+use profile::{Phase, Profile, TimedReader};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tcp_info::TcpInfo;
+use throttle::Throttled;
+use transport::Connection;
+use version::{is_own_nonce, SentNonce, VersionBuilder};
+
+/// Length of the header preceding the payload of a serialized message.
+pub const MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Log target of the version handshake, so it can be followed apart from the rest of this module.
+pub const HANDSHAKE_LOG: &str = "spam_block_reqs::handshake";
+
+/// Log target of sending requests and pacing them.
+pub const SEND_LOG: &str = "spam_block_reqs::send";
+
+/// Log target of the receive loop, which logs every message read.
+pub const RECEIVE_LOG: &str = "spam_block_reqs::receive";
+
+/// Size of the batches requests are written to the socket in.
+const WRITE_CHUNK_SIZE: usize = 1 << 20;
+
+/// Bytes a connection's receiving side holds at most: its read buffer and one message.
+const RECEIVE_BUFFERS_SIZE: usize = 2 * MAX_MSG_SIZE;
+
+/// Inventory entries a connection generates and serializes requests for at a time, so it only
+/// holds a chunk of its requests however many it makes.
+const REQUEST_CHUNK: usize = 4096;
+
+/// Bytes reserved for a chunk of requests and its write buffer before it is generated. Chunks
+/// whose requests turn out larger are reserved again at their size.
+const REQUEST_CHUNK_SIZE: usize = WRITE_CHUNK_SIZE;
+
+/// Progress reported by a connection thread back to the coordinating thread.
+pub struct Event {
+    pub conn: usize,
+    pub time: Instant,
+    pub kind: EventKind,
+}
+
+pub enum EventKind {
+    Connected,
+    /// The handshake completed with a peer that announced this version, its stages taking this
+    /// long.
+    HandshakeComplete(PeerVersion, HandshakeStages),
+    RequestsSent,
+    /// A batch of requests, a chunk of up to [`REQUEST_CHUNK`] inventory entries, started being
+    /// sent.
+    BatchSent {
+        requests: usize,
+        /// Responses answering the batch.
+        responses: usize,
+    },
+    /// A response arrived.
+    Response {
+        /// Time since its request was sent.
+        latency: Duration,
+        /// Whether it answers the first request of a burst, see [`RequestConfig::burst`].
+        first_in_burst: bool,
+        /// Size of the message on the wire, header included.
+        bytes: usize,
+        /// Block the response carries, for block, compact block and blocktxn responses whose
+        /// payload was read.
+        block_hash: Option<BlockHash>,
+    },
+    /// We served a block the target requested from us.
+    BlockServed,
+    /// Time a write of our requests spent in the socket, which grows once the send buffer is
+    /// full because the target stopped draining it.
+    WriteBlocked(Duration),
+    /// A sample of the connection's TCP state.
+    TcpInfo(TcpInfo),
+    /// A response arrived whose payload does not match the checksum in its header.
+    BadChecksum,
+    /// The target stated how it wants us to relay to it, giving everything it asked for so far.
+    RelayPreferences(RelayPreferences),
+    /// The connection made no progress for this long and was torn down; nothing it sends later
+    /// counts.
+    Stalled(Duration),
+    Error(Error),
+}
+
+/// What the target announced about itself in its version message.
+#[derive(Clone, Debug)]
+pub struct PeerVersion {
+    pub version: u32,
+    pub services: ServiceFlags,
+    pub user_agent: String,
+    pub start_height: i32,
+}
+
+impl From<&VersionMessage> for PeerVersion {
+    fn from(message: &VersionMessage) -> Self {
+        PeerVersion {
+            version: message.version,
+            services: message.services,
+            user_agent: message.user_agent.clone(),
+            start_height: message.start_height,
+        }
+    }
+}
+
+/// When the peer's replies to our version arrived, counted from sending it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandshakeStages {
+    pub version: Duration,
+    pub verack: Duration,
+}
+
+/// Sends events tagged with the id of the connection they belong to.
+#[derive(Clone)]
+pub struct EventSender {
+    conn: usize,
+    sender: Sender<Event>,
+}
+
+impl EventSender {
+    pub fn new(conn: usize, sender: Sender<Event>) -> Self {
+        Self { conn, sender }
+    }
+
+    pub fn conn(&self) -> usize {
+        self.conn
+    }
+
+    /// Returns false once the receiving side has hung up.
+    pub fn send(&self, kind: EventKind) -> bool {
+        self.sender
+            .send(Event {
+                conn: self.conn,
+                time: Instant::now(),
+                kind,
+            })
+            .is_ok()
+    }
+}
+
+/// What to request from the peer.
+#[derive(Clone, Debug)]
+pub struct RequestConfig {
+    pub magic: u32,
+    /// Block hashes to request, rotated through in order.
+    pub block_hashes: Vec<BlockHash>,
+    /// Transaction ids used for transaction inventory entries, rotated through in order.
+    pub txids: Vec<Txid>,
+    /// Number of inventory entries to request. A getdata of [`generator::MissRequests`] counts
+    /// as one, however many entries it carries.
+    pub number: usize,
+    /// Number of inventory entries to carry in each getdata message.
+    pub batch: usize,
+    /// Blocks to serve when the target requests them from us.
+    pub block_source: Option<Arc<dyn BlockSource>>,
+    /// Bytes per second to read responses at, to act as a slow peer.
+    pub read_rate: Option<u64>,
+    /// Upper bound of a random pause before each request message, so connections do not send
+    /// in lockstep.
+    pub jitter: Option<Duration>,
+    /// Number of request messages to send at once before pausing for `pause`.
+    pub burst: Option<usize>,
+    /// Quiet period between bursts.
+    pub pause: Duration,
+    /// Rate to pace request messages to, adjusted while the requests are being sent.
+    pub rate: Option<Arc<SharedRate>>,
+    /// Run state to hold back requests on while the run is paused through the control API.
+    pub control: Option<Arc<RunControl>>,
+    /// Where to add up the time the client spends on its own work, see [`Phase`].
+    pub profile: Option<Arc<Profile>>,
+    /// Check the checksum of every message received. A response failing it is reported as
+    /// [`EventKind::BadChecksum`] instead of being counted.
+    pub verify_checksums: bool,
+    /// Count responses by their headers alone, reading the socket in large chunks and skipping
+    /// their payloads, so the client does as little work per response as possible. Rules out
+    /// checksums and generators that look at payloads.
+    pub discard: bool,
+    /// Workers to hand responses to for the generator to decode, instead of decoding them on
+    /// the connection's receiving thread. Responses are then told apart by command alone.
+    pub decode_pool: Option<Arc<DecodePool>>,
+    /// Budget to reserve each connection's buffers from, waiting for room under its cap.
+    pub memory: Option<Arc<MemoryBudget>>,
+    /// Consumer to forward every block received to, on top of counting it.
+    pub pipe: Option<Arc<BlockPipe>>,
+    /// Version message to open the handshake with, instead of the handler's default.
+    pub version: Option<VersionBuilder>,
+    /// Implementation to imitate the handshake of.
+    pub emulation: Option<Emulation>,
+    /// How long the whole handshake may take.
+    pub handshake_timeout: Option<Duration>,
+    /// Services the target must advertise on top of those the requests need.
+    pub required_services: ServiceFlags,
+    /// Whether every block requested is among the last
+    /// [`NETWORK_LIMITED_BLOCKS`](headers::NETWORK_LIMITED_BLOCKS) of the target's chain, which
+    /// peers advertising only `NETWORK_LIMITED` serve. Otherwise the requests need `NETWORK`.
+    pub recent_blocks: bool,
+    /// Send the requests over and over until the run ends instead of once, for soak tests.
+    pub repeat: bool,
+}
+
+impl RequestConfig {
+    pub fn handler(&self) -> MessageHandler {
+        let mut handler = MessageHandler::new(self.magic, self.block_source.clone());
+        if let Some(version) = &self.version {
+            handler = handler.with_version(version.clone());
+        }
+        match self.emulation {
+            Some(emulation) => handler.with_emulation(emulation),
+            None => handler,
+        }
+    }
+
+    /// A reader for responses on `stream`, throttled to `read_rate` if set.
+    fn response_reader(&self, stream: &Connection) -> Result<Box<dyn Read + Send>> {
+        let reader = stream.try_clone()?;
+        Ok(match self.read_rate {
+            Some(rate) => Box::new(Throttled::new(reader, rate)),
+            None => Box::new(reader),
+        })
+    }
+}
+
+/// The type of a single getdata inventory entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InventoryType {
+    Block,
+    WitnessBlock,
+    CompactBlock,
+    Tx,
+    WitnessTx,
+}
+
+impl InventoryType {
+    /// The command of the message the peer answers this entry with.
+    pub fn response_command(&self) -> &'static str {
+        match self {
+            InventoryType::Block | InventoryType::WitnessBlock => "block",
+            InventoryType::CompactBlock => "cmpctblock",
+            InventoryType::Tx | InventoryType::WitnessTx => "tx",
+        }
+    }
+
+    pub fn is_tx(&self) -> bool {
+        matches!(self, InventoryType::Tx | InventoryType::WitnessTx)
+    }
+}
+
+impl fmt::Display for InventoryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InventoryType::Block => "block",
+            InventoryType::WitnessBlock => "witness-block",
+            InventoryType::CompactBlock => "compact-block",
+            InventoryType::Tx => "tx",
+            InventoryType::WitnessTx => "witness-tx",
+        })
+    }
+}
+
+impl FromStr for InventoryType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(InventoryType::Block),
+            "witness-block" => Ok(InventoryType::WitnessBlock),
+            "compact-block" => Ok(InventoryType::CompactBlock),
+            "tx" => Ok(InventoryType::Tx),
+            "witness-tx" => Ok(InventoryType::WitnessTx),
+            _ => Err(anyhow!(
+                "Invalid inventory type {s}, expected one of block, witness-block, compact-block, tx, witness-tx"
+            )),
+        }
+    }
+}
 
 pub fn request_witness_blocks(
-    stream: &mut TcpStream,
-    block_hash: BlockHash,
-    number: usize,
-    sender: &Sender<Option<Error>>,
-    magic: u32,
+    stream: &mut Connection,
+    config: &RequestConfig,
+    events: &EventSender,
 ) -> Result<()> {
-    perform_handshake(stream, magic)?;
+    request_inventory(stream, &[InventoryType::WitnessBlock], config, events)
+}
 
-    let msg = RawNetworkMessage {
-        magic,
-        payload: NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)]),
-    };
-    make_requests(stream, msg, number)?;
+pub fn request_blocks(
+    stream: &mut Connection,
+    config: &RequestConfig,
+    events: &EventSender,
+) -> Result<()> {
+    request_inventory(stream, &[InventoryType::Block], config, events)
+}
+
+pub fn request_compact_blocks(
+    stream: &mut Connection,
+    config: &RequestConfig,
+    events: &EventSender,
+) -> Result<()> {
+    request_inventory(stream, &[InventoryType::CompactBlock], config, events)
+}
 
-    receive_responses(stream, "block", sender)?;
+/// Requests inventory entries whose types cycle through `template`, so a single getdata can
+/// mix entry types when batching.
+pub fn request_inventory(
+    stream: &mut Connection,
+    template: &[InventoryType],
+    config: &RequestConfig,
+    events: &EventSender,
+) -> Result<()> {
+    let generator = InventoryRequests::new(template.to_vec());
+    request_with(stream, &generator, config, events)
+}
 
-    Ok(())
+pub fn request_blocktxns(
+    stream: &mut Connection,
+    indexes: Vec<u64>,
+    config: &RequestConfig,
+    events: &EventSender,
+) -> Result<()> {
+    request_with(stream, &BlockTxnRequests::new(indexes), config, events)
 }
 
-pub fn request_blocks(
-    stream: &mut TcpStream,
-    block_hash: BlockHash,
-    number: usize,
-    sender: &Sender<Option<Error>>,
-    magic: u32,
+/// Sends the requests built by `generator` after the handshake and counts the responses it
+/// recognizes.
+pub fn request_with(
+    stream: &mut Connection,
+    generator: &dyn RequestGenerator,
+    config: &RequestConfig,
+    events: &EventSender,
 ) -> Result<()> {
-    perform_handshake(stream, magic)?;
+    // The receive buffers are held until the run ends, so only the requests wait for room.
+    let _receiving = config
+        .memory
+        .as_ref()
+        .map(|memory| memory.reserve_now(RECEIVE_BUFFERS_SIZE));
+    // Generated before the handshake, so requests the generator cannot build fail at once.
+    let mut chunks = RequestChunks::new(generator, config);
+    let first = chunks.next_chunk()?;
 
-    let msg = RawNetworkMessage {
-        magic,
-        payload: NetworkMessage::GetData(vec![Inventory::Block(block_hash)]),
+    let handler = config.handler();
+    let peer = handshake(stream, &handler, config.handshake_timeout, events)?;
+    check_services(
+        peer.services,
+        generator.required_services(config) | config.required_services,
+    )?;
+    let relay_preferences = handler.relay_preferences();
+    if relay_preferences != RelayPreferences::default() {
+        events.send(EventKind::RelayPreferences(relay_preferences));
+    }
+
+    send_and_receive(stream, first, chunks, generator, config, &handler, events)
+}
+
+/// A serialized request message and the number of responses it asks for.
+struct Request {
+    bytes: Vec<u8>,
+    responses: usize,
+}
+
+/// Serialized requests, and the memory reserved for them until they are sent.
+struct Chunk {
+    requests: Vec<Request>,
+    _reservation: Option<Reservation>,
+}
+
+impl Chunk {
+    fn batch_sent(&self) -> EventKind {
+        EventKind::BatchSent {
+            requests: self.requests.len(),
+            responses: self.requests.iter().map(|request| request.responses).sum(),
+        }
+    }
+}
+
+/// Generates a connection's requests [`REQUEST_CHUNK`] entries at a time, reserving the memory
+/// for each chunk from `config.memory` before generating it.
+struct RequestChunks<'a> {
+    generator: &'a dyn RequestGenerator,
+    config: &'a RequestConfig,
+    /// `config` without its block hashes, which each chunk takes its own share of.
+    base: RequestConfig,
+    /// Entries generated so far.
+    generated: usize,
+}
+
+impl<'a> RequestChunks<'a> {
+    fn new(generator: &'a dyn RequestGenerator, config: &'a RequestConfig) -> Self {
+        Self {
+            generator,
+            config,
+            base: RequestConfig {
+                block_hashes: Vec::new(),
+                ..config.clone()
+            },
+            generated: 0,
+        }
+    }
+
+    /// The next chunk of requests, or `None` once all `config.number` entries were generated.
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        let config = self.config;
+        if self.generated >= config.number {
+            return Ok(None);
+        }
+        let mut reservation = config
+            .memory
+            .as_ref()
+            .map(|memory| memory.reserve(REQUEST_CHUNK_SIZE));
+        let start = Instant::now();
+        let batch = config.batch.max(1);
+        let number = ((REQUEST_CHUNK / batch).max(1) * batch).min(config.number - self.generated);
+        // Each chunk starts rotating through the block hashes where the previous one's requests
+        // end, as each connection of a run does.
+        let len = config.block_hashes.len();
+        let block_hashes = match len {
+            0 => Vec::new(),
+            _ => config
+                .block_hashes
+                .iter()
+                .cycle()
+                .skip(self.generated % len)
+                .take(number.min(len))
+                .copied()
+                .collect(),
+        };
+        let chunk_config = RequestConfig {
+            number,
+            block_hashes,
+            ..self.base.clone()
+        };
+        let requests: Vec<_> = self
+            .generator
+            .requests(&chunk_config)?
+            .into_iter()
+            .map(|payload| Request {
+                responses: self.generator.expected_responses(&payload),
+                bytes: serialize(&RawNetworkMessage {
+                    magic: config.magic,
+                    payload,
+                }),
+            })
+            .collect();
+        if let Some(profile) = &config.profile {
+            profile.add(Phase::Serialize, start.elapsed());
+        }
+        self.generated += number;
+        let size: usize = requests.iter().map(|request| request.bytes.len()).sum();
+        let needed = size + size.min(WRITE_CHUNK_SIZE);
+        if let Some(memory) = config
+            .memory
+            .as_ref()
+            .filter(|_| needed > REQUEST_CHUNK_SIZE)
+        {
+            // Released first, as waiting while holding it could leave every connection waiting
+            // on the others.
+            drop(reservation.take());
+            reservation = Some(memory.reserve(needed));
+        }
+        Ok(Some(Chunk {
+            requests,
+            _reservation: reservation,
+        }))
+    }
+}
+
+/// Sends `first`, then the rest of `chunks`, and counts the responses `generator` recognizes,
+/// releasing each chunk once it is sent.
+///
+/// With `config.jitter`, `config.burst` or `config.rate`, the requests are spread out from
+/// another thread while responses are received.
+fn send_and_receive(
+    stream: &mut Connection,
+    first: Option<Chunk>,
+    mut chunks: RequestChunks,
+    generator: &dyn RequestGenerator,
+    config: &RequestConfig,
+    handler: &MessageHandler,
+    events: &EventSender,
+) -> Result<()> {
+    let reader = config.response_reader(stream)?;
+    let timeline = Timeline::default();
+    let profile = config.profile.as_deref();
+    let receiving = Receiving {
+        profile,
+        verify_checksums: config.verify_checksums,
+        discard: config.discard,
+        decode_pool: config.decode_pool.as_deref(),
+        pipe: config.pipe.as_deref(),
     };
-    make_requests(stream, msg, number)?;
+    if config.jitter.is_none()
+        && config.burst.is_none()
+        && config.rate.is_none()
+        && config.control.is_none()
+        && !config.repeat
+    {
+        let mut next = first;
+        let mut first_in_burst = true;
+        while let Some(chunk) = next {
+            for request in &chunk.requests {
+                timeline.record(request.responses, first_in_burst);
+                first_in_burst = false;
+            }
+            events.send(chunk.batch_sent());
+            make_requests(stream, &chunk.requests, events, profile)?;
+            drop(chunk);
+            next = chunks.next_chunk()?;
+        }
+        events.send(EventKind::RequestsSent);
+        return receive_responses(
+            reader, stream, handler, generator, &timeline, events, receiving,
+        );
+    }
 
-    receive_responses(stream, "block", sender)?;
+    let burst = config.burst.unwrap_or(usize::MAX).max(1);
+    let writer = Mutex::new(stream.try_clone()?);
+    let done = AtomicBool::new(false);
+    let (timeline, writer, done) = (&timeline, &writer, &done);
+    // Named after the connection's thread, so its log lines say which connection they are from.
+    let name = format!("{}-send", thread::current().name().unwrap_or("conn"));
+    // A stream of its own, apart from the connection thread's, for the jitter and the requests
+    // it draws.
+    let stream_id = (events.conn() as u64 + 1) << 32;
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name(name)
+            .spawn_scoped(scope, move || {
+                rng::seed_thread(stream_id);
+                // Reported rather than left to the scope, which would only see it once the target
+                // stopped sending.
+                let sent = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut due = Instant::now();
+                    let mut next = first;
+                    let mut round = 0;
+                    // Index of the request in its round.
+                    let mut i = 0;
+                    loop {
+                        let chunk = match next.take() {
+                            Some(chunk) => chunk,
+                            None => {
+                                let chunk = match chunks.next_chunk() {
+                                    Ok(chunk) => chunk,
+                                    Err(e) => {
+                                        events.send(EventKind::Error(e));
+                                        return;
+                                    }
+                                };
+                                match chunk {
+                                    Some(chunk) => chunk,
+                                    None if config.repeat && i > 0 => {
+                                        round += 1;
+                                        trace!(target: SEND_LOG, "Starting round {round}");
+                                        chunks = RequestChunks::new(generator, config);
+                                        i = 0;
+                                        continue;
+                                    }
+                                    None => break,
+                                }
+                            }
+                        };
+                        let mut batch = Some(chunk.batch_sent());
+                        for request in &chunk.requests {
+                            if done.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            if let Some(control) = &config.control {
+                                if !control.wait_until_running() {
+                                    return;
+                                }
+                            }
+                            let jitter = config.jitter.map_or(Duration::ZERO, |jitter| {
+                                rng::with_rng(|rng| jitter.mul_f64(rng.gen::<f64>()))
+                            });
+                            let pause = if i > 0 && i % burst == 0 {
+                                config.pause
+                            } else {
+                                Duration::ZERO
+                            };
+                            thread::sleep(pause + jitter);
+                            if let Some(rate) = &config.rate {
+                                // Falling behind a rate that went up must not turn into a burst.
+                                let interval = Duration::from_secs_f64(1.0 / rate.get());
+                                due = due.max(Instant::now() - interval) + interval;
+                                thread::sleep(due.saturating_duration_since(Instant::now()));
+                            }
+                            timeline.record(request.responses, i % burst == 0);
+                            if let Some(batch) = batch.take() {
+                                events.send(batch);
+                            }
+                            let result = make_requests(
+                                &mut LockedWriter(writer),
+                                slice::from_ref(request),
+                                events,
+                                profile,
+                            );
+                            if let Err(e) = result {
+                                events.send(EventKind::Error(e));
+                                return;
+                            }
+                            i += 1;
+                        }
+                    }
+                    events.send(EventKind::RequestsSent);
+                }));
+                if let Err(panic) = sent {
+                    events.send(EventKind::Error(anyhow!(
+                        "Sending requests panicked: {}",
+                        panic_message(panic.as_ref())
+                    )));
+                }
+            })?;
+        let received = receive_responses(
+            reader,
+            &mut LockedWriter(writer),
+            handler,
+            generator,
+            timeline,
+            events,
+            receiving,
+        );
+        done.store(true, Ordering::Relaxed);
+        received
+    })
+}
 
-    Ok(())
+/// When the request for each expected response was sent, in the order responses arrive, which
+/// is the order the target processes requests in. Entries are dropped once answered, so
+/// repeating requests for hours does not grow it.
+#[derive(Default)]
+struct Timeline {
+    requests: Mutex<Requests>,
 }
 
-pub fn request_compact_blocks(
-    stream: &mut TcpStream,
-    block_hash: BlockHash,
-    number: usize,
-    sender: &Sender<Option<Error>>,
-    magic: u32,
+#[derive(Default)]
+struct Requests {
+    /// Index of the first entry of `sent`.
+    first: usize,
+    sent: VecDeque<(Instant, bool)>,
+}
+
+impl Timeline {
+    /// Records a request for `responses` responses sent now.
+    fn record(&self, responses: usize, first_in_burst: bool) {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        requests
+            .sent
+            .extend((0..responses).map(|i| (now, first_in_burst && i == 0)));
+    }
+
+    /// The response event for the `index`th response, of `bytes` bytes, arriving now.
+    fn response(&self, index: usize, bytes: usize, block_hash: Option<BlockHash>) -> EventKind {
+        let mut requests = self.requests.lock().unwrap();
+        // Responses arrive in order, so earlier entries are done with, except the last one.
+        let answered = index
+            .saturating_sub(requests.first)
+            .min(requests.sent.len().saturating_sub(1));
+        requests.sent.drain(..answered);
+        requests.first += answered;
+        // A target answering more than we asked for is measured against our last request.
+        let (sent_at, first_in_burst) = requests
+            .sent
+            .get(index.saturating_sub(requests.first))
+            .or(requests.sent.back())
+            .copied()
+            .unwrap_or((Instant::now(), false));
+        EventKind::Response {
+            latency: sent_at.elapsed(),
+            first_in_burst,
+            bytes,
+            block_hash,
+        }
+    }
+}
+
+/// A connection whose reads time out once `deadline` passed, so a peer trickling its bytes
+/// cannot stretch an exchange past it one read at a time.
+struct DeadlineStream<'a> {
+    stream: &'a mut Connection,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Lets several threads write whole messages to one stream without interleaving them.
+struct LockedWriter<'a, W>(&'a Mutex<W>);
+
+impl<W: Write> Write for LockedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Serves the target the blocks it requests from `handler`'s block source, for stressing the
+/// target's block validation while it syncs from us.
+pub fn feed_blocks(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    events: &EventSender,
 ) -> Result<()> {
-    perform_handshake(stream, magic)?;
+    handshake(stream, handler, None, events)?;
 
-    let msg = RawNetworkMessage {
-        magic,
-        payload: NetworkMessage::GetData(vec![Inventory::CompactBlock(block_hash)]),
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    loop {
+        let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+        let served = handler.handle(stream, &message.payload)?;
+        for _ in 0..served {
+            if !events.send(EventKind::BlockServed) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Sends `block` unsolicited `config.number` times, or until the run ends with `config.repeat`,
+/// optionally limited to `rate` blocks per second. Each block is followed by a ping, so its pong
+/// reveals when the target finished processing the block.
+pub fn flood_blocks(
+    stream: &mut Connection,
+    block: &Block,
+    rate: Option<f64>,
+    config: &RequestConfig,
+    events: &EventSender,
+) -> Result<()> {
+    let handler = config.handler();
+    let peer = handshake(stream, &handler, config.handshake_timeout, events)?;
+    check_services(peer.services, config.required_services)?;
+
+    let sent_at = Arc::new(Mutex::new(HashMap::new()));
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let receiver = {
+        let reader = stream.try_clone()?;
+        let writer = writer.clone();
+        let sent_at = sent_at.clone();
+        let events = events.clone();
+        thread::spawn(move || receive_pongs(reader, &writer, &handler, &sent_at, &events))
     };
-    make_requests(stream, msg, number)?;
 
-    receive_responses(stream, "cmpctblock", sender)?;
+    let block_message = serialize(&RawNetworkMessage {
+        magic: config.magic,
+        payload: NetworkMessage::Block(block.clone()),
+    });
+    let interval = rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+    let start = Instant::now();
+    let blocks = if config.repeat {
+        u64::MAX
+    } else {
+        config.number as u64
+    };
+    let mut sent = 0;
+    for nonce in 0..blocks {
+        if let Some(control) = &config.control {
+            if !control.wait_until_running() {
+                break;
+            }
+        }
+        if let Some(interval) = interval {
+            let due = start + interval.mul_f64(nonce as f64);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        let ping = serialize(&RawNetworkMessage {
+            magic: config.magic,
+            payload: NetworkMessage::Ping(nonce),
+        });
+        let mut writer = writer.lock().unwrap();
+        let start = Instant::now();
+        sent_at.lock().unwrap().insert(nonce, start);
+        writer.write_all(&block_message)?;
+        writer.write_all(&ping)?;
+        events.send(EventKind::WriteBlocked(start.elapsed()));
+        sent += 1;
+    }
+    trace!(target: SEND_LOG, "Sent {sent} unsolicited blocks");
+    events.send(EventKind::RequestsSent);
 
-    Ok(())
+    receiver
+        .join()
+        .map_err(|_| anyhow!("Pong receiver panicked"))?
 }
 
-pub fn request_blocktxns(
-    stream: &mut TcpStream,
-    block_hash: BlockHash,
-    indexes: Vec<u64>,
-    number: usize,
-    sender: &Sender<Option<Error>>,
-    magic: u32,
+fn receive_pongs(
+    reader: Connection,
+    writer: &Mutex<Connection>,
+    handler: &MessageHandler,
+    sent_at: &Mutex<HashMap<u64, Instant>>,
+    events: &EventSender,
 ) -> Result<()> {
-    perform_handshake(stream, magic)?;
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, reader);
+    loop {
+        let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+        match message.payload {
+            NetworkMessage::Pong(nonce) => {
+                let Some(sent_at) = sent_at.lock().unwrap().remove(&nonce) else {
+                    continue;
+                };
+                let response = EventKind::Response {
+                    latency: sent_at.elapsed(),
+                    first_in_burst: false,
+                    bytes: MESSAGE_HEADER_SIZE + size_of::<u64>(),
+                    block_hash: None,
+                };
+                if !events.send(response) {
+                    return Ok(());
+                }
+            }
+            payload => {
+                handler.handle(&mut *writer.lock().unwrap(), &payload)?;
+            }
+        }
+    }
+}
 
-    let msg = RawNetworkMessage {
-        magic,
-        payload: NetworkMessage::GetBlockTxn(GetBlockTxn {
-            txs_request: BlockTransactionsRequest {
-                block_hash,
-                indexes,
-            },
-        }),
+/// Service flags by the names nodes log them under.
+const SERVICE_NAMES: [(ServiceFlags, &str); 6] = [
+    (ServiceFlags::NETWORK, "NETWORK"),
+    (ServiceFlags::GETUTXO, "GETUTXO"),
+    (ServiceFlags::BLOOM, "BLOOM"),
+    (ServiceFlags::WITNESS, "WITNESS"),
+    (ServiceFlags::COMPACT_FILTERS, "COMPACT_FILTERS"),
+    (ServiceFlags::NETWORK_LIMITED, "NETWORK_LIMITED"),
+];
+
+/// Fails if a peer advertising `services` cannot serve requests needing `required`, naming what
+/// is missing. `NETWORK_LIMITED`, i.e. serving recent blocks, is met by `NETWORK` as well.
+pub fn check_services(services: ServiceFlags, required: ServiceFlags) -> Result<()> {
+    let mut services = services;
+    if services.has(ServiceFlags::NETWORK) {
+        services.add(ServiceFlags::NETWORK_LIMITED);
+    }
+    let missing: Vec<_> = SERVICE_NAMES
+        .iter()
+        .filter(|(flag, _)| required.has(*flag) && !services.has(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Target does not advertise {}, so it would not answer the requests",
+        missing.join(", ")
+    ))
+}
+
+/// The message a panic was raised with, from the payload [`std::panic::catch_unwind`] returns.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+/// Parses a comma separated list of service names, e.g. `witness,network`, as nodes log them but
+/// in any case and with `-` for `_`.
+pub fn parse_services(s: &str) -> Result<ServiceFlags> {
+    s.split(',').try_fold(ServiceFlags::NONE, |services, name| {
+        let normalized = name.trim().to_uppercase().replace('-', "_");
+        SERVICE_NAMES
+            .iter()
+            .find(|(_, known)| *known == normalized)
+            .map(|(flag, _)| services | *flag)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid service {name}, expected one of {}",
+                    SERVICE_NAMES
+                        .iter()
+                        .map(|(_, known)| known.to_lowercase().replace('_', "-"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    })
+}
+
+/// Handshakes over a run's connection, giving up once the whole handshake took `timeout`, and
+/// reports its completion.
+fn handshake(
+    stream: &mut Connection,
+    handler: &MessageHandler,
+    timeout: Option<Duration>,
+    events: &EventSender,
+) -> Result<VersionMessage> {
+    let (peer, stages) = match timeout {
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            staged_handshake(&mut DeadlineStream { stream, deadline }, handler)?
+        }
+        None => staged_handshake(stream, handler)?,
     };
-    make_requests(stream, msg, number)?;
+    stream.set_read_timeout(None)?;
+    events.send(EventKind::HandshakeComplete(
+        PeerVersion::from(&peer),
+        stages,
+    ));
+    Ok(peer)
+}
 
-    receive_responses(stream, "blocktxn", sender)?;
+/// Exchanges version and verack messages with the peer, returning the peer's version message.
+pub(crate) fn perform_handshake<S: Read + Write>(
+    stream: &mut S,
+    handler: &MessageHandler,
+) -> Result<VersionMessage> {
+    staged_handshake(stream, handler).map(|(peer, _)| peer)
+}
 
-    Ok(())
+/// The handshake stage the peer failed to complete, for `e` raised while waiting for it.
+fn stalled_stage(e: consensus::encode::Error, version: bool, elapsed: Duration) -> Error {
+    let stage = if version {
+        "version received but no verack"
+    } else {
+        "no version received"
+    };
+    match e {
+        consensus::encode::Error::Io(e)
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+        {
+            anyhow!("Handshake timed out after {elapsed:.2?}: {stage}")
+        }
+        consensus::encode::Error::Io(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+            ) =>
+        {
+            anyhow!("Target disconnected during the handshake after {elapsed:.2?}: {stage}")
+        }
+        e => anyhow!("Invalid message during the handshake ({stage}): {e}"),
+    }
 }
 
-fn perform_handshake(stream: &mut TcpStream, magic: u32) -> Result<()> {
-    let version_message = build_version_message()?;
+/// Like [`perform_handshake`], also timing its stages, and failing with the stage the peer
+/// stalled at.
+pub(crate) fn staged_handshake<S: Read + Write>(
+    stream: &mut S,
+    handler: &MessageHandler,
+) -> Result<(VersionMessage, HandshakeStages)> {
+    let magic = handler.magic();
+    let emulation = handler.emulation();
+    let version = handler.version().build()?;
+    let _sent = SentNonce::new(version.nonce);
     let message = RawNetworkMessage {
         magic,
-        payload: NetworkMessage::Version(version_message),
+        payload: NetworkMessage::Version(version),
     };
-    let _ = stream.write(&serialize(&message))?;
-    trace!("Sent version message");
-    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    stream.write_all(&serialize(&message))?;
+    trace!(target: HANDSHAKE_LOG, "Sent version message");
+    if handler.addrv2() {
+        // BIP155 only honors sendaddrv2 sent before verack.
+        let message = RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::SendAddrV2,
+        };
+        stream.write_all(&serialize(&message))?;
+        trace!(target: HANDSHAKE_LOG, "Sent sendaddrv2 message");
+    }
+    let start = Instant::now();
+    let mut stages = HandshakeStages::default();
+    let mut peer = None;
     loop {
-        let reply = RawNetworkMessage::consensus_decode(&mut reader)?;
+        // Read unbuffered so nothing the peer sends after verack is consumed here.
+        let reply = RawNetworkMessage::consensus_decode(stream)
+            .map_err(|e| stalled_stage(e, peer.is_some(), start.elapsed()))?;
         match reply.payload {
-            NetworkMessage::Version(_) => {
-                trace!("Received version message");
-                let message = RawNetworkMessage {
-                    magic,
-                    payload: NetworkMessage::Verack,
-                };
-                let _ = stream.write(&serialize(&message))?;
-                trace!("Sent verack message");
+            NetworkMessage::Version(version) => {
+                stages.version = start.elapsed();
+                trace!(target: HANDSHAKE_LOG, "Received version message");
+                if is_own_nonce(version.nonce) {
+                    return Err(anyhow!(
+                        "Connected to ourselves: the target sent back our own version nonce, \
+                         e.g. through a proxy or NAT loop"
+                    ));
+                }
+                let mut replies = emulation
+                    .map(|emulation| emulation.before_verack(version.version))
+                    .unwrap_or_default();
+                replies.push(NetworkMessage::Verack);
+                peer = Some(version);
+                for payload in replies {
+                    let command = payload.cmd();
+                    stream.write_all(&serialize(&RawNetworkMessage { magic, payload }))?;
+                    trace!(target: HANDSHAKE_LOG, "Sent {command} message");
+                }
             }
             NetworkMessage::Verack => {
-                trace!("Received verack message");
+                stages.verack = start.elapsed();
+                trace!(target: HANDSHAKE_LOG, "Received verack message");
+                for payload in emulation.map(Emulation::after_verack).unwrap_or_default() {
+                    let command = payload.cmd();
+                    stream.write_all(&serialize(&RawNetworkMessage { magic, payload }))?;
+                    trace!(target: HANDSHAKE_LOG, "Sent {command} message");
+                }
                 break;
             }
-            _ => {
-                trace!("Received message {:?}", reply.payload);
+            payload if peer.is_none() => {
+                return Err(anyhow!(
+                    "Handshake failed: target sent {} before its version message",
+                    payload.cmd()
+                ));
+            }
+            payload => {
+                handler.handle(stream, &payload)?;
             }
         }
     }
-    trace!("Handshake complete");
-    Ok(())
+    trace!(
+        target: HANDSHAKE_LOG,
+        "Handshake complete: version after {:.2?}, verack after {:.2?}",
+        stages.version,
+        stages.verack
+    );
+    let peer = peer.ok_or_else(|| anyhow!("Peer sent verack before its version message"))?;
+    Ok((peer, stages))
 }
 
-fn build_version_message() -> Result<VersionMessage> {
-    let empty_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+fn make_requests<W: Write>(
+    writer: &mut W,
+    requests: &[Request],
+    events: &EventSender,
+    profile: Option<&Profile>,
+) -> Result<()> {
+    let size: usize = requests.iter().map(|request| request.bytes.len()).sum();
+    let start = Instant::now();
+    // Batches small requests into large writes without copying all of them at once.
+    let mut writer = BufWriter::with_capacity(size.min(WRITE_CHUNK_SIZE), writer);
+    for request in requests {
+        writer.write_all(&request.bytes)?;
+    }
+    writer.flush()?;
+    let blocked = start.elapsed();
+    events.send(EventKind::WriteBlocked(blocked));
+    if let Some(profile) = profile {
+        profile.add(Phase::Write, blocked);
+    }
 
-    let services = ServiceFlags::WITNESS;
-    let addr_recv = Address::new(&empty_address, services);
-    let addr_from = Address::new(&empty_address, services);
-    let nonce: u64 = secp256k1::rand::thread_rng().gen();
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    trace!(target: SEND_LOG, "Sent {} msgs", requests.len());
 
-    let msg = VersionMessage::new(
-        services,
-        timestamp.try_into().unwrap(),
-        addr_recv,
-        addr_from,
-        nonce,
-        String::from("/BlockSpammer:1.0/"),
-        0,
-    );
-    Ok(msg)
+    Ok(())
 }
 
-fn make_requests<W: Write>(writer: &mut W, msg: RawNetworkMessage, number: usize) -> Result<()> {
-    let mut msgs = Vec::with_capacity(number);
-    for _ in 0..number {
-        msgs.push(serialize(&msg.clone()));
+/// Reads the command and payload length from a message header, failing on a length over the
+/// size limit before anything is allocated for the payload.
+pub fn parse_header(header: &[u8; MESSAGE_HEADER_SIZE]) -> Result<(CommandString, usize)> {
+    let command: CommandString = deserialize(&header[4..16])?;
+    let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+    if len > MAX_MSG_SIZE {
+        return Err(anyhow!(
+            "Target sent a {len} byte message, over the size limit"
+        ));
     }
-    writer.write(&msgs.into_iter().flatten().collect::<Vec<_>>())?;
+    Ok((command, len))
+}
 
-    trace!("Sent {number} msgs");
+/// Fails if a message header is not for the network of `magic`.
+pub(crate) fn check_magic(header: &[u8; MESSAGE_HEADER_SIZE], magic: u32) -> Result<()> {
+    let sent = u32::from_le_bytes(header[..4].try_into().unwrap());
+    if sent != magic {
+        return Err(anyhow!(
+            "Target sent a message with magic {sent:08x}, expected {magic:08x}"
+        ));
+    }
+    Ok(())
+}
 
+/// Answers a message from the target that is not a response, reporting any relay preference it
+/// states.
+pub(crate) fn answer<W: Write>(
+    writer: &mut W,
+    handler: &MessageHandler,
+    message: &NetworkMessage,
+    events: &EventSender,
+) -> Result<()> {
+    handler.handle(writer, message)?;
+    if RelayPreferences::is_preference(message) {
+        events.send(EventKind::RelayPreferences(handler.relay_preferences()));
+    }
     Ok(())
 }
 
-fn receive_responses<R: Read>(
+/// How [`receive_responses`] treats the messages it reads, beyond counting responses.
+#[derive(Clone, Copy, Default)]
+struct Receiving<'a> {
+    profile: Option<&'a Profile>,
+    verify_checksums: bool,
+    discard: bool,
+    decode_pool: Option<&'a DecodePool>,
+    pipe: Option<&'a BlockPipe>,
+}
+
+fn receive_responses<R: Read, W: Write>(
     reader: R,
-    command: &str,
-    sender: &Sender<Option<Error>>,
+    writer: &mut W,
+    handler: &MessageHandler,
+    generator: &dyn RequestGenerator,
+    timeline: &Timeline,
+    events: &EventSender,
+    receiving: Receiving,
 ) -> Result<()> {
-    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, reader);
+    if receiving.discard {
+        return discard_responses(reader, writer, handler, generator, timeline, events);
+    }
+    let mut frames = Frames::new(BufReader::with_capacity(
+        MAX_MSG_SIZE,
+        TimedReader::new(reader),
+    ));
 
+    let mut received = 0;
     loop {
-        let _: u32 = Decodable::consensus_decode_from_finite_reader(&mut reader)?;
-        let cmd = CommandString::consensus_decode_from_finite_reader(&mut reader)?;
-        let _ = CheckedData::consensus_decode_from_finite_reader(&mut reader)?;
-        if cmd.to_string() == command {
-            trace!("Received {command} msg");
-            let Ok(_) = sender.send(None) else { break; };
-        } else if (command == "cmpctblock" || command == "blocktxn") && cmd.to_string() == "block" {
-            return Err(anyhow!("Received block response instead of expected {command}. Requested block is too deep in the chain. Try with a block that is <10 blocks deep from chain tip."));
+        let start = Instant::now();
+        let read_before = frames.get_ref().get_ref().elapsed();
+        let frame = frames
+            .next()
+            .unwrap_or_else(|| Err(anyhow!("Target closed the connection")))?;
+        let command = frame.command.to_string();
+        if receiving.verify_checksums && !frame.checksum_ok() {
+            trace!(target: RECEIVE_LOG, "Received {command} msg with a bad checksum");
+            if generator.is_response_command(&command) {
+                if !events.send(EventKind::BadChecksum) {
+                    break;
+                }
+                // Still takes its request's place, so later responses keep their latencies.
+                received += 1;
+            }
+        } else if match receiving.decode_pool {
+            Some(_) => generator.is_response_command(&command),
+            None => generator.is_response(&command, &frame.payload)?,
+        } {
+            trace!(target: RECEIVE_LOG, "Received {command} msg");
+            let bytes = frame.size();
+            let block_hash = frame.block_hash();
+            if let Some(pipe) = receiving.pipe.filter(|_| command == "block") {
+                pipe.forward(&frame.payload)?;
+            }
+            if let Some(pool) = receiving.decode_pool {
+                pool.submit(command, frame.payload)?;
+            }
+            if !events.send(timeline.response(received, bytes, block_hash)) {
+                break;
+            }
+            received += 1;
+        } else {
+            // Only messages we don't count are fully decoded, checksum included, so the target's
+            // own requests can be answered.
+            answer(writer, handler, &frame.decode()?.payload, events)?;
+        }
+        if let Some(profile) = receiving.profile {
+            let read = frames.get_ref().get_ref().elapsed() - read_before;
+            profile.add(Phase::Read, read);
+            profile.add(Phase::Decode, start.elapsed().saturating_sub(read));
         }
     }
 
-    trace!("Finished receiving");
+    trace!(target: RECEIVE_LOG, "Finished receiving");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{
+        sent_messages, Fault, MockStream, BLOCK_RESPONSES, BLOCK_RESPONSES_PING_NONCE, HANDSHAKE,
+    };
+    use crate::generator::{Miss, MissRequests};
+    use crate::version::VersionBuilder;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::Network;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    fn handler() -> MessageHandler {
+        MessageHandler::new(Network::Bitcoin.magic(), None)
+    }
+
+    /// A timeline of `responses` requested just now, in a single getdata.
+    fn timeline(responses: usize) -> Timeline {
+        let timeline = Timeline::default();
+        timeline.record(responses, true);
+        timeline
+    }
+
+    #[test]
+    fn handshake_sends_version_then_verack() {
+        let mut stream = MockStream::new(HANDSHAKE);
+        perform_handshake(&mut stream, &handler()).unwrap();
+
+        let sent = sent_messages(&stream.output);
+        assert_eq!(sent.len(), 2);
+        assert!(matches!(sent[0], NetworkMessage::Version(_)));
+        assert_eq!(sent[1], NetworkMessage::Verack);
+    }
+
+    #[test]
+    fn addrv2_handshake_announces_it_before_verack() {
+        let mut stream = MockStream::new(HANDSHAKE);
+        perform_handshake(&mut stream, &handler().with_addrv2()).unwrap();
+
+        let sent = sent_messages(&stream.output);
+        assert_eq!(
+            sent[1..],
+            [NetworkMessage::SendAddrV2, NetworkMessage::Verack]
+        );
+    }
+
+    #[test]
+    fn emulated_handshake_follows_the_implementation() {
+        let mut stream = MockStream::new(HANDSHAKE);
+        let handler = handler().with_emulation("core".parse().unwrap());
+        perform_handshake(&mut stream, &handler).unwrap();
+
+        let sent = sent_messages(&stream.output);
+        let NetworkMessage::Version(version) = &sent[0] else {
+            panic!("Did not open with a version message");
+        };
+        assert_eq!(
+            (version.user_agent.as_str(), version.version),
+            ("/Satoshi:25.0.0/", 70016)
+        );
+        let commands: Vec<_> = sent[1..].iter().map(NetworkMessage::cmd).collect();
+        assert_eq!(
+            commands,
+            [
+                "wtxidrelay",
+                "sendaddrv2",
+                "verack",
+                "sendheaders",
+                "sendcmpct"
+            ]
+        );
+        assert!("lnd".parse::<Emulation>().is_err());
+    }
+
+    #[test]
+    fn handshake_opens_with_the_handler_version() {
+        let mut stream = MockStream::new(HANDSHAKE);
+        let version = VersionBuilder::default()
+            .with_user_agent("/Satoshi:26.0.0/")
+            .with_relay(true)
+            .with_nonce(7);
+        perform_handshake(&mut stream, &handler().with_version(version)).unwrap();
+
+        let NetworkMessage::Version(sent) = &sent_messages(&stream.output)[0] else {
+            panic!("Did not open with a version message");
+        };
+        assert_eq!(
+            (sent.user_agent.as_str(), sent.relay, sent.nonce),
+            ("/Satoshi:26.0.0/", true, 7)
+        );
+    }
+
+    #[test]
+    fn handshake_failures_name_the_stage_that_stalled() {
+        let messages = |payloads: Vec<NetworkMessage>| -> String {
+            payloads
+                .into_iter()
+                .flat_map(|payload| {
+                    serialize(&RawNetworkMessage {
+                        magic: Network::Bitcoin.magic(),
+                        payload,
+                    })
+                })
+                .collect::<Vec<u8>>()
+                .to_hex()
+        };
+        let failure = |fixture: &str| {
+            let mut stream = MockStream::new(fixture);
+            perform_handshake(&mut stream, &handler())
+                .unwrap_err()
+                .to_string()
+        };
+        let version = VersionBuilder::default()
+            .with_nonce(0x7e57)
+            .build()
+            .unwrap();
+
+        assert!(failure("").ends_with("no version received"));
+        let no_verack = failure(&messages(vec![NetworkMessage::Version(version)]));
+        assert!(no_verack.starts_with("Target disconnected during the handshake"));
+        assert!(no_verack.ends_with("version received but no verack"));
+        assert!(failure(&messages(vec![NetworkMessage::Ping(1)])).contains("sent ping before"));
+
+        let mut stream = MockStream::new(HANDSHAKE);
+        let (_, stages) = staged_handshake(&mut stream, &handler()).unwrap();
+        assert!(stages.version <= stages.verack);
+    }
+
+    #[test]
+    fn handshake_timeout_bounds_the_whole_handshake() {
+        use bitcoin::hashes::hex::FromHex;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Each byte arrives well within the timeout, the whole handshake well past it.
+            for byte in Vec::<u8>::from_hex(HANDSHAKE).unwrap() {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let mut stream = Connection::Tcp(std::net::TcpStream::connect(address).unwrap());
+        let (tx, _rx) = channel();
+        let err = handshake(
+            &mut stream,
+            &handler(),
+            Some(Duration::from_millis(200)),
+            &EventSender::new(0, tx),
+        )
+        .unwrap_err();
+        drop(stream);
+        peer.join().unwrap();
+        assert!(err.to_string().starts_with("Handshake timed out"), "{err}");
+    }
+
+    #[test]
+    fn handshake_with_ourselves_is_an_error() {
+        let version = VersionBuilder::default().with_nonce(0x5e1f);
+        let echo: Vec<u8> = [
+            NetworkMessage::Version(version.build().unwrap()),
+            NetworkMessage::Verack,
+        ]
+        .into_iter()
+        .flat_map(|payload| {
+            serialize(&RawNetworkMessage {
+                magic: Network::Bitcoin.magic(),
+                payload,
+            })
+        })
+        .collect();
+        let mut stream = MockStream::new(&echo.to_hex());
+        let err = perform_handshake(&mut stream, &handler().with_version(version)).unwrap_err();
+        assert!(err.to_string().contains("Connected to ourselves"));
+    }
+
+    fn request_config() -> RequestConfig {
+        use bitcoin::hashes::Hash;
+        RequestConfig {
+            magic: Network::Bitcoin.magic(),
+            block_hashes: vec![BlockHash::all_zeros()],
+            txids: Vec::new(),
+            number: 1,
+            batch: 1,
+            block_source: None,
+            read_rate: None,
+            jitter: None,
+            burst: None,
+            pause: Duration::ZERO,
+            rate: None,
+            control: None,
+            profile: None,
+            verify_checksums: false,
+            discard: false,
+            decode_pool: None,
+            memory: None,
+            pipe: None,
+            version: None,
+            emulation: None,
+            handshake_timeout: None,
+            required_services: ServiceFlags::NONE,
+            recent_blocks: false,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn services_are_checked_against_the_requests() {
+        let witness_blocks = InventoryRequests::new(vec![InventoryType::WitnessBlock]);
+        let required = witness_blocks.required_services(&request_config());
+        assert!(check_services(ServiceFlags::NETWORK | ServiceFlags::WITNESS, required).is_ok());
+        assert!(check_services(
+            ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS,
+            required
+        )
+        .is_err());
+        let required = witness_blocks.required_services(&RequestConfig {
+            recent_blocks: true,
+            ..request_config()
+        });
+        assert!(check_services(ServiceFlags::NETWORK | ServiceFlags::WITNESS, required).is_ok());
+        assert!(check_services(
+            ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS,
+            required
+        )
+        .is_ok());
+        let err = check_services(ServiceFlags::BLOOM, required).unwrap_err();
+        assert!(err.to_string().contains("WITNESS, NETWORK_LIMITED"));
+        assert_eq!(
+            parse_services("Witness,compact-filters").unwrap(),
+            ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS
+        );
+        assert!(parse_services("witness,foo").is_err());
+    }
+
+    #[test]
+    fn handshake_leaves_post_verack_messages_unread() {
+        let mut stream = MockStream::new(HANDSHAKE);
+        perform_handshake(&mut stream, &handler()).unwrap();
+
+        let next = RawNetworkMessage::consensus_decode(&mut stream).unwrap();
+        assert_eq!(next.cmd(), "sendcmpct");
+    }
+
+    #[test]
+    fn relay_preferences_are_reported_as_they_arrive() {
+        let handler = handler();
+        let mut stream = MockStream::new(HANDSHAKE);
+        perform_handshake(&mut stream, &handler).unwrap();
+        let (tx, rx) = channel();
+        let result = receive_responses(
+            stream,
+            &mut Vec::new(),
+            &handler,
+            &InventoryRequests::new(vec![InventoryType::Block]),
+            &timeline(1),
+            &EventSender::new(0, tx),
+            Receiving::default(),
+        );
+        assert!(result.is_err());
+
+        let reported: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::RelayPreferences(preferences) => Some(preferences),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reported.len(), 2);
+        assert_eq!(
+            reported[1].to_string(),
+            "sendcmpct low-bandwidth v2, feefilter 1000 sat/kvB, wtxidrelay"
+        );
+    }
+
+    #[test]
+    fn requests_are_generated_a_chunk_at_a_time_under_the_memory_budget() {
+        use crate::memory::MemoryBudget;
+
+        let memory = Arc::new(MemoryBudget::new(Some(REQUEST_CHUNK_SIZE)));
+        let config = RequestConfig {
+            number: 5 * REQUEST_CHUNK + 1,
+            batch: 16,
+            memory: Some(memory.clone()),
+            ..request_config()
+        };
+        let generator = InventoryRequests::new(vec![InventoryType::WitnessBlock]);
+        let mut chunks = RequestChunks::new(&generator, &config);
+        let (mut entries, mut count) = (0, 0);
+        while let Some(chunk) = chunks.next_chunk().unwrap() {
+            assert!(memory.peak() <= REQUEST_CHUNK_SIZE);
+            entries += chunk
+                .requests
+                .iter()
+                .map(|request| request.responses)
+                .sum::<usize>();
+            count += 1;
+        }
+        assert_eq!(entries, config.number);
+        assert_eq!(count, 6);
+    }
+
+    #[test]
+    fn receive_responses_counts_blocks_and_answers_pings() {
+        let mut writer = Vec::new();
+        let (tx, rx) = channel();
+        let events = EventSender::new(0, tx);
+
+        // The fixture ends without the connection closing, which surfaces as an EOF error.
+        let result = receive_responses(
+            MockStream::new(BLOCK_RESPONSES),
+            &mut writer,
+            &handler(),
+            &InventoryRequests::new(vec![InventoryType::Block]),
+            &timeline(2),
+            &events,
+            Receiving::default(),
+        );
+        assert!(result.is_err());
+
+        let responses = rx
+            .try_iter()
+            .filter(|event| matches!(event.kind, EventKind::Response { .. }))
+            .count();
+        assert_eq!(responses, 2);
+        assert_eq!(
+            sent_messages(&writer),
+            vec![NetworkMessage::Pong(BLOCK_RESPONSES_PING_NONCE)]
+        );
+    }
+
+    #[test]
+    fn receive_responses_rejects_blocks_when_expecting_compact_blocks() {
+        let mut writer = Vec::new();
+        let (tx, _rx) = channel();
+        let events = EventSender::new(0, tx);
+
+        let err = receive_responses(
+            MockStream::new(BLOCK_RESPONSES),
+            &mut writer,
+            &handler(),
+            &InventoryRequests::new(vec![InventoryType::CompactBlock]),
+            &timeline(1),
+            &events,
+            Receiving::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("too deep"));
+    }
+
+    #[test]
+    fn missed_lookups_are_counted_by_their_notfound_or_pong() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::network::message_blockdata::Inventory;
+
+        let txs = MissRequests::new(Miss::Tx);
+        let blocks = MissRequests::new(Miss::Block);
+        let getdata = NetworkMessage::GetData(vec![Inventory::Transaction(Txid::all_zeros()); 4]);
+        assert_eq!(txs.expected_responses(&getdata), 1);
+        assert_eq!(blocks.expected_responses(&getdata), 0);
+        assert_eq!(blocks.expected_responses(&NetworkMessage::Ping(0)), 1);
+        // A served request type hits an error on notfound rather than waiting on it forever.
+        assert!(InventoryRequests::new(vec![InventoryType::Tx])
+            .is_response("notfound", &[])
+            .is_err());
+
+        let magic = Network::Bitcoin.magic();
+        let fixture: Vec<u8> = [
+            NetworkMessage::NotFound(vec![Inventory::Transaction(Txid::all_zeros())]),
+            NetworkMessage::Ping(7),
+            NetworkMessage::NotFound(vec![Inventory::Transaction(Txid::all_zeros())]),
+        ]
+        .into_iter()
+        .flat_map(|payload| serialize(&RawNetworkMessage { magic, payload }))
+        .collect();
+        let mut writer = Vec::new();
+        let (tx, rx) = channel();
+        let result = receive_responses(
+            MockStream::new(&fixture.to_hex()),
+            &mut writer,
+            &handler(),
+            &txs,
+            &timeline(2),
+            &EventSender::new(0, tx),
+            Receiving::default(),
+        );
+        assert!(result.is_err());
+        let responses = rx
+            .try_iter()
+            .filter(|event| matches!(event.kind, EventKind::Response { .. }))
+            .count();
+        assert_eq!(responses, 2);
+        assert_eq!(sent_messages(&writer), vec![NetworkMessage::Pong(7)]);
+    }
+
+    /// Receives `block` responses from a peer replaying [`BLOCK_RESPONSES`] with `faults`,
+    /// returning the outcome, the latency of each counted response and what was sent back.
+    fn receive_with_faults(faults: &[Fault]) -> (Result<()>, Vec<Duration>, Vec<NetworkMessage>) {
+        let mut writer = Vec::new();
+        let (tx, rx) = channel();
+        let events = EventSender::new(0, tx);
+        let result = receive_responses(
+            MockStream::with_faults(BLOCK_RESPONSES, faults),
+            &mut writer,
+            &handler(),
+            &InventoryRequests::new(vec![InventoryType::Block]),
+            &timeline(2),
+            &events,
+            Receiving::default(),
+        );
+        let latencies = rx
+            .try_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::Response { latency, .. } => Some(latency),
+                _ => None,
+            })
+            .collect();
+        (result, latencies, sent_messages(&writer))
+    }
+
+    #[test]
+    fn delayed_response_is_reflected_in_latency() {
+        let delay = Duration::from_millis(50);
+        let (_, latencies, _) = receive_with_faults(&[Fault::Delay(2, delay)]);
+        assert_eq!(latencies.len(), 2);
+        assert!(latencies[0] < delay);
+        assert!(latencies[1] >= delay);
+    }
+
+    #[test]
+    fn dropped_response_is_not_counted() {
+        let (result, latencies, sent) = receive_with_faults(&[Fault::Drop(0)]);
+        assert!(result.is_err());
+        assert_eq!(latencies.len(), 1);
+        assert_eq!(sent, vec![NetworkMessage::Pong(BLOCK_RESPONSES_PING_NONCE)]);
+    }
+
+    #[test]
+    fn wrong_command_response_is_not_counted() {
+        let (_, latencies, _) = receive_with_faults(&[Fault::WrongCommand(0, "blocc")]);
+        assert_eq!(latencies.len(), 1);
+    }
+
+    #[test]
+    fn wrong_command_with_undecodable_payload_is_an_error() {
+        let (result, latencies, sent) = receive_with_faults(&[Fault::WrongCommand(1, "headers")]);
+        assert!(result.is_err());
+        assert_eq!(latencies.len(), 1);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn disconnect_mid_message_is_an_error() {
+        let (result, latencies, sent) = receive_with_faults(&[Fault::Disconnect(1)]);
+        assert!(result.is_err());
+        assert_eq!(latencies.len(), 1);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn garbage_frame_is_an_error() {
+        let (result, latencies, _) = receive_with_faults(&[Fault::Garbage(1)]);
+        assert!(result.is_err());
+        assert_eq!(latencies.len(), 1);
+    }
+
+    #[test]
+    fn handshake_fails_when_peer_disconnects_before_verack() {
+        let mut stream = MockStream::with_faults(HANDSHAKE, &[Fault::Disconnect(3)]);
+        assert!(perform_handshake(&mut stream, &handler()).is_err());
+    }
+
+    #[test]
+    fn handshake_fails_on_garbage_frame() {
+        let mut stream = MockStream::with_faults(HANDSHAKE, &[Fault::Garbage(0)]);
+        assert!(perform_handshake(&mut stream, &handler()).is_err());
+    }
+
+    #[test]
+    fn receive_responses_reports_bad_checksums_only_when_verifying() {
+        for verify_checksums in [false, true] {
+            let mut writer = Vec::new();
+            let (tx, rx) = channel();
+            let events = EventSender::new(0, tx);
+            let result = receive_responses(
+                MockStream::with_faults(BLOCK_RESPONSES, &[Fault::BadChecksum(0)]),
+                &mut writer,
+                &handler(),
+                &InventoryRequests::new(vec![InventoryType::Block]),
+                &timeline(2),
+                &events,
+                Receiving {
+                    verify_checksums,
+                    ..Receiving::default()
+                },
+            );
+            assert!(result.is_err());
+
+            let events: Vec<_> = rx.try_iter().collect();
+            let count = |bad: bool| {
+                events
+                    .iter()
+                    .filter(|event| match event.kind {
+                        EventKind::BadChecksum => bad,
+                        EventKind::Response { .. } => !bad,
+                        _ => false,
+                    })
+                    .count()
+            };
+            let expected_bad = usize::from(verify_checksums);
+            assert_eq!(count(true), expected_bad);
+            assert_eq!(count(false), 2 - expected_bad);
+            assert_eq!(
+                sent_messages(&writer),
+                vec![NetworkMessage::Pong(BLOCK_RESPONSES_PING_NONCE)]
+            );
+        }
+    }
+
+    #[test]
+    fn discarding_receiver_counts_blocks_and_answers_pings() {
+        let mut writer = Vec::new();
+        let (tx, rx) = channel();
+        let events = EventSender::new(0, tx);
+
+        // The delay splits the input into two reads at the ping.
+        let stream = MockStream::with_faults(
+            BLOCK_RESPONSES,
+            &[Fault::Delay(1, Duration::from_millis(1))],
+        );
+        let result = receive_responses(
+            stream,
+            &mut writer,
+            &handler(),
+            &InventoryRequests::new(vec![InventoryType::Block]),
+            &timeline(2),
+            &events,
+            Receiving {
+                discard: true,
+                ..Receiving::default()
+            },
+        );
+        assert!(result.is_err());
+
+        let bytes: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::Response { bytes, .. } => Some(bytes),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bytes, vec![309, 309]);
+        assert_eq!(
+            sent_messages(&writer),
+            vec![NetworkMessage::Pong(BLOCK_RESPONSES_PING_NONCE)]
+        );
+    }
+
+    #[test]
+    fn discarding_receiver_fails_on_a_block_answering_a_compact_block_request() {
+        let (tx, rx) = channel();
+        let result = receive_responses(
+            MockStream::new(BLOCK_RESPONSES),
+            &mut Vec::new(),
+            &handler(),
+            &InventoryRequests::new(vec![InventoryType::CompactBlock]),
+            &timeline(2),
+            &EventSender::new(0, tx),
+            Receiving {
+                discard: true,
+                ..Receiving::default()
+            },
+        );
+        assert!(result.unwrap_err().to_string().contains("too deep"));
+        assert!(!rx
+            .try_iter()
+            .any(|event| matches!(event.kind, EventKind::Response { .. })));
+    }
+}
@@ -0,0 +1,307 @@
+use crate::client::P2pClient;
+use crate::filters::{download_filters, fetch_blocks_by_hash, matching_blocks, BASIC_FILTER};
+use crate::headers::MAX_HEADERS_RESULTS;
+use crate::rng::with_rng;
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::PROTOCOL_VERSION;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::GetHeadersMessage;
+use bitcoin::network::message_filter::GetCFHeaders;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::{BlockHash, BlockHeader, FilterHash, FilterHeader, Script, WPubkeyHash};
+use log::trace;
+use std::io::{Read, Write};
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+/// The most filter headers a peer serves for a single getcfheaders, per BIP157.
+pub const MAX_GETCFHEADERS_SIZE: usize = 2000;
+
+/// Blocks whose filters a client without a birthday downloads: about two weeks' worth.
+pub const DEFAULT_FILTER_BLOCKS: usize = 2016;
+
+/// Size of a header in a headers message: the header and an empty transaction count.
+const HEADERS_ENTRY_SIZE: usize = 81;
+
+/// What one step of the light client flow cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepCost {
+    /// Items received: headers, filter headers, filters or blocks.
+    pub items: usize,
+    /// Payload bytes those items took.
+    pub bytes: usize,
+    pub time: Duration,
+}
+
+impl AddAssign for StepCost {
+    fn add_assign(&mut self, other: Self) {
+        self.items += other.items;
+        self.bytes += other.bytes;
+        self.time += other.time;
+    }
+}
+
+/// What a light client's sync cost the target, step by step.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LightClientReport {
+    pub headers: StepCost,
+    pub filter_headers: StepCost,
+    pub filters: StepCost,
+    /// Blocks whose filter matched one of the wallet's scripts, fetched in full.
+    pub blocks: StepCost,
+}
+
+impl LightClientReport {
+    /// The steps as (name, cost), in the order the client takes them.
+    pub fn steps(&self) -> [(&'static str, StepCost); 4] {
+        [
+            ("headers", self.headers),
+            ("filter headers", self.filter_headers),
+            ("filters", self.filters),
+            ("blocks", self.blocks),
+        ]
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.steps().iter().map(|(_, cost)| cost.time).sum()
+    }
+}
+
+impl AddAssign<&LightClientReport> for LightClientReport {
+    fn add_assign(&mut self, other: &LightClientReport) {
+        self.headers += other.headers;
+        self.filter_headers += other.filter_headers;
+        self.filters += other.filters;
+        self.blocks += other.blocks;
+    }
+}
+
+/// `size` random P2WPKH scripts, standing in for a wallet's addresses. Each matches a block's
+/// filter by chance about once in 784931 blocks, as BIP158 intends, so the wallet's real
+/// activity is simulated with a match rate given to [`sync`].
+pub fn random_wallet(size: usize) -> Vec<Script> {
+    (0..size)
+        .map(|_| {
+            let key_hash: [u8; 20] = with_rng(|rng| rng.gen());
+            Script::new_v0_p2wpkh(&WPubkeyHash::from_inner(key_hash))
+        })
+        .collect()
+}
+
+/// Downloads every header of the peer's best chain after `genesis`, returning the hashes of the
+/// chain from `genesis` on.
+pub fn sync_headers<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    genesis: BlockHash,
+) -> Result<Vec<BlockHash>> {
+    let mut hashes = vec![genesis];
+    loop {
+        client.send(NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: vec![*hashes.last().expect("starts with genesis")],
+            stop_hash: BlockHash::all_zeros(),
+        }))?;
+        let headers = loop {
+            if let NetworkMessage::Headers(headers) = client.recv()? {
+                break headers;
+            }
+        };
+        if let Some(first) = headers.first() {
+            if first.prev_blockhash != *hashes.last().expect("starts with genesis") {
+                return Err(anyhow!("Target sent headers not connecting to our chain"));
+            }
+        }
+        hashes.extend(headers.iter().map(BlockHeader::block_hash));
+        trace!("Synced {} headers", hashes.len() - 1);
+        if headers.len() < MAX_HEADERS_RESULTS {
+            return Ok(hashes);
+        }
+    }
+}
+
+/// Downloads the filter hashes of `block_hashes`, the blocks from `start_height` on in chain
+/// order, checking each batch's filter headers connect to the previous batch's.
+pub fn download_filter_hashes<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    start_height: u32,
+    block_hashes: &[BlockHash],
+) -> Result<Vec<FilterHash>> {
+    let mut filter_hashes = Vec::with_capacity(block_hashes.len());
+    let mut last_header: Option<FilterHeader> = None;
+    for (i, chunk) in block_hashes.chunks(MAX_GETCFHEADERS_SIZE).enumerate() {
+        let stop_hash = *chunk.last().expect("chunks are not empty");
+        client.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: BASIC_FILTER,
+            start_height: start_height + (i * MAX_GETCFHEADERS_SIZE) as u32,
+            stop_hash,
+        }))?;
+        let cfheaders = loop {
+            match client.recv()? {
+                NetworkMessage::CFHeaders(cfheaders) if cfheaders.stop_hash == stop_hash => {
+                    break cfheaders
+                }
+                _ => {}
+            }
+        };
+        if cfheaders.filter_hashes.len() != chunk.len() {
+            return Err(anyhow!(
+                "Target sent {} filter headers up to {stop_hash} instead of {}",
+                cfheaders.filter_hashes.len(),
+                chunk.len()
+            ));
+        }
+        if last_header.is_some_and(|last| last != cfheaders.previous_filter_header) {
+            return Err(anyhow!(
+                "Filter headers up to {stop_hash} do not connect to the previous ones"
+            ));
+        }
+        last_header = Some(
+            cfheaders
+                .filter_hashes
+                .iter()
+                .fold(cfheaders.previous_filter_header, |previous, filter_hash| {
+                    filter_hash.filter_header(&previous)
+                }),
+        );
+        filter_hashes.extend(cfheaders.filter_hashes);
+    }
+    Ok(filter_hashes)
+}
+
+/// Syncs like a BIP157 light client with `wallet` created at height `birthday`: downloads the
+/// whole headers chain after `genesis`, then the filter headers and filters from the birthday
+/// on, checking the filters against their headers, and fetches the blocks matching the wallet.
+/// Without a birthday, filters of the last [`DEFAULT_FILTER_BLOCKS`] blocks are downloaded.
+/// `match_rate`, from 0 to 1, of the blocks not matching are fetched too, as if the wallet had
+/// transactions in them.
+pub fn sync<S: Read + Write>(
+    client: &mut P2pClient<S>,
+    genesis: BlockHash,
+    birthday: Option<usize>,
+    wallet: &[Script],
+    match_rate: f64,
+) -> Result<LightClientReport> {
+    let start = Instant::now();
+    let chain = sync_headers(client, genesis)?;
+    let headers = StepCost {
+        items: chain.len() - 1,
+        bytes: (chain.len() - 1) * HEADERS_ENTRY_SIZE,
+        time: start.elapsed(),
+    };
+    let tip = chain.len() - 1;
+    let birthday = birthday
+        .unwrap_or_else(|| (tip + 1).saturating_sub(DEFAULT_FILTER_BLOCKS))
+        .min(tip);
+    let block_hashes = &chain[birthday..];
+
+    let start = Instant::now();
+    let filter_hashes = download_filter_hashes(client, birthday as u32, block_hashes)?;
+    let filter_headers = StepCost {
+        items: filter_hashes.len(),
+        bytes: filter_hashes.len() * FilterHash::LEN,
+        time: start.elapsed(),
+    };
+
+    let start = Instant::now();
+    let filters = download_filters(client, birthday as u32, block_hashes)?;
+    let filters_cost = StepCost {
+        items: filters.len(),
+        bytes: filters.iter().map(|filter| filter.filter.len()).sum(),
+        time: start.elapsed(),
+    };
+    for (filter, filter_hash) in filters.iter().zip(&filter_hashes) {
+        if FilterHash::hash(&filter.filter) != *filter_hash {
+            return Err(anyhow!(
+                "Filter of block {} does not match its filter header",
+                filter.block_hash
+            ));
+        }
+    }
+
+    let start = Instant::now();
+    let mut matched = matching_blocks(&filters, wallet)?;
+    matched.extend(
+        block_hashes
+            .iter()
+            .filter(|block_hash| !matched.contains(block_hash))
+            .filter(|_| with_rng(|rng| rng.gen_bool(match_rate)))
+            .copied()
+            .collect::<Vec<_>>(),
+    );
+    let bytes = fetch_blocks_by_hash(client, &matched)?;
+    Ok(LightClientReport {
+        headers,
+        filter_headers,
+        filters: filters_cost,
+        blocks: StepCost {
+            items: matched.len(),
+            bytes,
+            time: start.elapsed(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{MockStream, BLOCK_RESPONSES};
+    use crate::mine::mine_chain;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::network::message::RawNetworkMessage;
+    use bitcoin::network::message_filter::{CFHeaders, CFilter};
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::Network;
+
+    #[test]
+    fn light_client_syncs_headers_filters_and_matching_blocks() {
+        let magic = Network::Bitcoin.magic();
+        let genesis = genesis_block(Network::Bitcoin);
+        let headers = mine_chain(genesis.block_hash(), 2, 0x207fffff, 0);
+        let chain: Vec<_> = [genesis.block_hash()]
+            .into_iter()
+            .chain(headers.iter().map(BlockHeader::block_hash))
+            .collect();
+        // Only the genesis block pays to a script; the filters of the others are empty.
+        let contents = [
+            BlockFilter::new_script_filter(&genesis, |_| unreachable!())
+                .unwrap()
+                .content,
+            vec![0],
+            vec![0],
+        ];
+        let mut replies = vec![
+            NetworkMessage::Headers(headers),
+            NetworkMessage::CFHeaders(CFHeaders {
+                filter_type: 0,
+                stop_hash: chain[2],
+                previous_filter_header: FilterHeader::all_zeros(),
+                filter_hashes: contents
+                    .iter()
+                    .map(|content| FilterHash::hash(content))
+                    .collect(),
+            }),
+        ];
+        replies.extend(chain.iter().zip(&contents).map(|(block_hash, content)| {
+            NetworkMessage::CFilter(CFilter {
+                filter_type: 0,
+                block_hash: *block_hash,
+                filter: content.clone(),
+            })
+        }));
+        let fixture: String = replies
+            .into_iter()
+            .map(|payload| serialize(&RawNetworkMessage { magic, payload }).to_hex())
+            .chain([BLOCK_RESPONSES.to_string()])
+            .collect();
+        let mut client = P2pClient::new(MockStream::new(&fixture), magic);
+        let mut wallet = random_wallet(10);
+        wallet.push(genesis.txdata[0].output[0].script_pubkey.clone());
+        let report = sync(&mut client, genesis.block_hash(), Some(0), &wallet, 0.0).unwrap();
+        let items = report.steps().map(|(_, cost)| cost.items);
+        assert_eq!(items, [2, 3, 3, 1]);
+        assert_eq!(report.blocks.bytes, genesis.size());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use std::io;
+
+/// File descriptors a connection holds at most: its socket and the handles cloned from it to
+/// write requests, to tear it down and to sample its TCP state.
+pub const FDS_PER_CONNECTION: u64 = 4;
+
+/// File descriptors kept aside for everything else the process opens, such as the standard
+/// streams, log files and the connections probing the target before a run.
+pub const RESERVED_FDS: u64 = 64;
+
+/// The soft and hard limits on the file descriptors the process may open.
+#[cfg(target_os = "linux")]
+fn rlimit() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit writes a single rlimit through the pointer.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+/// The soft limit on the file descriptors the process may open, if there is one.
+#[cfg(target_os = "linux")]
+pub fn open_files_limit() -> io::Result<Option<u64>> {
+    let limit = rlimit()?;
+    Ok((limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_files_limit() -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Raises the soft limit on open file descriptors towards `wanted`, as far as the hard limit
+/// allows, returning the new soft limit.
+#[cfg(target_os = "linux")]
+pub fn raise_open_files_limit(wanted: u64) -> io::Result<u64> {
+    let mut limit = rlimit()?;
+    if limit.rlim_cur >= wanted {
+        return Ok(limit.rlim_cur);
+    }
+    limit.rlim_cur = wanted.min(limit.rlim_max);
+    // SAFETY: setrlimit only reads the rlimit behind the pointer.
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn raise_open_files_limit(wanted: u64) -> io::Result<u64> {
+    Ok(wanted)
+}
+
+/// File descriptors `connections` connections may need, including the reserved ones.
+pub fn needed_fds(connections: usize) -> u64 {
+    connections as u64 * FDS_PER_CONNECTION + RESERVED_FDS
+}
+
+/// The most connections a run can open under a limit of `limit` file descriptors.
+pub fn max_connections(limit: u64) -> usize {
+    (limit.saturating_sub(RESERVED_FDS) / FDS_PER_CONNECTION) as usize
+}
+
+/// Fails before anything is opened if `connections` connections could run out of file
+/// descriptors midway through a run, after trying to raise the soft limit to fit them.
+pub fn check_connections(connections: usize) -> Result<()> {
+    let Some(limit) = open_files_limit()? else {
+        return Ok(());
+    };
+    let needed = needed_fds(connections);
+    if needed <= limit {
+        return Ok(());
+    }
+    let limit = match raise_open_files_limit(needed) {
+        Ok(raised) => raised,
+        Err(e) => {
+            eprintln!("Could not raise the open file limit: {e}");
+            limit
+        }
+    };
+    if needed > limit {
+        return Err(anyhow!(
+            "{connections} connections need up to {needed} file descriptors but the limit is \
+             {limit}, which fits at most {} connections; raise it with `ulimit -n {needed}` or \
+             use fewer connections",
+            max_connections(limit)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_fit_the_file_limit() {
+        assert_eq!(needed_fds(0), RESERVED_FDS);
+        assert_eq!(max_connections(needed_fds(1000)), 1000);
+        assert_eq!(max_connections(needed_fds(1000) - 1), 999);
+        assert_eq!(max_connections(RESERVED_FDS / 2), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn raising_the_file_limit_stops_at_the_hard_limit() {
+        let limit = rlimit().unwrap();
+        // Already within the soft limit, so nothing changes.
+        assert_eq!(raise_open_files_limit(1).unwrap(), limit.rlim_cur);
+        let raised = raise_open_files_limit(u64::MAX).unwrap();
+        assert_eq!(raised, limit.rlim_max);
+        assert_eq!(rlimit().unwrap().rlim_cur, raised);
+    }
+
+    #[test]
+    fn open_file_limit_bounds_the_connections() {
+        assert_eq!(needed_fds(250), 1064);
+        assert_eq!(max_connections(needed_fds(250)), 250);
+        assert_eq!(max_connections(needed_fds(250) - 1), 249);
+        assert_eq!(max_connections(RESERVED_FDS - 1), 0);
+        check_connections(1).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, BlockHeader};
+
+/// Hashes a locator must hold before its steps back start doubling. As the step to the next
+/// hash is taken before doubling, the first 12 hashes are one block apart.
+const DENSE_HASHES: usize = 10;
+
+/// Indexes into a chain of `len` blocks of the blocks a locator names, tip first: the last 12
+/// blocks, then exponentially larger steps back, always ending with the first block. This is the
+/// same spacing bitcoind uses.
+fn locator_indexes(len: usize) -> Vec<usize> {
+    let mut indexes = Vec::new();
+    let Some(mut index) = len.checked_sub(1) else {
+        return indexes;
+    };
+    let mut step = 1;
+    loop {
+        indexes.push(index);
+        if index == 0 {
+            return indexes;
+        }
+        index = index.saturating_sub(step);
+        if indexes.len() > DENSE_HASHES {
+            step *= 2;
+        }
+    }
+}
+
+/// Builds a block locator for getheaders or getblocks from `chain`, a list of consecutive block
+/// hashes in chain order, oldest first. The locator names the tip first and the first hash of
+/// `chain` last, so a peer on the same chain finds a recent common block, and a peer on a fork
+/// still finds one no older than the start of `chain`.
+pub fn locator_from_hashes(chain: &[BlockHash]) -> Vec<BlockHash> {
+    locator_indexes(chain.len())
+        .into_iter()
+        .map(|index| chain[index])
+        .collect()
+}
+
+/// Builds a block locator from a stored chain of consecutive `headers`, oldest first, like
+/// [`locator_from_hashes`]. It ends with the parent of the first header unless that is genesis,
+/// whose parent is all zeros.
+pub fn locator_from_headers(headers: &[BlockHeader]) -> Vec<BlockHash> {
+    let mut locator: Vec<_> = locator_indexes(headers.len())
+        .into_iter()
+        .map(|index| headers[index].block_hash())
+        .collect();
+    if let Some(first) = headers.first() {
+        if first.prev_blockhash != BlockHash::all_zeros() {
+            locator.push(first.prev_blockhash);
+        }
+    }
+    locator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locator_is_dense_at_the_tip_then_doubles_back_to_the_first_hash() {
+        let chain: Vec<_> = (0..100u8).map(|i| BlockHash::hash(&[i])).collect();
+        let locator = locator_from_hashes(&chain);
+        let expected: Vec<_> = (88..100)
+            .rev()
+            .chain([86, 82, 74, 58, 26, 0])
+            .map(|i| chain[i])
+            .collect();
+        assert_eq!(locator, expected);
+        assert_eq!(locator_from_hashes(&chain[..1]), vec![chain[0]]);
+        assert!(locator_from_hashes(&[]).is_empty());
+    }
+}
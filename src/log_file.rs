@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file writer that rotates to `<path>.1`, `<path>.2`, ... once it exceeds a size limit.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, max_size: Option<u64>, keep: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_size,
+            keep,
+        })
+    }
+
+    fn backup_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                let from = self.backup_path(index);
+                if from.exists() {
+                    rename(from, self.backup_path(index + 1))?;
+                }
+            }
+            rename(&self.path, self.backup_path(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.written > 0 && self.written + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Parses a byte size such as `4096`, `512K`, `10M` or `1G`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| anyhow!("Invalid size {s}, expected e.g. 4096, 512K, 10M or 1G"))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size {s} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_with_a_suffix_and_reject_overflow() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size(" 512k ").unwrap(), 512 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+}
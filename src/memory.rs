@@ -0,0 +1,146 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, Default)]
+struct Usage {
+    in_use: usize,
+    /// Part of `in_use` held by reservations that waited for room, which the cap applies to.
+    capped: usize,
+    peak: usize,
+}
+
+/// Bytes the client holds in request and receive buffers and decode queues across every
+/// connection, optionally capping the requests and queues, which are released as the run goes on.
+/// Reserving past the cap waits until enough is released, so a large run slows down instead of
+/// exhausting memory.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    cap: Option<usize>,
+    usage: Mutex<Usage>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<usize>) -> Self {
+        Self {
+            cap,
+            usage: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserves `bytes`, first waiting until they fit under the cap along with the other
+    /// reservations that waited. Only memory released during the run, such as requests until
+    /// they are sent, should wait, as waiting on memory held until the run ends would never
+    /// finish. A reservation larger than the cap is granted once no other waiting reservation is
+    /// held, so it delays rather than blocks.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Reservation {
+        self.reserve_while(bytes, || true)
+    }
+
+    /// Reserves `bytes` like [`reserve`](Self::reserve), but stops waiting as soon as
+    /// `can_wait` returns false, such as once nothing is left that would release memory.
+    /// `can_wait` is checked again on every release.
+    pub fn reserve_while(
+        self: &Arc<Self>,
+        bytes: usize,
+        can_wait: impl Fn() -> bool,
+    ) -> Reservation {
+        let usage = self.usage.lock().unwrap();
+        let mut usage = self
+            .released
+            .wait_while(usage, |usage| {
+                self.cap
+                    .is_some_and(|cap| usage.capped > 0 && usage.capped + bytes > cap)
+                    && can_wait()
+            })
+            .unwrap();
+        usage.capped += bytes;
+        self.add(&mut usage, bytes, true)
+    }
+
+    /// Reserves `bytes` without waiting or counting towards the cap, for memory held as long as
+    /// a connection is.
+    pub fn reserve_now(self: &Arc<Self>, bytes: usize) -> Reservation {
+        let mut usage = self.usage.lock().unwrap();
+        self.add(&mut usage, bytes, false)
+    }
+
+    /// The most bytes reserved at once so far.
+    pub fn peak(&self) -> usize {
+        self.usage.lock().unwrap().peak
+    }
+
+    fn add(self: &Arc<Self>, usage: &mut Usage, bytes: usize, capped: bool) -> Reservation {
+        usage.in_use += bytes;
+        usage.peak = usage.peak.max(usage.in_use);
+        Reservation {
+            budget: self.clone(),
+            bytes,
+            capped,
+        }
+    }
+
+    fn release(&self, bytes: usize, capped: bool) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.in_use -= bytes;
+        if capped {
+            usage.capped -= bytes;
+        }
+        self.released.notify_all();
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`] until dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+    capped: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes, self.capped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reservations_past_the_cap_wait_for_a_release() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let held = budget.reserve(60);
+        // Memory held as long as a connection is does not count towards the cap.
+        let connection = budget.reserve_now(1000);
+        let (tx, rx) = channel();
+        let waiting = {
+            let budget = budget.clone();
+            thread::spawn(move || tx.send(budget.reserve(60)).unwrap())
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(held);
+        let granted = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+        assert_eq!(budget.peak(), 1060);
+
+        // A reservation larger than the cap is granted once it is the only one waiting.
+        drop(granted);
+        let large = budget.reserve(500);
+        assert_eq!(budget.peak(), 1500);
+        drop((large, connection));
+        assert_eq!(budget.usage.lock().unwrap().in_use, 0);
+    }
+
+    #[test]
+    fn reservations_stop_waiting_once_nothing_would_release() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let _held = budget.reserve(100);
+        let _over = budget.reserve_while(50, || false);
+        assert_eq!(budget.peak(), 150);
+    }
+}
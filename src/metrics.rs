@@ -0,0 +1,211 @@
+use crate::http;
+use anyhow::{anyhow, Result};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MEASUREMENT: &str = "spam_block_reqs";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Intervals waiting to be exported before further ones are dropped.
+const EXPORT_QUEUE_SIZE: usize = 8;
+
+/// What a run measured over one report interval.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntervalMetrics {
+    /// When the interval ended.
+    pub time: SystemTime,
+    /// Responses received since the start of the run.
+    pub responses: usize,
+    /// Responses per second over the interval.
+    pub rate: f64,
+    /// Bytes per second over the interval.
+    pub throughput: f64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl IntervalMetrics {
+    fn fields(&self) -> [(&'static str, String); 6] {
+        let millis = |duration: Duration| (duration.as_secs_f64() * 1000.0).to_string();
+        [
+            ("responses", self.responses.to_string()),
+            ("rate", self.rate.to_string()),
+            ("bytes_per_second", self.throughput.to_string()),
+            ("p50_ms", millis(self.p50)),
+            ("p99_ms", millis(self.p99)),
+            ("max_ms", millis(self.max)),
+        ]
+    }
+}
+
+/// Where interval metrics are pushed.
+#[derive(Clone, Debug, PartialEq)]
+enum Sink {
+    /// An InfluxDB write endpoint, e.g. `http://localhost:8086/write?db=nodes`.
+    Influx(String),
+    /// A Graphite plaintext listener, e.g. `localhost:2003`.
+    Graphite(String),
+}
+
+/// Pushes each interval's metrics of a run to InfluxDB or Graphite, tagged with the target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsExporter {
+    sink: Sink,
+    target: String,
+}
+
+impl MetricsExporter {
+    /// Parses an `influx:<write url>` or `graphite:<host:port>` spec.
+    pub fn from_spec(spec: &str, target: &str) -> Result<Self> {
+        let sink = match spec.split_once(':') {
+            Some(("influx", url)) => Sink::Influx(url.to_string()),
+            Some(("graphite", address)) => Sink::Graphite(address.to_string()),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid metrics export spec {spec}, expected influx:<url> or \
+                     graphite:<host:port>"
+                ))
+            }
+        };
+        Ok(Self {
+            sink,
+            target: target.to_string(),
+        })
+    }
+
+    pub fn export(&self, metrics: &IntervalMetrics) -> Result<()> {
+        match &self.sink {
+            Sink::Influx(url) => {
+                http::post(url, "text/plain", self.line_protocol(metrics)?.as_bytes())
+            }
+            Sink::Graphite(address) => {
+                let address = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("Could not resolve {address}"))?;
+                let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                stream.write_all(self.plaintext(metrics)?.as_bytes())?;
+                Ok(())
+            }
+        }
+    }
+
+    /// The metrics as a single InfluxDB line, timestamped in nanoseconds.
+    pub fn line_protocol(&self, metrics: &IntervalMetrics) -> Result<String> {
+        let target = self
+            .target
+            .replace(',', "\\,")
+            .replace('=', "\\=")
+            .replace(' ', "\\ ");
+        let fields = metrics
+            .fields()
+            .map(|(name, value)| match name {
+                "responses" => format!("{name}={value}i"),
+                _ => format!("{name}={value}"),
+            })
+            .join(",");
+        let nanos = metrics.time.duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(format!("{MEASUREMENT},target={target} {fields} {nanos}\n"))
+    }
+
+    /// The metrics as Graphite plaintext lines under `spam_block_reqs.<target>`, timestamped in
+    /// seconds.
+    pub fn plaintext(&self, metrics: &IntervalMetrics) -> Result<String> {
+        // Dots separate path components, so the target's address cannot keep its own.
+        let target: String = self
+            .target
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let seconds = metrics.time.duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(metrics
+            .fields()
+            .iter()
+            .map(|(name, value)| format!("{MEASUREMENT}.{target}.{name} {value} {seconds}\n"))
+            .collect())
+    }
+}
+
+/// Exports metrics from a thread of its own, so a slow or unreachable sink does not hold up the
+/// run they measure. Dropping it waits for the intervals still queued to be exported.
+pub struct BackgroundExporter {
+    sender: Option<SyncSender<IntervalMetrics>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundExporter {
+    pub fn spawn(exporter: MetricsExporter) -> Result<Self> {
+        let (sender, receiver) = sync_channel::<IntervalMetrics>(EXPORT_QUEUE_SIZE);
+        let thread = thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for metrics in receiver {
+                    // Losing a point on a dashboard is no reason to end the run.
+                    if let Err(e) = exporter.export(&metrics) {
+                        eprintln!("Could not export the interval's metrics: {e}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues `metrics` to be exported, or drops them if the sink is too far behind.
+    pub fn export(&self, metrics: IntervalMetrics) {
+        let sender = self.sender.as_ref().expect("only taken on drop");
+        if let Err(TrySendError::Full(_)) = sender.try_send(metrics) {
+            eprintln!("Could not export the interval's metrics: the sink is too far behind");
+        }
+    }
+}
+
+impl Drop for BackgroundExporter {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_metrics_export_as_influx_lines_and_graphite_paths() {
+        let metrics = IntervalMetrics {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            responses: 1200,
+            rate: 40.5,
+            throughput: 1e6,
+            p50: Duration::from_millis(5),
+            p99: Duration::from_micros(40_500),
+            max: Duration::from_millis(90),
+        };
+        let influx =
+            MetricsExporter::from_spec("influx:http://localhost:8086/write?db=nodes", "node 1")
+                .unwrap();
+        assert_eq!(
+            influx.line_protocol(&metrics).unwrap(),
+            "spam_block_reqs,target=node\\ 1 responses=1200i,rate=40.5,bytes_per_second=1000000,\
+             p50_ms=5,p99_ms=40.5,max_ms=90 1700000000000000000\n"
+        );
+        let graphite =
+            MetricsExporter::from_spec("graphite:localhost:2003", "10.0.0.2:8333").unwrap();
+        let lines = graphite.plaintext(&metrics).unwrap();
+        assert_eq!(lines.lines().count(), 6);
+        assert_eq!(
+            lines.lines().nth(4),
+            Some("spam_block_reqs.10_0_0_2_8333.p99_ms 40.5 1700000000")
+        );
+        assert!(MetricsExporter::from_spec("prometheus:localhost:9090", "node").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use bitcoin::consensus::params::Params;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::{BlockHash, BlockHeader, Network, TxMerkleNode};
+
+use crate::rng::with_rng;
+
+/// Builds a header on `prev_blockhash` with a random merkle root and grinds the nonce until the
+/// header's hash meets the target encoded in `bits`.
+///
+/// The header is only valid on its own. Grinding takes about 2^32 hashes at mainnet's minimum
+/// difficulty (`0x1d00ffff`), but is instant with regtest's `0x207fffff`.
+pub fn mine_header(prev_blockhash: BlockHash, bits: u32, time: u32) -> BlockHeader {
+    let mut header = BlockHeader {
+        version: 0x2000_0000,
+        prev_blockhash,
+        merkle_root: TxMerkleNode::from_inner(with_rng(|rng| rng.gen())),
+        time,
+        bits,
+        nonce: 0,
+    };
+    let target = header.target();
+    loop {
+        if header.validate_pow(&target).is_ok() {
+            return header;
+        }
+        header.nonce = header.nonce.wrapping_add(1);
+        if header.nonce == 0 {
+            header.merkle_root = TxMerkleNode::from_inner(with_rng(|rng| rng.gen()));
+        }
+    }
+}
+
+/// The bits of `network`'s minimum difficulty, the easiest headers it accepts.
+pub fn minimum_difficulty_bits(network: Network) -> u32 {
+    BlockHeader::compact_target_from_u256(&Params::new(network).pow_limit)
+}
+
+/// Mines `count` headers, each building on the previous one, starting on `prev_blockhash`.
+pub fn mine_chain(
+    prev_blockhash: BlockHash,
+    count: usize,
+    bits: u32,
+    time: u32,
+) -> Vec<BlockHeader> {
+    let mut headers: Vec<BlockHeader> = Vec::with_capacity(count);
+    for i in 0..count {
+        let prev = headers
+            .last()
+            .map_or(prev_blockhash, BlockHeader::block_hash);
+        headers.push(mine_header(prev, bits, time + i as u32));
+    }
+    headers
+}
+
+/// A random hash the target is practically guaranteed not to know.
+pub fn unknown_block_hash() -> BlockHash {
+    BlockHash::from_inner(with_rng(|rng| rng.gen()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimum_difficulty_follows_the_network() {
+        assert_eq!(minimum_difficulty_bits(Network::Bitcoin), 0x1d00ffff);
+        assert_eq!(minimum_difficulty_bits(Network::Testnet), 0x1d00ffff);
+        assert_eq!(minimum_difficulty_bits(Network::Regtest), 0x207fffff);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::frames::{timed_out, Frames};
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Connection;
+use anyhow::Result;
+use bitcoin::consensus::serialize;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::BlockHash;
+use log::trace;
+use std::collections::BTreeMap;
+use std::io::{BufReader, Write};
+use std::time::{Duration, Instant};
+
+/// How the target reacted to messages we sent it.
+#[derive(Debug, Default)]
+pub struct Reaction {
+    pub messages_sent: usize,
+    /// Number of messages received from the target, by command.
+    pub received: BTreeMap<String, usize>,
+    /// Inventory the target requested from us via getdata.
+    pub requested: Vec<Inventory>,
+    /// Tip of the locator in the target's last getheaders, i.e. the furthest header it asked us
+    /// to continue from.
+    pub last_locator_tip: Option<BlockHash>,
+    /// Time since we started sending at which the target disconnected us, if it did.
+    pub disconnected_after: Option<Duration>,
+}
+
+/// Sends `messages` after the handshake, then keeps reading until `observe` has passed,
+/// recording how the target reacts.
+pub fn send_and_observe(
+    stream: &mut Connection,
+    magic: u32,
+    messages: Vec<NetworkMessage>,
+    observe: Duration,
+) -> Result<Reaction> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    // Frames keep what arrived of a message across read timeouts.
+    let mut frames = Frames::new(BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?));
+    let mut reaction = Reaction::default();
+    let start = Instant::now();
+    for payload in messages {
+        let message = RawNetworkMessage { magic, payload };
+        if stream.write_all(&serialize(&message)).is_err() {
+            reaction.disconnected_after = Some(start.elapsed());
+            return Ok(reaction);
+        }
+        reaction.messages_sent += 1;
+    }
+    trace!("Sent {} messages", reaction.messages_sent);
+
+    let deadline = start + observe;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let message = match frames.next().map(|frame| frame?.decode()) {
+            Some(Ok(message)) => message,
+            Some(Err(e)) if timed_out(&e) => {
+                break;
+            }
+            _ => {
+                reaction.disconnected_after = Some(start.elapsed());
+                break;
+            }
+        };
+        *reaction
+            .received
+            .entry(message.cmd().to_string())
+            .or_default() += 1;
+        match &message.payload {
+            NetworkMessage::GetHeaders(request) => {
+                reaction.last_locator_tip = request.locator_hashes.first().copied();
+            }
+            NetworkMessage::GetData(inventory) => reaction.requested.extend(inventory),
+            _ => {}
+        }
+        handler.handle(stream, &message.payload)?;
+    }
+    Ok(reaction)
+}
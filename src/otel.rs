@@ -0,0 +1,162 @@
+use crate::http;
+use anyhow::Result;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SERVICE_NAME: &str = "spam-block-reqs";
+
+pub type SpanId = [u8; 8];
+
+struct Span {
+    id: SpanId,
+    parent: Option<SpanId>,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+/// Spans and metrics of a single run, exported to an OTLP/HTTP collector as JSON.
+pub struct Telemetry {
+    trace_id: [u8; 16],
+    spans: Vec<Span>,
+    metrics: Vec<Value>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self {
+            trace_id: thread_rng().gen(),
+            spans: Vec::new(),
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Records a finished span and returns its id for use as a parent.
+    pub fn span(
+        &mut self,
+        name: &str,
+        parent: Option<SpanId>,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: Vec<(&str, Value)>,
+    ) -> SpanId {
+        let id = thread_rng().gen();
+        self.spans.push(Span {
+            id,
+            parent,
+            name: name.to_string(),
+            start,
+            end,
+            attributes: attributes
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        });
+        id
+    }
+
+    /// Records a monotonic counter covering the interval from `start` to `end`.
+    pub fn counter(&mut self, name: &str, value: u64, start: SystemTime, end: SystemTime) {
+        self.metrics.push(json!({
+            "name": name,
+            "sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": [{
+                    "asInt": value.to_string(),
+                    "startTimeUnixNano": nanos(start),
+                    "timeUnixNano": nanos(end),
+                }],
+            },
+        }));
+    }
+
+    pub fn gauge(&mut self, name: &str, unit: &str, value: f64, time: SystemTime) {
+        self.metrics.push(json!({
+            "name": name,
+            "unit": unit,
+            "gauge": {
+                "dataPoints": [{ "asDouble": value, "timeUnixNano": nanos(time) }],
+            },
+        }));
+    }
+
+    /// Posts the spans and metrics to `<endpoint>/v1/traces` and `<endpoint>/v1/metrics`.
+    pub fn export(&self, endpoint: &str) -> Result<()> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = json!({
+            "attributes": [attribute("service.name", json!(SERVICE_NAME))],
+        });
+        let scope = json!({ "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") });
+
+        let spans: Vec<Value> = self.spans.iter().map(|span| self.span_json(span)).collect();
+        let traces = json!({
+            "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{ "scope": scope, "spans": spans }],
+            }],
+        });
+        http::post(
+            &format!("{endpoint}/v1/traces"),
+            "application/json",
+            traces.to_string().as_bytes(),
+        )?;
+
+        let metrics = json!({
+            "resourceMetrics": [{
+                "resource": resource,
+                "scopeMetrics": [{ "scope": scope, "metrics": self.metrics }],
+            }],
+        });
+        http::post(
+            &format!("{endpoint}/v1/metrics"),
+            "application/json",
+            metrics.to_string().as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn span_json(&self, span: &Span) -> Value {
+        json!({
+            "traceId": self.trace_id.to_hex(),
+            "spanId": span.id.to_hex(),
+            "parentSpanId": span.parent.map(|parent| parent.to_hex()).unwrap_or_default(),
+            "name": span.name,
+            "kind": 3,
+            "startTimeUnixNano": nanos(span.start),
+            "endTimeUnixNano": nanos(span.end),
+            "attributes": span
+                .attributes
+                .iter()
+                .map(|(key, value)| attribute(key, value.clone()))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
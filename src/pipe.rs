@@ -0,0 +1,222 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+/// Size of the buffer blocks are written through, so small blocks do not cost a write each.
+const PIPE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Blocks waiting for the consumer before further ones are dropped.
+const PIPE_QUEUE_SIZE: usize = 64;
+
+/// What a [`BlockPipe`] forwarded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PipeReport {
+    pub blocks: usize,
+    pub bytes: u64,
+    /// Blocks dropped because the consumer fell [`PIPE_QUEUE_SIZE`] blocks behind.
+    pub dropped: usize,
+}
+
+/// Forwards the payload of every block received, on any connection, to a downstream consumer
+/// such as an indexer under test. Each block is written whole as its serialized size, a u32
+/// little-endian, followed by the block itself.
+///
+/// Blocks are written from a thread of their own, so a slow consumer does not hold up the
+/// connections and inflate the latencies they measure. Blocks it cannot keep up with are dropped
+/// and counted instead.
+pub struct BlockPipe {
+    spec: String,
+    sender: Mutex<Option<SyncSender<Vec<u8>>>>,
+    writer: Mutex<Option<JoinHandle<Result<PipeReport>>>>,
+    dropped: AtomicUsize,
+}
+
+impl BlockPipe {
+    /// Opens a pipe from a `tcp:host:port`, `fd:n` or path spec. A path may name a FIFO, in
+    /// which case this waits for its reader.
+    pub fn open(spec: &str) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match spec.split_once(':') {
+            Some(("tcp", address)) => Box::new(
+                TcpStream::connect(address)
+                    .map_err(|e| anyhow!("Could not connect to {address}: {e}"))?,
+            ),
+            Some(("fd", fd)) => Box::new(fd_file(parse_fd(fd)?)?),
+            _ => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(spec)
+                    .map_err(|e| anyhow!("Could not open {spec}: {e}"))?,
+            ),
+        };
+        let (sender, receiver) = sync_channel(PIPE_QUEUE_SIZE);
+        let owned_spec = spec.to_string();
+        let writer = thread::Builder::new()
+            .name("pipe".to_string())
+            .spawn(move || write_blocks(writer, receiver, &owned_spec))?;
+        Ok(Self {
+            spec: spec.to_string(),
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicUsize::new(0),
+        })
+    }
+
+    /// Queues the serialized block `payload` to be written, length-prefixed, or drops it if the
+    /// consumer is too far behind. Fails once writing to the consumer failed.
+    pub fn forward(&self, payload: &[u8]) -> Result<()> {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return Err(anyhow!("Pipe to {} is closed", self.spec));
+        };
+        match sender.try_send(payload.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(anyhow!(
+                "Could not pipe a block to {}: the consumer stopped reading",
+                self.spec
+            )),
+        }
+    }
+
+    /// Writes out the blocks still queued and closes the pipe, returning what it forwarded.
+    pub fn finish(&self) -> Result<PipeReport> {
+        drop(self.sender.lock().unwrap().take());
+        let writer = self
+            .writer
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("Pipe to {} is already closed", self.spec))?;
+        let mut report = writer
+            .join()
+            .map_err(|_| anyhow!("Pipe to {} panicked", self.spec))??;
+        report.dropped = self.dropped.load(Ordering::Relaxed);
+        Ok(report)
+    }
+
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+}
+
+impl fmt::Debug for BlockPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockPipe")
+            .field("spec", &self.spec)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Writes the blocks received on `receiver` until every sender is gone.
+fn write_blocks(
+    writer: Box<dyn Write + Send>,
+    receiver: Receiver<Vec<u8>>,
+    spec: &str,
+) -> Result<PipeReport> {
+    let mut writer = BufWriter::with_capacity(PIPE_BUFFER_SIZE, writer);
+    let mut report = PipeReport::default();
+    let fail = |e| anyhow!("Could not pipe blocks to {spec}: {e}");
+    for payload in receiver {
+        writer
+            .write_all(&(payload.len() as u32).to_le_bytes())
+            .and_then(|_| writer.write_all(&payload))
+            .map_err(fail)?;
+        report.blocks += 1;
+        report.bytes += payload.len() as u64;
+    }
+    writer.flush().map_err(fail)?;
+    Ok(report)
+}
+
+fn parse_fd(fd: &str) -> Result<i32> {
+    match fd.parse() {
+        // Standard input, output and error carry the tool's own I/O.
+        Ok(fd) if fd >= 3 => Ok(fd),
+        _ => Err(anyhow!("Invalid file descriptor {fd}, expected 3 or above")),
+    }
+}
+
+/// Fails unless `spec` is an `fd:n` spec naming a descriptor the process inherited open, or no
+/// `fd:` spec at all. Must run before the process opens files of its own, which could otherwise
+/// be given the descriptor's number.
+pub fn check_inherited(spec: &str) -> Result<()> {
+    match spec.split_once(':') {
+        Some(("fd", fd)) => is_open(parse_fd(fd)?),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_open(fd: i32) -> Result<()> {
+    // SAFETY: F_GETFD only reads the descriptor's flags, and fails on a closed descriptor.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(anyhow!(
+            "File descriptor {fd} is not open, e.g. run with {fd}> >(consumer)"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn fd_file(fd: i32) -> Result<File> {
+    use std::os::unix::io::FromRawFd;
+    is_open(fd)?;
+    // SAFETY: the descriptor is open, and was handed to us for nothing but the blocks, so the
+    // file is its only owner.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_open(_fd: i32) -> Result<()> {
+    Err(anyhow!(
+        "Piping to a file descriptor is only supported on Linux"
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fd_file(fd: i32) -> Result<File> {
+    is_open(fd).map(|_| unreachable!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::{Block, Network};
+
+    #[test]
+    fn piped_blocks_are_length_prefixed() {
+        let path = std::env::temp_dir().join(format!("pipe-{}.blocks", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pipe = BlockPipe::open(path.to_str().unwrap()).unwrap();
+        for network in [Network::Bitcoin, Network::Regtest] {
+            pipe.forward(&serialize(&genesis_block(network))).unwrap();
+        }
+        assert_eq!(pipe.finish().unwrap().blocks, 2);
+
+        let piped = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut rest = &piped[..];
+        let mut blocks = Vec::new();
+        while !rest.is_empty() {
+            let size = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            blocks.push(deserialize::<Block>(&rest[4..4 + size]).unwrap());
+            rest = &rest[4 + size..];
+        }
+        assert_eq!(blocks[1], genesis_block(Network::Regtest));
+        assert_eq!(blocks.len(), 2);
+        assert!(BlockPipe::open("fd:1").is_err());
+        assert!(check_inherited("fd:1000").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::analyze::RecordedResponse;
+use crate::history::RunMetrics;
+use crate::stats::percentile;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Width of the bars of a chart at its largest value, in pixels.
+const CHART_WIDTH: f64 = 480.0;
+/// Height of a bar of a chart, gap included.
+const BAR_HEIGHT: usize = 22;
+/// Width of the labels left of the bars.
+const LABEL_WIDTH: usize = 300;
+
+/// A matrix of spam runs: every request type at every load level against every target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    /// Name the plan's reports are filed under.
+    pub name: String,
+    pub targets: Vec<String>,
+    /// Values of `--request-type`, e.g. `witness-block`.
+    pub request_types: Vec<String>,
+    /// Values of `--connections`, the load levels.
+    pub connections: Vec<usize>,
+    /// Requests each run makes.
+    pub number: usize,
+    /// Further options for every run, e.g. `["--network", "signet", "--batch", "16"]`.
+    pub arguments: Vec<String>,
+}
+
+/// One run of a plan.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub target: String,
+    pub request_type: String,
+    pub connections: usize,
+}
+
+impl Cell {
+    /// Name the cell's output and events are filed under, e.g.
+    /// `nightly-10_0_0_2_8333-witness-block-16`.
+    pub fn name(&self, plan: &Plan) -> String {
+        let target: String = self
+            .target
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!(
+            "{}-{target}-{}-{}",
+            plan.name, self.request_type, self.connections
+        )
+    }
+
+    /// Arguments to run the tool with for this cell.
+    pub fn arguments(&self, plan: &Plan) -> Vec<String> {
+        let mut arguments = vec![
+            "spam".to_string(),
+            "--address".to_string(),
+            self.target.clone(),
+            "--request-type".to_string(),
+            self.request_type.clone(),
+            "--connections".to_string(),
+            self.connections.to_string(),
+            "--number".to_string(),
+            plan.number.to_string(),
+        ];
+        arguments.extend(plan.arguments.iter().cloned());
+        arguments
+    }
+
+    /// The cell as a row label, e.g. `10.0.0.2:8333 witness-block ×16`.
+    fn label(&self) -> String {
+        format!(
+            "{} {} ×{}",
+            self.target, self.request_type, self.connections
+        )
+    }
+}
+
+/// What running a cell left.
+#[derive(Clone, Debug)]
+pub struct CellOutcome {
+    pub cell: Cell,
+    /// Exit code of the run, or `None` if it was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// What the run printed, standard error included.
+    pub output: PathBuf,
+    /// What the run measured, or why that could not be read from its events.
+    pub metrics: Result<RunMetrics, String>,
+}
+
+impl Plan {
+    /// Reads the plan in the YAML file at `path`: a `name`, lists of `targets`, `request_types`
+    /// and `connections`, and optionally the `number` of requests per run and further
+    /// `arguments` for every run.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read plan {}: {e}", path.display()))?;
+        let plan: Value = serde_yaml_ng::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid plan {}: {e}", path.display()))?;
+        Self::from_value(&plan).map_err(|e| anyhow!("Invalid plan {}: {e}", path.display()))
+    }
+
+    fn from_value(plan: &Value) -> Result<Self> {
+        let name = plan["name"]
+            .as_str()
+            .filter(|name| {
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .ok_or_else(|| anyhow!("the plan needs a name of letters, digits, - and _"))?
+            .to_string();
+        let strings = |key: &str, required: bool| -> Result<Vec<String>> {
+            let strings = match &plan[key] {
+                Value::Null if !required => return Ok(Vec::new()),
+                Value::Array(values) => values
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(value) => Some(value.clone()),
+                        Value::Number(value) => Some(value.to_string()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>(),
+                _ => None,
+            };
+            match strings {
+                Some(strings) if !required || !strings.is_empty() => Ok(strings),
+                _ => Err(anyhow!("{key} must be a non-empty list")),
+            }
+        };
+        let connections = strings("connections", true)?
+            .iter()
+            .map(|connections| match connections.parse() {
+                Ok(connections) if connections > 0 => Ok(connections),
+                _ => Err(anyhow!("Invalid connections {connections}")),
+            })
+            .collect::<Result<_>>()?;
+        let number = match &plan["number"] {
+            Value::Null => 1000,
+            number => number
+                .as_u64()
+                .filter(|number| *number > 0)
+                .ok_or_else(|| anyhow!("number must be a positive integer"))?
+                as usize,
+        };
+        Ok(Plan {
+            name,
+            targets: strings("targets", true)?,
+            request_types: strings("request_types", true)?,
+            connections,
+            number,
+            arguments: strings("arguments", false)?,
+        })
+    }
+
+    /// Every cell of the matrix, target by target, then request type by request type, from the
+    /// lightest load to the heaviest.
+    pub fn cells(&self) -> Vec<Cell> {
+        let mut connections = self.connections.clone();
+        connections.sort_unstable();
+        let mut cells = Vec::new();
+        for target in &self.targets {
+            for request_type in &self.request_types {
+                cells.extend(connections.iter().map(|&connections| Cell {
+                    target: target.clone(),
+                    request_type: request_type.clone(),
+                    connections,
+                }));
+            }
+        }
+        cells
+    }
+}
+
+/// What the run that wrote the event log at `path` measured, from its responses and summary.
+pub fn read_metrics(path: &Path) -> Result<RunMetrics> {
+    let contents = fs::read_to_string(path)?;
+    let mut latencies = Vec::new();
+    let mut summary = None;
+    for line in contents.lines() {
+        let event: Value = serde_json::from_str(line)?;
+        if event["event"] == "summary" {
+            summary = Some((
+                event["responses"].as_u64().unwrap_or_default() as usize,
+                Duration::from_micros(event["elapsed_us"].as_u64().unwrap_or_default()),
+            ));
+        } else if let Some(response) = RecordedResponse::from_event(&event)? {
+            latencies.extend(response.latency);
+        }
+    }
+    let (responses, elapsed) =
+        summary.ok_or_else(|| anyhow!("the run ended before its summary"))?;
+    latencies.sort();
+    Ok(RunMetrics {
+        responses,
+        elapsed,
+        p50: percentile(&latencies, 50.0),
+        p99: percentile(&latencies, 99.0),
+    })
+}
+
+/// A self-contained HTML report of the outcomes of a plan's runs: a table of every cell and
+/// charts of their response rates and p99 latencies.
+pub fn render_html(plan: &Plan, outcomes: &[CellOutcome], generated: &str) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{name}</title>\n\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         th,td{{border:1px solid #ccc;padding:4px 8px;text-align:right}}\
+         th:first-child,td:first-child{{text-align:left}}.failed{{color:#b00}}\
+         svg{{display:block;margin:1em 0}}</style></head><body>\n\
+         <h1>{name}</h1>\n<p>Generated {generated}: {cells} runs of {number} requests, {targets} \
+         targets &times; {types} request types &times; {levels} load levels.</p>\n",
+        name = escape(&plan.name),
+        generated = escape(generated),
+        cells = outcomes.len(),
+        number = plan.number,
+        targets = plan.targets.len(),
+        types = plan.request_types.len(),
+        levels = plan.connections.len(),
+    );
+    html.push_str(
+        "<table>\n<tr><th>Target</th><th>Request type</th><th>Connections</th><th>Exit</th>\
+         <th>Responses</th><th>Elapsed</th><th>Responses/s</th><th>p50</th><th>p99</th>\
+         <th>Output</th></tr>\n",
+    );
+    for outcome in outcomes {
+        let cell = &outcome.cell;
+        let exit = match outcome.exit_code {
+            Some(code) => code.to_string(),
+            None => "signal".to_string(),
+        };
+        let failed = outcome.exit_code != Some(0) || outcome.metrics.is_err();
+        let _ = write!(
+            html,
+            "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{exit}</td>",
+            if failed { " class=\"failed\"" } else { "" },
+            escape(&cell.target),
+            escape(&cell.request_type),
+            cell.connections,
+        );
+        match &outcome.metrics {
+            Ok(metrics) => {
+                let _ = write!(
+                    html,
+                    "<td>{}</td><td>{:.2?}</td><td>{:.1}</td><td>{:.2?}</td><td>{:.2?}</td>",
+                    metrics.responses,
+                    metrics.elapsed,
+                    metrics.rate(),
+                    metrics.p50,
+                    metrics.p99
+                );
+            }
+            Err(e) => {
+                let _ = write!(html, "<td colspan=\"5\">{}</td>", escape(e));
+            }
+        }
+        let _ = writeln!(
+            html,
+            "<td>{}</td></tr>",
+            escape(&outcome.output.display().to_string())
+        );
+    }
+    html.push_str("</table>\n");
+
+    let bars = |value: fn(&RunMetrics) -> f64| -> Vec<(String, f64)> {
+        outcomes
+            .iter()
+            .filter_map(|outcome| {
+                let metrics = outcome.metrics.as_ref().ok()?;
+                Some((outcome.cell.label(), value(metrics)))
+            })
+            .collect()
+    };
+    html.push_str(&bar_chart(
+        "Responses per second",
+        "/s",
+        &bars(RunMetrics::rate),
+    ));
+    html.push_str(&bar_chart(
+        "p99 latency",
+        "ms",
+        &bars(|metrics| metrics.p99.as_secs_f64() * 1000.0),
+    ));
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// An inline SVG chart with a horizontal bar per (label, value).
+fn bar_chart(title: &str, unit: &str, bars: &[(String, f64)]) -> String {
+    let mut svg = format!("<h2>{}</h2>\n", escape(title));
+    if bars.is_empty() {
+        svg.push_str("<p>No run measured anything.</p>\n");
+        return svg;
+    }
+    let max = bars
+        .iter()
+        .map(|(_, value)| *value)
+        .fold(f64::EPSILON, f64::max);
+    let width = LABEL_WIDTH + CHART_WIDTH as usize + 120;
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{}\" \
+         font-size=\"12\">",
+        bars.len() * BAR_HEIGHT
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let y = i * BAR_HEIGHT;
+        let length = value / max * CHART_WIDTH;
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\
+             <rect x=\"{LABEL_WIDTH}\" y=\"{}\" width=\"{length:.1}\" height=\"{}\" \
+             fill=\"#4a7ebb\"/><text x=\"{:.1}\" y=\"{}\">{value:.1} {}</text>",
+            LABEL_WIDTH - 6,
+            y + 15,
+            escape(label),
+            y + 3,
+            BAR_HEIGHT - 6,
+            LABEL_WIDTH as f64 + length + 6.0,
+            y + 15,
+            escape(unit),
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_runs_its_matrix_and_reports_it_as_html() {
+        let dir = std::env::temp_dir().join(format!("plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plan.yaml");
+        std::fs::write(
+            &path,
+            "name: nightly\ntargets: [10.0.0.2:8333, 10.0.0.3:8333]\n\
+             request_types: [witness-block]\nconnections: [16, 4]\n",
+        )
+        .unwrap();
+        let plan = Plan::read(&path).unwrap();
+        let cells = plan.cells();
+        assert_eq!(cells.len(), 4);
+        assert_eq!(
+            cells[0].name(&plan),
+            "nightly-10_0_0_2_8333-witness-block-4"
+        );
+        assert_eq!(
+            cells[1].arguments(&plan)[..5],
+            [
+                "spam",
+                "--address",
+                "10.0.0.2:8333",
+                "--request-type",
+                "witness-block"
+            ]
+        );
+
+        let events = dir.join("events.jsonl");
+        std::fs::write(
+            &events,
+            concat!(
+                "{\"event\":\"response\",\"conn\":0,\"ts_us\":1,\"latency_us\":2000}\n",
+                "{\"event\":\"response\",\"conn\":1,\"ts_us\":2,\"latency_us\":8000}\n",
+                "{\"event\":\"summary\",\"responses\":2,\"elapsed_us\":500000,\"ts_us\":3}\n",
+            ),
+        )
+        .unwrap();
+        let metrics = read_metrics(&events).unwrap();
+        assert_eq!((metrics.responses, metrics.rate()), (2, 4.0));
+        assert_eq!(metrics.p99, Duration::from_millis(8));
+
+        let outcomes = [
+            CellOutcome {
+                cell: cells[0].clone(),
+                exit_code: Some(0),
+                output: dir.join("first.log"),
+                metrics: Ok(metrics),
+            },
+            CellOutcome {
+                cell: cells[1].clone(),
+                exit_code: Some(1),
+                output: dir.join("second.log"),
+                metrics: Err("the run ended before its summary".to_string()),
+            },
+        ];
+        let html = render_html(&plan, &outcomes, "20240131T023000Z");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(html.matches("<tr").count(), 3);
+        assert_eq!(html.matches("<svg").count(), 2);
+        assert!(html.contains("<tr class=\"failed\">"));
+        assert!(html.contains("10.0.0.2:8333 witness-block ×4"));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::rng::with_rng;
+use crate::transport::Connection;
+use anyhow::Result;
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::Transaction;
+use log::trace;
+use std::io::{BufReader, Write};
+use std::time::{Duration, Instant};
+
+/// Relays `txs` to the target unsolicited, in order, so parents must come before their
+/// children, and returns once the target has processed them all.
+///
+/// Seeding the target's mempool this way controls which transactions of a block a compact block
+/// run finds missing and has to request via getblocktxn. A target in initial block download or
+/// running with -blocksonly ignores relayed transactions.
+pub fn prefill_mempool(
+    stream: &mut Connection,
+    magic: u32,
+    txs: &[Transaction],
+) -> Result<Duration> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let start = Instant::now();
+    let nonce = with_rng(|rng| rng.gen());
+    let bytes: Vec<u8> = txs
+        .iter()
+        .map(|tx| NetworkMessage::Tx(tx.clone()))
+        .chain([NetworkMessage::Ping(nonce)])
+        .flat_map(|payload| serialize(&RawNetworkMessage { magic, payload }))
+        .collect();
+    stream.write_all(&bytes)?;
+    trace!("Relayed {} transactions", txs.len());
+
+    // The target handles a peer's messages in order, so its pong follows processing every
+    // transaction.
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+    loop {
+        let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+        match message.payload {
+            NetworkMessage::Pong(pong) if pong == nonce => return Ok(start.elapsed()),
+            payload => {
+                handler.handle(stream, &payload)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A part of the client's own work on a connection.
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    /// Building and serializing request messages.
+    Serialize,
+    /// Writing requests to the socket, including time blocked on a full send buffer.
+    Write,
+    /// Reading from the socket, including time waiting for the target to send.
+    Read,
+    /// Decoding received messages, checking and counting responses and answering the target's
+    /// own requests.
+    Decode,
+}
+
+/// Time the client spent in each [`Phase`], summed over every connection sharing it.
+#[derive(Debug, Default)]
+pub struct Profile([AtomicU64; 4]);
+
+/// What a [`Profile`] measured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProfileReport {
+    pub serialize: Duration,
+    pub write: Duration,
+    pub read: Duration,
+    pub decode: Duration,
+}
+
+impl ProfileReport {
+    /// Share of the receiving side's time spent decoding rather than reading. As it approaches
+    /// 1, responses are waiting in the socket for us rather than us for the target, so the
+    /// client is what limits the measured throughput.
+    pub fn decode_share(&self) -> f64 {
+        let receiving = self.read + self.decode;
+        if receiving.is_zero() {
+            return 0.0;
+        }
+        self.decode.as_secs_f64() / receiving.as_secs_f64()
+    }
+}
+
+impl Profile {
+    pub fn add(&self, phase: Phase, elapsed: Duration) {
+        self.0[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, adding the time it takes to `phase`.
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        let get =
+            |phase: Phase| Duration::from_nanos(self.0[phase as usize].load(Ordering::Relaxed));
+        ProfileReport {
+            serialize: get(Phase::Serialize),
+            write: get(Phase::Write),
+            read: get(Phase::Read),
+            decode: get(Phase::Decode),
+        }
+    }
+}
+
+/// A reader keeping the total time spent in reads of `inner`, so the time a receiving loop
+/// spends on anything else can be told apart.
+pub(crate) struct TimedReader<R> {
+    inner: R,
+    elapsed: Duration,
+}
+
+impl<R> TimedReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        TimedReader {
+            inner,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.read(buf);
+        self.elapsed += start.elapsed();
+        result
+    }
+}
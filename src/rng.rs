@@ -0,0 +1,40 @@
+use bitcoin::secp256k1::rand::rngs::StdRng;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore, SeedableRng};
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+static SEED: OnceLock<u64> = OnceLock::new();
+
+thread_local! {
+    static RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Makes every random choice derive from `seed` instead of the OS, so a run can be reproduced.
+///
+/// Must be called before any randomness is drawn; later calls are ignored.
+pub fn set_seed(seed: u64) {
+    let _ = SEED.set(seed);
+}
+
+/// Seeds the calling thread's generator from the run's seed and `stream`, so threads that draw
+/// concurrently, e.g. one per connection, each get a reproducible sequence regardless of
+/// scheduling. Without a seed this does nothing.
+pub fn seed_thread(stream: u64) {
+    if let Some(seed) = SEED.get() {
+        let rng = StdRng::seed_from_u64(seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        RNG.with(|cell| *cell.borrow_mut() = Some(rng));
+    }
+}
+
+/// Runs `f` with this thread's seeded generator, or `thread_rng` when no seed was set.
+///
+/// A thread that never called [`seed_thread`] is seeded as stream 0.
+pub fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    let Some(seed) = SEED.get() else {
+        return f(&mut thread_rng());
+    };
+    RNG.with(|cell| {
+        let mut rng = cell.borrow_mut();
+        f(rng.get_or_insert_with(|| StdRng::seed_from_u64(*seed)))
+    })
+}
@@ -0,0 +1,284 @@
+use crate::latency::parse_duration;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a scheduled job runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum When {
+    /// As soon as the daemon starts, then again after each interval.
+    Every(Duration),
+    /// Once a day, at this many seconds past midnight UTC.
+    DailyAt(u64),
+}
+
+impl When {
+    /// The first time the job runs, in seconds since the Unix epoch, for a daemon started at
+    /// `now`.
+    pub fn first(&self, now: u64) -> u64 {
+        match self {
+            When::Every(_) => now,
+            When::DailyAt(_) => self.next(now),
+        }
+    }
+
+    /// The next time the job runs after a run due at `last`, in seconds since the Unix epoch.
+    /// Runs missed while an earlier one overran are skipped, so the next is never before `now`.
+    pub fn next_after(&self, last: u64, now: u64) -> u64 {
+        match self {
+            When::Every(interval) => {
+                let interval = interval.as_secs().max(1);
+                let missed = now.saturating_sub(last) / interval;
+                last + (missed + 1) * interval
+            }
+            When::DailyAt(_) => self.next(now.max(last)),
+        }
+    }
+
+    /// The first time of day strictly after `after`, for [`When::DailyAt`].
+    fn next(&self, after: u64) -> u64 {
+        let When::DailyAt(time) = self else {
+            return after;
+        };
+        let today = after - after % SECS_PER_DAY + time;
+        if today > after {
+            today
+        } else {
+            today + SECS_PER_DAY
+        }
+    }
+}
+
+/// A workload the daemon runs on a schedule.
+#[derive(Clone, Debug)]
+pub struct Job {
+    /// Name the job's reports are filed under.
+    pub name: String,
+    pub when: When,
+    /// Arguments to run the tool with, e.g. `["--address", "10.0.0.2:8333", "spam"]`.
+    pub arguments: Vec<String>,
+}
+
+/// A finished run of a job and the reports it left.
+#[derive(Clone, Debug)]
+pub struct JobRun {
+    /// When the run started, in seconds since the Unix epoch.
+    pub started: u64,
+    pub elapsed: Duration,
+    /// Exit code of the run, or `None` if it was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// What the run printed, standard error included.
+    pub output: PathBuf,
+    /// The run's `--events` log.
+    pub events: PathBuf,
+}
+
+impl JobRun {
+    /// The run as a line of the reports directory's index.
+    pub fn to_json(&self, job: &Job) -> Value {
+        json!({
+            "job": job.name,
+            "started": utc_timestamp(self.started),
+            "elapsed_us": self.elapsed.as_micros() as u64,
+            "exit_code": self.exit_code,
+            "output": self.output,
+            "events": self.events,
+        })
+    }
+}
+
+/// A run of `job` started at `started` that failed to start or to leave its reports, as a line
+/// of the reports directory's index.
+pub fn failed_run_json(job: &Job, started: u64, error: &anyhow::Error) -> Value {
+    json!({
+        "job": job.name,
+        "started": utc_timestamp(started),
+        "error": format!("{error:#}"),
+    })
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Runs `program` with the job's arguments, writing its output and events to files in `reports`
+/// named after the job and the time it started, e.g. `nightly-20240131T023000Z.log` and `.jsonl`.
+pub fn run_job(program: &Path, job: &Job, reports: &Path) -> Result<JobRun> {
+    run_workload(program, &job.name, &job.arguments, reports)
+}
+
+/// Runs `program` with `arguments` like [`run_job`] runs a job named `name`.
+pub fn run_workload(
+    program: &Path,
+    name: &str,
+    arguments: &[String],
+    reports: &Path,
+) -> Result<JobRun> {
+    let started = unix_now()?;
+    let stem = format!("{name}-{}", utc_timestamp(started));
+    let output = reports.join(format!("{stem}.log"));
+    let events = reports.join(format!("{stem}.jsonl"));
+    let log = File::create(&output)
+        .map_err(|e| anyhow!("Could not create report {}: {e}", output.display()))?;
+    let start = Instant::now();
+    let status = Command::new(program)
+        .args(arguments)
+        .arg("--events")
+        .arg(format!("jsonl:{}", events.display()))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .status()?;
+    Ok(JobRun {
+        started,
+        elapsed: start.elapsed(),
+        exit_code: status.code(),
+        output,
+        events,
+    })
+}
+
+/// Reads the jobs in the TOML schedule at `path`, each a `[[job]]` table with a `name`, either
+/// `every = "6h"` or `at = "02:30"` (UTC), and the `arguments` to run the tool with.
+pub fn read(path: &Path) -> Result<Vec<Job>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Could not read schedule {}: {e}", path.display()))?;
+    let schedule: Map<String, Value> = toml::from_str(&contents)
+        .map_err(|e| anyhow!("Invalid schedule {}: {e}", path.display()))?;
+    let jobs = match schedule.get("job") {
+        Some(Value::Array(jobs)) if !jobs.is_empty() => jobs,
+        _ => return Err(anyhow!("Schedule {} has no [[job]]", path.display())),
+    };
+    let mut names = Vec::new();
+    jobs.iter()
+        .map(|job| {
+            let job = parse_job(job)?;
+            if names.contains(&job.name) {
+                return Err(anyhow!("Schedule has two jobs named {}", job.name));
+            }
+            names.push(job.name.clone());
+            Ok(job)
+        })
+        .collect()
+}
+
+fn parse_job(job: &Value) -> Result<Job> {
+    let name = job["name"]
+        .as_str()
+        .filter(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .ok_or_else(|| anyhow!("Every job needs a name of letters, digits, - and _"))?
+        .to_string();
+    let when = match (job["every"].as_str(), job["at"].as_str()) {
+        (Some(every), None) => When::Every(
+            parse_duration(every).map_err(|e| anyhow!("Invalid every for job {name}: {e}"))?,
+        ),
+        (None, Some(at)) => When::DailyAt(
+            parse_time_of_day(at).ok_or_else(|| anyhow!("Invalid at for job {name}: {at}"))?,
+        ),
+        _ => return Err(anyhow!("Job {name} needs either every or at")),
+    };
+    if matches!(when, When::Every(interval) if interval < Duration::from_secs(1)) {
+        return Err(anyhow!(
+            "Job {name} cannot run more often than every second"
+        ));
+    }
+    let arguments = job["arguments"]
+        .as_array()
+        .and_then(|arguments| {
+            arguments
+                .iter()
+                .map(|argument| argument.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| anyhow!("Job {name} needs its arguments as a list of strings"))?;
+    Ok(Job {
+        name,
+        when,
+        arguments,
+    })
+}
+
+/// Parses `HH:MM` into seconds past midnight.
+fn parse_time_of_day(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+/// Formats seconds since the Unix epoch as a compact UTC timestamp, e.g. `20240131T023000Z`, that
+/// sorts in time order in file names.
+pub fn utc_timestamp(secs: u64) -> String {
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = (secs / SECS_PER_DAY) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = secs % SECS_PER_DAY;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduled_jobs_run_at_their_times_and_skip_missed_runs() {
+        // 2024-02-29 23:59:59 UTC.
+        let now = 1_709_251_199;
+        assert_eq!(utc_timestamp(now), "20240229T235959Z");
+        let nightly = When::DailyAt(2 * 3600 + 30 * 60);
+        assert_eq!(utc_timestamp(nightly.first(now)), "20240301T023000Z");
+        let at = nightly.first(now);
+        assert_eq!(nightly.next_after(at, at + 60), at + 86_400);
+
+        let hourly = When::Every(Duration::from_secs(3600));
+        assert_eq!(hourly.first(now), now);
+        assert_eq!(hourly.next_after(now, now + 60), now + 3600);
+        // A run overrunning by two hours skips the runs it overlapped.
+        assert_eq!(hourly.next_after(now, now + 7300), now + 3 * 3600);
+    }
+
+    #[test]
+    fn jobs_that_fail_to_run_are_indexed_with_their_error() {
+        let job = Job {
+            name: "nightly".to_string(),
+            when: When::Every(Duration::from_secs(3600)),
+            arguments: Vec::new(),
+        };
+        let reports = Path::new("/nonexistent/reports");
+        let error = run_job(Path::new("/bin/true"), &job, reports).unwrap_err();
+        let line = failed_run_json(&job, 1_709_251_199, &error);
+        assert_eq!(line["job"], "nightly");
+        assert_eq!(line["started"], "20240229T235959Z");
+        assert!(line["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Could not create report /nonexistent/reports/nightly-"));
+    }
+}
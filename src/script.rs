@@ -0,0 +1,130 @@
+use crate::handler::MessageHandler;
+use crate::shell::{describe, ShellCommand};
+use crate::transport::Connection;
+use crate::{perform_handshake, rng, PeerVersion, MESSAGE_HEADER_SIZE};
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::secp256k1::rand::Rng;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope};
+use std::io::{BufReader, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs the rhai script `source` over `stream` after the handshake.
+///
+/// Besides the rhai builtins, the script can use:
+///
+/// - `peer`, a map of the target's version message
+/// - `send(command)` to send a shell command, e.g. `send("getdata block <hash>")` or
+///   `send("ping")`
+/// - `receive(timeout_ms)`, returning the next message the target sends as a map with its
+///   `command`, a `summary` and the `payload` in hex, or `()` if none arrives in time
+/// - `wait_for(command, timeout_ms)`, like `receive` but skipping other messages
+/// - `sleep(ms)`
+///
+/// Pings and requests from the target are answered in the background.
+pub fn run_script(mut stream: Connection, magic: u32, source: &str) -> Result<()> {
+    let handler = MessageHandler::new(magic, None);
+    let peer = PeerVersion::from(&perform_handshake(&mut stream, &handler)?);
+
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let (tx, rx) = channel();
+    {
+        let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?);
+        let writer = writer.clone();
+        thread::spawn(move || -> Result<()> {
+            loop {
+                let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+                handler.handle(&mut *writer.lock().unwrap(), &message.payload)?;
+                if tx.send(message).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+    }
+    let rx = Rc::new(rx);
+
+    let mut engine = Engine::new();
+    engine.register_fn(
+        "send",
+        move |line: &str| -> Result<(), Box<EvalAltResult>> {
+            let payload = match ShellCommand::parse(line).map_err(|e| e.to_string())? {
+                Some(ShellCommand::GetData(inventory)) => NetworkMessage::GetData(vec![inventory]),
+                Some(ShellCommand::Ping) => NetworkMessage::Ping(rng::with_rng(|rng| rng.gen())),
+                Some(ShellCommand::Mempool) => NetworkMessage::MemPool,
+                Some(ShellCommand::Raw(bytes)) => {
+                    return write(&writer, &bytes);
+                }
+                _ => return Err(format!("Cannot send {line:?}").into()),
+            };
+            write(&writer, &serialize(&RawNetworkMessage { magic, payload }))
+        },
+    );
+    {
+        let rx = rx.clone();
+        engine.register_fn("receive", move |timeout_ms: i64| {
+            receive(&rx, None, timeout_ms)
+        });
+    }
+    engine.register_fn("wait_for", move |command: &str, timeout_ms: i64| {
+        receive(&rx, Some(command), timeout_ms)
+    });
+    engine.register_fn("sleep", |ms: i64| {
+        thread::sleep(Duration::from_millis(ms.max(0) as u64))
+    });
+
+    let mut peer_map = Map::new();
+    peer_map.insert("version".into(), (peer.version as i64).into());
+    peer_map.insert("services".into(), (peer.services.to_u64() as i64).into());
+    peer_map.insert("user_agent".into(), peer.user_agent.into());
+    peer_map.insert("start_height".into(), (peer.start_height as i64).into());
+    let mut scope = Scope::new();
+    scope.push_constant("peer", peer_map);
+
+    let result = engine.run_with_scope(&mut scope, source);
+    stream.shutdown();
+    result.map_err(|e| anyhow!("Script failed: {e}"))
+}
+
+fn write(writer: &Mutex<Connection>, bytes: &[u8]) -> Result<(), Box<EvalAltResult>> {
+    writer
+        .lock()
+        .unwrap()
+        .write_all(bytes)
+        .map_err(|e| format!("Could not send: {e}").into())
+}
+
+/// Waits up to `timeout_ms` for the next message, or the next with `command` if given.
+fn receive(
+    rx: &Receiver<RawNetworkMessage>,
+    command: Option<&str>,
+    timeout_ms: i64,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let message = match rx.recv_timeout(timeout) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => return Ok(Dynamic::UNIT),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err("Target closed the connection".into())
+            }
+        };
+        if command.is_some_and(|command| command != message.cmd()) {
+            continue;
+        }
+        let mut map = Map::new();
+        map.insert("command".into(), message.cmd().to_string().into());
+        map.insert("summary".into(), describe(&message.payload).into());
+        map.insert(
+            "payload".into(),
+            serialize(&message)[MESSAGE_HEADER_SIZE..].to_hex().into(),
+        );
+        return Ok(map.into());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::handler::MessageHandler;
+use crate::transport::Connection;
+use crate::{perform_handshake, rng, InventoryType, PeerVersion};
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::{BlockHash, Txid};
+use std::collections::HashMap;
+use std::io::{stdout, BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+const HELP: &str = "\
+getdata <block|witness-block|compact-block|tx|witness-tx> <hash>
+ping
+mempool
+raw <hex>     send a whole serialized message, header included, as is
+help
+quit";
+
+/// A line typed into the shell.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellCommand {
+    GetData(Inventory),
+    Ping,
+    Mempool,
+    Raw(Vec<u8>),
+    Help,
+    Quit,
+}
+
+impl ShellCommand {
+    /// Parses a line, returning `None` for a blank one.
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            [] => return Ok(None),
+            ["getdata", inventory_type, hash] => {
+                let inventory = match inventory_type.parse()? {
+                    InventoryType::Block => Inventory::Block(BlockHash::from_hex(hash)?),
+                    InventoryType::WitnessBlock => {
+                        Inventory::WitnessBlock(BlockHash::from_hex(hash)?)
+                    }
+                    InventoryType::CompactBlock => {
+                        Inventory::CompactBlock(BlockHash::from_hex(hash)?)
+                    }
+                    InventoryType::Tx => Inventory::Transaction(Txid::from_hex(hash)?),
+                    InventoryType::WitnessTx => {
+                        Inventory::WitnessTransaction(Txid::from_hex(hash)?)
+                    }
+                };
+                ShellCommand::GetData(inventory)
+            }
+            ["ping"] => ShellCommand::Ping,
+            ["mempool"] => ShellCommand::Mempool,
+            ["raw", hex] => ShellCommand::Raw(Vec::from_hex(hex)?),
+            ["help"] => ShellCommand::Help,
+            ["quit" | "exit"] => ShellCommand::Quit,
+            _ => return Err(anyhow!("Unknown command {line:?}, try help")),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Performs the handshake over `stream`, then sends the commands read from `input` one line at a
+/// time, printing each message the target sends until `input` ends or a quit command.
+pub fn run_shell(mut stream: Connection, magic: u32, input: impl BufRead) -> Result<()> {
+    let handler = MessageHandler::new(magic, None);
+    let peer = PeerVersion::from(&perform_handshake(&mut stream, &handler)?);
+    println!(
+        "Connected to {} (version {}, height {}). Type help for commands",
+        peer.user_agent, peer.version, peer.start_height
+    );
+
+    let pings_sent = Arc::new(Mutex::new(HashMap::new()));
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let reader = stream.try_clone()?;
+        let writer = writer.clone();
+        let pings_sent = pings_sent.clone();
+        thread::spawn(move || {
+            if let Err(e) = print_messages(reader, &writer, &handler, &pings_sent) {
+                println!("\nConnection closed: {e}");
+            }
+        });
+    }
+
+    prompt()?;
+    for line in input.lines() {
+        let payload = match ShellCommand::parse(&line?) {
+            Ok(None) => None,
+            Ok(Some(ShellCommand::GetData(inventory))) => {
+                Some(NetworkMessage::GetData(vec![inventory]))
+            }
+            Ok(Some(ShellCommand::Ping)) => {
+                let nonce = rng::with_rng(|rng| rng.gen());
+                pings_sent.lock().unwrap().insert(nonce, Instant::now());
+                Some(NetworkMessage::Ping(nonce))
+            }
+            Ok(Some(ShellCommand::Mempool)) => Some(NetworkMessage::MemPool),
+            Ok(Some(ShellCommand::Raw(bytes))) => {
+                writer.lock().unwrap().write_all(&bytes)?;
+                None
+            }
+            Ok(Some(ShellCommand::Help)) => {
+                println!("{HELP}");
+                None
+            }
+            Ok(Some(ShellCommand::Quit)) => break,
+            Err(e) => {
+                println!("{e}");
+                None
+            }
+        };
+        if let Some(payload) = payload {
+            let message = RawNetworkMessage { magic, payload };
+            writer.lock().unwrap().write_all(&serialize(&message))?;
+        }
+        prompt()?;
+    }
+    stream.shutdown();
+    Ok(())
+}
+
+fn prompt() -> Result<()> {
+    print!("> ");
+    stdout().flush()?;
+    Ok(())
+}
+
+fn print_messages(
+    reader: Connection,
+    writer: &Mutex<Connection>,
+    handler: &MessageHandler,
+    pings_sent: &Mutex<HashMap<u64, Instant>>,
+) -> Result<()> {
+    let mut reader = BufReader::with_capacity(MAX_MSG_SIZE, reader);
+    loop {
+        let message = RawNetworkMessage::consensus_decode(&mut reader)?;
+        let description = match &message.payload {
+            NetworkMessage::Pong(nonce) => match pings_sent.lock().unwrap().remove(nonce) {
+                Some(sent_at) => format!("pong {nonce} after {:.2?}", sent_at.elapsed()),
+                None => format!("pong {nonce}"),
+            },
+            payload => describe(payload),
+        };
+        // Replace the prompt with the message and print the prompt again below it.
+        println!("\r< {description}");
+        prompt()?;
+        handler.handle(&mut *writer.lock().unwrap(), &message.payload)?;
+    }
+}
+
+/// One line summarizing `message`, leaving out the bulk of blocks and transactions.
+pub fn describe(message: &NetworkMessage) -> String {
+    match message {
+        NetworkMessage::Block(block) => format!(
+            "block {} ({} txs, {} bytes)",
+            block.block_hash(),
+            block.txdata.len(),
+            serialize(block).len()
+        ),
+        NetworkMessage::Tx(tx) => format!(
+            "tx {} ({} inputs, {} outputs, {} bytes)",
+            tx.txid(),
+            tx.input.len(),
+            tx.output.len(),
+            serialize(tx).len()
+        ),
+        NetworkMessage::CmpctBlock(compact) => format!(
+            "cmpctblock {} ({} short ids, {} prefilled txs)",
+            compact.compact_block.header.block_hash(),
+            compact.compact_block.short_ids.len(),
+            compact.compact_block.prefilled_txs.len()
+        ),
+        NetworkMessage::BlockTxn(block_txn) => format!(
+            "blocktxn {} ({} txs)",
+            block_txn.transactions.block_hash,
+            block_txn.transactions.transactions.len()
+        ),
+        NetworkMessage::Headers(headers) => match headers.last() {
+            Some(last) => format!(
+                "headers ({} headers, last {})",
+                headers.len(),
+                last.block_hash()
+            ),
+            None => String::from("headers (0 headers)"),
+        },
+        NetworkMessage::Inv(inventory)
+        | NetworkMessage::GetData(inventory)
+        | NetworkMessage::NotFound(inventory) => {
+            let entries: Vec<_> = inventory.iter().map(|entry| format!("{entry:?}")).collect();
+            format!("{} [{}]", message.cmd(), entries.join(", "))
+        }
+        NetworkMessage::Unknown { command, payload } => {
+            format!("{command} (unknown, payload {})", payload.to_hex())
+        }
+        message => format!("{message:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+
+    #[test]
+    fn shell_lines_parse_into_commands() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let hash = genesis.block_hash();
+        assert_eq!(ShellCommand::parse("  ").unwrap(), None);
+        assert_eq!(
+            ShellCommand::parse(&format!("getdata witness-block {hash}")).unwrap(),
+            Some(ShellCommand::GetData(Inventory::WitnessBlock(hash)))
+        );
+        assert_eq!(
+            ShellCommand::parse("raw f9beb4d9").unwrap(),
+            Some(ShellCommand::Raw(vec![0xf9, 0xbe, 0xb4, 0xd9]))
+        );
+        assert_eq!(
+            ShellCommand::parse("exit").unwrap(),
+            Some(ShellCommand::Quit)
+        );
+        assert!(ShellCommand::parse("getdata block nothex").is_err());
+        assert!(ShellCommand::parse("getdata filter 00").is_err());
+        assert!(ShellCommand::parse("ping twice").is_err());
+
+        assert_eq!(
+            describe(&NetworkMessage::Block(genesis)),
+            format!("block {hash} (1 txs, 285 bytes)")
+        );
+        assert_eq!(
+            describe(&NetworkMessage::Unknown {
+                command: "spam".parse().unwrap(),
+                payload: vec![0xab],
+            }),
+            "spam (unknown, payload ab)"
+        );
+    }
+}
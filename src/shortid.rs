@@ -0,0 +1,109 @@
+use crate::generator::{Inspector, InventoryRequests};
+use crate::tx::read_hex_file;
+use crate::InventoryType;
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::network::message_compact_blocks::CmpctBlock;
+use bitcoin::util::bip152::{HeaderAndShortIds, ShortId};
+use bitcoin::{Transaction, Wtxid};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How the short IDs of the compact blocks received compare to a snapshot of transactions.
+#[derive(Clone, Debug, Default)]
+pub struct ShortIdReport {
+    pub blocks: usize,
+    /// Short IDs matching the SipHash of a snapshot transaction's wtxid.
+    pub matched: usize,
+    /// Short IDs matching no snapshot transaction.
+    pub unmatched: usize,
+    /// Short IDs appearing more than once in the same compact block.
+    pub duplicates: usize,
+    /// Compact blocks whose prefilled transaction indexes point past the end of the block, or
+    /// that do not prefill the coinbase.
+    pub invalid_prefilled: usize,
+}
+
+/// Requests compact blocks and checks the short IDs of each one received against the BIP152
+/// short IDs of a snapshot of transactions, e.g. the target's mempool or the block's own
+/// transactions, in which case every short ID should match.
+#[derive(Debug)]
+pub struct ShortIdVerifier {
+    requests: InventoryRequests,
+    wtxids: Vec<Wtxid>,
+    report: Mutex<ShortIdReport>,
+}
+
+impl ShortIdVerifier {
+    pub fn new(txs: &[Transaction]) -> Self {
+        Self {
+            requests: InventoryRequests::new(vec![InventoryType::CompactBlock]),
+            wtxids: txs.iter().map(Transaction::wtxid).collect(),
+            report: Mutex::default(),
+        }
+    }
+
+    /// Loads the snapshot from a file with one hex encoded transaction per line.
+    pub fn from_hex_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(&read_hex_file(path)?))
+    }
+
+    pub fn report(&self) -> ShortIdReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn verify(&self, block: &HeaderAndShortIds) {
+        let keys = ShortId::calculate_siphash_keys(&block.header, block.nonce);
+        let expected: HashSet<_> = self
+            .wtxids
+            .iter()
+            .map(|wtxid| ShortId::with_siphash_keys(wtxid, keys))
+            .collect();
+        let mut seen = HashSet::with_capacity(block.short_ids.len());
+
+        let mut report = self.report.lock().unwrap();
+        report.blocks += 1;
+        for short_id in &block.short_ids {
+            if !seen.insert(short_id) {
+                report.duplicates += 1;
+            }
+            if expected.contains(short_id) {
+                report.matched += 1;
+            } else {
+                report.unmatched += 1;
+            }
+        }
+
+        let len = block.short_ids.len() + block.prefilled_txs.len();
+        let mut index = None;
+        let mut valid = true;
+        for prefilled in &block.prefilled_txs {
+            let next = index.map_or(0, |index| index + 1) + prefilled.idx as usize;
+            valid &= next < len && (next != 0 || prefilled.tx.is_coin_base());
+            index = Some(next);
+        }
+        valid &= block
+            .prefilled_txs
+            .first()
+            .is_some_and(|first| first.idx == 0);
+        if !valid {
+            report.invalid_prefilled += 1;
+        }
+    }
+}
+
+impl Inspector for ShortIdVerifier {
+    type Inner = InventoryRequests;
+
+    fn inner(&self) -> &InventoryRequests {
+        &self.requests
+    }
+
+    fn inspect(&self, _command: &str, payload: &[u8]) -> Result<()> {
+        let compact: CmpctBlock = deserialize(payload)
+            .map_err(|e| anyhow!("Target sent an undecodable cmpctblock: {e}"))?;
+        self.verify(&compact.compact_block);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::frames::{timed_out, Frames};
+use crate::handler::MessageHandler;
+use crate::perform_handshake;
+use crate::transport::Connection;
+use anyhow::Result;
+use bitcoin::consensus::serialize;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::{Transaction, Txid};
+use log::trace;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, Write};
+use std::time::{Duration, Instant};
+
+/// Most entries we put in a single inv message.
+pub const MAX_INV_ENTRIES: usize = 1000;
+
+/// What the target did with transactions we announced but withheld.
+#[derive(Debug, Default)]
+pub struct StallReport {
+    pub announced: usize,
+    /// Every getdata entry for one of our transactions, with the time since we announced.
+    pub requests: Vec<(Duration, Txid)>,
+    pub served: usize,
+    /// Time since we announced at which the target disconnected us, if it did.
+    pub disconnected_after: Option<Duration>,
+}
+
+/// Announces `txs` via inv and withholds them when the target requests them, or serves them only
+/// after `serve_delay`, observing the target's requests until `observe` has passed.
+pub fn announce_and_withhold(
+    stream: &mut Connection,
+    magic: u32,
+    txs: &[Transaction],
+    serve_delay: Option<Duration>,
+    observe: Duration,
+) -> Result<StallReport> {
+    let handler = MessageHandler::new(magic, None);
+    perform_handshake(stream, &handler)?;
+
+    let txs: HashMap<Txid, &Transaction> = txs.iter().map(|tx| (tx.txid(), tx)).collect();
+    let mut report = StallReport {
+        announced: txs.len(),
+        ..Default::default()
+    };
+    let txids: Vec<_> = txs.keys().copied().collect();
+    for chunk in txids.chunks(MAX_INV_ENTRIES) {
+        let message = RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::Inv(
+                chunk
+                    .iter()
+                    .map(|txid| Inventory::Transaction(*txid))
+                    .collect(),
+            ),
+        };
+        stream.write_all(&serialize(&message))?;
+    }
+    let start = Instant::now();
+    trace!("Announced {} transactions", txids.len());
+
+    // Frames keep what arrived of a message across read timeouts.
+    let mut frames = Frames::new(BufReader::with_capacity(MAX_MSG_SIZE, stream.try_clone()?));
+    let mut pending: VecDeque<(Instant, Txid)> = VecDeque::new();
+    let deadline = start + observe;
+    loop {
+        let now = Instant::now();
+        while let Some((due, txid)) = pending.front().copied() {
+            if due > now {
+                break;
+            }
+            pending.pop_front();
+            let message = RawNetworkMessage {
+                magic,
+                payload: NetworkMessage::Tx(txs[&txid].clone()),
+            };
+            stream.write_all(&serialize(&message))?;
+            report.served += 1;
+        }
+
+        let wake = pending
+            .front()
+            .map_or(deadline, |(due, _)| (*due).min(deadline));
+        let timeout = wake.saturating_duration_since(now);
+        if now >= deadline {
+            break;
+        }
+        stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let message = match frames.next().map(|frame| frame?.decode()) {
+            Some(Ok(message)) => message,
+            Some(Err(e)) if timed_out(&e) => {
+                continue;
+            }
+            _ => {
+                report.disconnected_after = Some(start.elapsed());
+                break;
+            }
+        };
+        match message.payload {
+            NetworkMessage::GetData(inventory) => {
+                for entry in inventory {
+                    let (Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid)) =
+                        entry
+                    else {
+                        continue;
+                    };
+                    if !txs.contains_key(&txid) {
+                        continue;
+                    }
+                    report.requests.push((start.elapsed(), txid));
+                    if let Some(delay) = serve_delay {
+                        pending.push_back((Instant::now() + delay, txid));
+                    }
+                }
+            }
+            payload => {
+                handler.handle(stream, &payload)?;
+            }
+        }
+    }
+    Ok(report)
+}
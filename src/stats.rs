@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+/// Two-sided p-value of the Mann-Whitney U test that samples `a` and `b` come from the same
+/// distribution, using the normal approximation with a tie correction. Returns 1 if either
+/// sample is empty or every value is tied.
+pub fn mann_whitney(a: &[f64], b: &[f64]) -> f64 {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    if a.is_empty() || b.is_empty() {
+        return 1.0;
+    }
+    let mut pooled: Vec<(f64, bool)> = a
+        .iter()
+        .map(|value| (*value, true))
+        .chain(b.iter().map(|value| (*value, false)))
+        .collect();
+    pooled.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // Tied values share the average of their ranks.
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < pooled.len() {
+        let end = start
+            + pooled[start..]
+                .iter()
+                .take_while(|(value, _)| *value == pooled[start].0)
+                .count();
+        let rank = (start + end + 1) as f64 / 2.0;
+        let in_a = pooled[start..end].iter().filter(|(_, in_a)| *in_a).count();
+        rank_sum += rank * in_a as f64;
+        let tied = (end - start) as f64;
+        ties += tied.powi(3) - tied;
+        start = end;
+    }
+
+    let n = n1 + n2;
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return 1.0;
+    }
+    let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+    (2.0 * (1.0 - normal_cdf(z))).min(1.0)
+}
+
+/// Standard normal cumulative distribution, via the Abramowitz and Stegun approximation of erf
+/// (formula 7.1.26, error below 1.5e-7).
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    if z >= 0.0 {
+        (1.0 + erf) / 2.0
+    } else {
+        (1.0 - erf) / 2.0
+    }
+}
+
+/// Nearest-rank percentile of sorted durations.
+pub fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mann_whitney_separates_shifted_samples_but_not_equal_ones() {
+        let a: Vec<_> = (0..50).map(f64::from).collect();
+        let shifted: Vec<_> = a.iter().map(|value| value + 25.0).collect();
+        assert!(mann_whitney(&a, &shifted) < 0.001);
+        assert!(mann_whitney(&a, &a) > 0.99);
+        assert_eq!(mann_whitney(&[1.0; 5], &[1.0; 5]), 1.0);
+        assert_eq!(mann_whitney(&a, &[]), 1.0);
+    }
+}
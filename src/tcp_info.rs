@@ -0,0 +1,92 @@
+use crate::transport::Connection;
+use crate::{EventKind, EventSender};
+use anyhow::Result;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often a connection's TCP state is sampled.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The kernel's view of a TCP connection at one point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpInfo {
+    /// Smoothed round trip time.
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// Segments retransmitted over the connection's lifetime.
+    pub total_retransmits: u32,
+    /// Congestion window, in segments.
+    pub cwnd: u32,
+    /// Bytes written but not yet acknowledged by the target.
+    pub send_queue: u32,
+}
+
+/// Reads the TCP state of the socket underneath `connection`.
+#[cfg(target_os = "linux")]
+pub fn sample(connection: &Connection) -> io::Result<TcpInfo> {
+    let fd = connection.raw_fd();
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: `info` and `len` are valid for writes and `len` holds the size of `info`.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut send_queue: libc::c_int = 0;
+    // SAFETY: TIOCOUTQ writes a single int through the pointer.
+    if unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut send_queue) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpInfo {
+        rtt: Duration::from_micros(info.tcpi_rtt.into()),
+        rtt_var: Duration::from_micros(info.tcpi_rttvar.into()),
+        total_retransmits: info.tcpi_total_retrans,
+        cwnd: info.tcpi_snd_cwnd,
+        send_queue: send_queue.max(0) as u32,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_connection: &Connection) -> io::Result<TcpInfo> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Stops its sampler when dropped, releasing the sampler's handle on the connection.
+pub struct Sampler(Arc<AtomicBool>);
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sends a [`EventKind::TcpInfo`] for `connection` every [`SAMPLE_INTERVAL`] until the returned
+/// [`Sampler`] is dropped or sampling fails, e.g. on platforms without TCP_INFO.
+pub fn spawn_sampler(connection: &Connection, events: EventSender) -> Result<Sampler> {
+    let connection = connection.try_clone()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let sampler = Sampler(stopped.clone());
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            let Ok(info) = sample(&connection) else {
+                return;
+            };
+            if !events.send(EventKind::TcpInfo(info)) {
+                return;
+            }
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+    });
+    Ok(sampler)
+}
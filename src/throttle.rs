@@ -0,0 +1,36 @@
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A reader that consumes at most `rate` bytes per second on average, so the target's send
+/// buffer for us fills up as it would for a slow peer.
+pub struct Throttled<R> {
+    inner: R,
+    rate: u64,
+    start: Instant,
+    read: u64,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, rate: u64) -> Self {
+        Throttled {
+            inner,
+            rate: rate.max(1),
+            start: Instant::now(),
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let due = self.start + Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        // Take at most a tenth of a second's worth at once, so reads stay evenly spread.
+        let max = (self.rate / 10).max(1) as usize;
+        let len = buf.len().min(max);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,405 @@
+use crate::latency::{DelayLine, Latency};
+use anyhow::{anyhow, Error, Result};
+use log::{debug, info};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind},
+    sync::Mutex,
+    time::Instant,
+};
+
+/// How long to wait for a connection attempt before racing it against the next address, as
+/// recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a TLS read holds the stream before letting a writer on another thread in.
+#[cfg(feature = "tls")]
+const TLS_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What carries the P2P framing to the target.
+#[derive(Clone, Default)]
+pub struct Transport {
+    /// HTTP proxy to tunnel the TCP connection through.
+    proxy: Option<Proxy>,
+    /// Delay added to our reads and writes, to emulate a distant peer.
+    latency: Option<Latency>,
+    /// TLS over the TCP connection, for targets behind a TLS-terminating tunnel or port forward.
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct Tls {
+    config: Arc<rustls::ClientConfig>,
+    /// Server name to send and verify instead of the target's host.
+    sni: Option<String>,
+}
+
+/// An HTTP proxy that supports CONNECT, given as `[http://][user:password@]host:port`.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    address: String,
+    /// Value of the Proxy-Authorization header, if credentials were given.
+    authorization: Option<String>,
+}
+
+impl FromStr for Proxy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.strip_prefix("http://").unwrap_or(s).trim_end_matches('/');
+        let (credentials, address) = match s.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, s),
+        };
+        if !address.contains(':') {
+            return Err(anyhow!("Proxy {address} has no port"));
+        }
+        Ok(Proxy {
+            address: address.to_string(),
+            authorization: credentials
+                .map(|credentials| format!("Basic {}", base64::encode(credentials))),
+        })
+    }
+}
+
+impl fmt::Display for Proxy {
+    /// Shows the proxy without its credentials, so it can be recorded in reports.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.authorization {
+            Some(_) => write!(f, "http://<redacted>@{}", self.address),
+            None => write!(f, "http://{}", self.address),
+        }
+    }
+}
+
+impl Proxy {
+    /// Connects to the proxy and asks it to open a tunnel to `target`.
+    fn connect(&self, target: &str, timeout: Option<Duration>) -> Result<TcpStream> {
+        let mut stream = tcp_connect(&self.address, timeout)?;
+        stream.set_read_timeout(timeout)?;
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read the response a byte at a time, so nothing the target sends through the tunnel
+        // right away is lost.
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(anyhow!("Proxy {} closed the connection", self.address));
+            }
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => {}
+            _ => return Err(anyhow!("Proxy {} refused tunnel: {status}", self.address)),
+        }
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+impl Transport {
+    /// A TLS transport trusting the webpki roots, plus the certificates in the PEM file `ca_file`
+    /// for tunnels with a private CA.
+    #[cfg(feature = "tls")]
+    pub fn tls(sni: Option<String>, ca_file: Option<&str>) -> Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        if let Some(path) = ca_file {
+            for der in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))? {
+                roots.add(&rustls::Certificate(der))?;
+            }
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Transport {
+            tls: Some(Tls {
+                config: Arc::new(config),
+                sni,
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// Tunnels connections through `proxy`.
+    pub fn via_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Delays everything sent and received by `latency`.
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    pub fn connect(&self, address: &str) -> Result<Connection> {
+        self.connect_with(address, None)
+    }
+
+    /// Like [`Transport::connect`], but gives up on establishing the connection after `timeout`.
+    pub fn connect_timeout(&self, address: &str, timeout: Duration) -> Result<Connection> {
+        self.connect_with(address, Some(timeout))
+    }
+
+    fn connect_with(&self, address: &str, timeout: Option<Duration>) -> Result<Connection> {
+        let connection = self.establish(address, timeout)?;
+        match self.latency {
+            Some(latency) => Ok(Connection::Delayed(Arc::new(DelayLine::new(
+                connection, latency,
+            )?))),
+            None => Ok(connection),
+        }
+    }
+
+    fn establish(&self, address: &str, timeout: Option<Duration>) -> Result<Connection> {
+        let stream = match &self.proxy {
+            Some(proxy) => proxy.connect(address, timeout)?,
+            None => tcp_connect(address, timeout)?,
+        };
+        #[cfg(feature = "tls")]
+        if let Some(Tls { config, sni }) = &self.tls {
+            let host = match sni {
+                Some(sni) => sni.as_str(),
+                None => host(address),
+            };
+            let name = rustls::ServerName::try_from(host)
+                .map_err(|_| anyhow!("Invalid TLS server name {host}"))?;
+            let mut tls = rustls::StreamOwned::new(
+                rustls::ClientConnection::new(config.clone(), name)?,
+                stream,
+            );
+            while tls.conn.is_handshaking() {
+                tls.conn.complete_io(&mut tls.sock)?;
+            }
+            tls.sock.set_read_timeout(Some(TLS_POLL_INTERVAL))?;
+            return Ok(Connection::Tls(Arc::new(TlsStream {
+                stream: Mutex::new(tls),
+                read_timeout: Mutex::new(None),
+            })));
+        }
+        Ok(Connection::Tcp(stream))
+    }
+}
+
+/// Connects to `address`, racing its resolved addresses per RFC 8305 (happy eyeballs): IPv6
+/// and IPv4 addresses are tried alternately, IPv6 first, starting a new attempt whenever the
+/// previous one fails or has not succeeded within [`CONNECTION_ATTEMPT_DELAY`], and the first to
+/// connect wins.
+fn tcp_connect(address: &str, timeout: Option<Duration>) -> Result<TcpStream> {
+    let addrs = interleave_families(address.to_socket_addrs()?.collect());
+    let connect = move |addr: SocketAddr| match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    };
+    match addrs.as_slice() {
+        [] => return Err(anyhow!("Could not resolve {address}")),
+        [addr] => return Ok(connect(*addr)?),
+        _ => {}
+    }
+
+    let (tx, rx) = channel();
+    let mut pending = 0;
+    let mut last_error = None;
+    let mut attempts = addrs.into_iter().peekable();
+    while pending > 0 || attempts.peek().is_some() {
+        if let Some(addr) = attempts.next() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _ = tx.send((addr, connect(addr)));
+            });
+            pending += 1;
+        }
+        let result = if attempts.peek().is_some() {
+            match rx.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        } else {
+            rx.recv()?
+        };
+        pending -= 1;
+        match result {
+            (addr, Ok(stream)) => {
+                let family = if addr.is_ipv6() { "IPv6" } else { "IPv4" };
+                info!("Connected to {address} over {family} ({addr})");
+                return Ok(stream);
+            }
+            (addr, Err(e)) => {
+                debug!("Could not connect to {addr}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.map_or_else(|| anyhow!("Could not connect to {address}"), Into::into))
+}
+
+/// Orders addresses alternating between IPv6 and IPv4, starting with IPv6, keeping the
+/// resolver's order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut ipv6, mut ipv4): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut interleaved = Vec::with_capacity(ipv6.len() + ipv4.len());
+    while let Some(addr) = ipv6.pop_front() {
+        interleaved.push(addr);
+        interleaved.extend(ipv4.pop_front());
+    }
+    interleaved.extend(ipv4);
+    interleaved
+}
+
+/// The host part of a `host:port` address, without brackets around IPv6 addresses.
+#[cfg(feature = "tls")]
+fn host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// A connection to the target. Clones share the underlying stream, so one thread can read
+/// while another writes.
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Arc<TlsStream>),
+    Delayed(Arc<DelayLine>),
+}
+
+/// A TLS session shared between clones of a [`Connection`].
+///
+/// Reads poll the socket and release the session in between, so a reader waiting for data
+/// delays a concurrent writer by at most [`TLS_POLL_INTERVAL`].
+#[cfg(feature = "tls")]
+pub struct TlsStream {
+    stream: Mutex<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Connection {
+    pub fn try_clone(&self) -> Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => Ok(Connection::Tcp(stream.try_clone()?)),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Ok(Connection::Tls(stream.clone())),
+            Connection::Delayed(stream) => Ok(Connection::Delayed(stream.clone())),
+        }
+    }
+
+    /// The socket underneath, for inspecting its TCP state.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn raw_fd(&self) -> std::os::fd::RawFd {
+        use std::os::fd::AsRawFd;
+        match self {
+            Connection::Tcp(stream) => stream.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.stream.lock().unwrap().sock.as_raw_fd(),
+            Connection::Delayed(stream) => stream.inner().raw_fd(),
+        }
+    }
+
+    /// Closes both directions, waking up any thread blocked reading from a clone.
+    pub fn shutdown(&self) {
+        match self {
+            Connection::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => {
+                let _ = stream.stream.lock().unwrap().sock.shutdown(Shutdown::Both);
+            }
+            Connection::Delayed(stream) => stream.inner().shutdown(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => {
+                *stream.read_timeout.lock().unwrap() = timeout;
+                Ok(())
+            }
+            Connection::Delayed(stream) => {
+                stream.set_read_timeout(timeout);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => {
+                let deadline = stream
+                    .read_timeout
+                    .lock()
+                    .unwrap()
+                    .map(|timeout| Instant::now() + timeout);
+                loop {
+                    match stream.stream.lock().unwrap().read(buf) {
+                        Err(e)
+                            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                        {
+                            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                                return Err(e);
+                            }
+                        }
+                        result => return result,
+                    }
+                }
+            }
+            Connection::Delayed(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.stream.lock().unwrap().write(buf),
+            Connection::Delayed(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.stream.lock().unwrap().flush(),
+            // Held back data is delivered by the delay line's writer thread in its own time.
+            Connection::Delayed(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::{
+    OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::rng::with_rng;
+
+/// Value of every output we construct, comfortably above the dust limit.
+const OUTPUT_VALUE: u64 = 10_000;
+
+/// Builds a standard-looking transaction spending output 0 of `parent`, with a dummy P2WPKH
+/// witness and a single P2WPKH output to a random key hash.
+///
+/// The signature is garbage, so the transaction only gets as far as the target's checks that
+/// run before script validation, e.g. the orphanage when `parent` is unknown.
+pub fn spending_transaction(parent: Txid) -> Transaction {
+    let (signature, mut pubkey, key_hash) = with_rng(|rng| {
+        let signature: Vec<u8> = (0..72).map(|_| rng.gen()).collect();
+        let pubkey: Vec<u8> = (0..33).map(|_| rng.gen()).collect();
+        let key_hash: [u8; 20] = rng.gen();
+        (signature, pubkey, key_hash)
+    });
+    pubkey[0] = 0x02;
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent, 0),
+            script_sig: Script::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::from_vec(vec![signature, pubkey]),
+        }],
+        output: vec![TxOut {
+            value: OUTPUT_VALUE,
+            script_pubkey: Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::from_inner(key_hash)),
+        }],
+    }
+}
+
+/// A random txid the target is practically guaranteed not to know.
+pub fn unknown_txid() -> Txid {
+    Txid::from_inner(with_rng(|rng| rng.gen()))
+}
+
+/// Loads transactions from a file with one hex encoded transaction per line, as returned by
+/// `bitcoin-cli getrawtransaction <txid>`.
+pub fn read_hex_file(path: impl AsRef<Path>) -> Result<Vec<Transaction>> {
+    let txs = read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(deserialize(&Vec::<u8>::from_hex(line)?)?))
+        .collect::<Result<Vec<Transaction>>>()?;
+    if txs.is_empty() {
+        return Err(anyhow!("Transaction file contains no transactions"));
+    }
+    Ok(txs)
+}
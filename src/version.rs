@@ -0,0 +1,149 @@
+use crate::rng;
+use anyhow::Result;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::secp256k1::rand::Rng;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// User agent we announce unless told otherwise.
+pub const USER_AGENT: &str = "/BlockSpammer:1.0/";
+
+/// Protocol version we announce unless told otherwise: the first at which Core sends every relay
+/// preference, wtxidrelay and sendaddrv2 included, so the handshake shows them all.
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// Nonces of the version messages whose handshakes are under way, to recognize one coming back.
+static PENDING_NONCES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// The version message we open a handshake with. Every field can be overridden; the timestamp is
+/// taken when it is built, and the nonce drawn then too unless one was set.
+#[derive(Clone, Debug)]
+pub struct VersionBuilder {
+    version: u32,
+    services: ServiceFlags,
+    user_agent: String,
+    start_height: i32,
+    relay: bool,
+    receiver: SocketAddr,
+    sender: SocketAddr,
+    nonce: Option<u64>,
+}
+
+impl Default for VersionBuilder {
+    fn default() -> Self {
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        Self {
+            version: PROTOCOL_VERSION,
+            services: ServiceFlags::WITNESS,
+            user_agent: USER_AGENT.to_string(),
+            start_height: 0,
+            relay: false,
+            receiver: unspecified,
+            sender: unspecified,
+            nonce: None,
+        }
+    }
+}
+
+impl VersionBuilder {
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Services to advertise, for ourselves and in both addresses.
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Adds `comment` to the user agent, as in `/BlockSpammer:1.0(conn-7)/`.
+    pub fn with_user_agent_comment(mut self, comment: &str) -> Self {
+        let name = self
+            .user_agent
+            .strip_suffix('/')
+            .unwrap_or(&self.user_agent);
+        self.user_agent = format!("{name}({comment})/");
+        self
+    }
+
+    pub fn with_start_height(mut self, start_height: i32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    /// Whether the peer should announce transactions to us before we send a filter.
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Address we claim to see the peer at.
+    pub fn with_receiver(mut self, receiver: SocketAddr) -> Self {
+        self.receiver = receiver;
+        self
+    }
+
+    /// Address we claim to be reachable at.
+    pub fn with_sender(mut self, sender: SocketAddr) -> Self {
+        self.sender = sender;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn build(&self) -> Result<VersionMessage> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(VersionMessage {
+            version: self.version,
+            services: self.services,
+            timestamp: timestamp.try_into()?,
+            receiver: Address::new(&self.receiver, self.services),
+            sender: Address::new(&self.sender, self.services),
+            nonce: self.nonce.unwrap_or_else(|| rng::with_rng(|rng| rng.gen())),
+            user_agent: self.user_agent.clone(),
+            start_height: self.start_height,
+            relay: self.relay,
+        })
+    }
+}
+
+/// A nonce we sent in a version message, remembered until the handshake ends.
+pub(crate) struct SentNonce(u64);
+
+impl SentNonce {
+    pub(crate) fn new(nonce: u64) -> Self {
+        PENDING_NONCES.lock().unwrap().push(nonce);
+        Self(nonce)
+    }
+}
+
+impl Drop for SentNonce {
+    fn drop(&mut self) {
+        let mut nonces = PENDING_NONCES.lock().unwrap();
+        if let Some(i) = nonces.iter().position(|nonce| *nonce == self.0) {
+            nonces.swap_remove(i);
+        }
+    }
+}
+
+/// Whether `nonce` is that of a version message we sent on a handshake still under way, meaning
+/// the peer sending it is ourselves.
+pub fn is_own_nonce(nonce: u64) -> bool {
+    PENDING_NONCES.lock().unwrap().contains(&nonce)
+}